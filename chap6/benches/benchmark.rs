@@ -1,4 +1,4 @@
-use chap6::do_matching;
+use chap6::{do_matching, RegexBuilder};
 use criterion::{criterion_group, criterion_main, Criterion};
 use std::time::Duration;
 
@@ -32,5 +32,41 @@ fn width_first(c: &mut Criterion) {
     }
 }
 
-criterion_group!(benches, width_first, depth_first);
+/// コンパイル済みの Regex を使い回す。作業領域もプールから使い回すので、毎回コンパイルし確保し直す
+/// do_matching との差がわかる
+fn shared_regex(c: &mut Criterion) {
+    let mut g = c.benchmark_group("Shared Regex");
+    g.measurement_time(Duration::from_secs(12));
+
+    for i in INPUTS {
+        for (name, depth_first) in [("Depth First", true), ("Width First", false)] {
+            let re = RegexBuilder::new(i.1).depth_first(depth_first).build().unwrap();
+            g.bench_with_input(format!("{name} {}", i.0), i.2, |b, text| {
+                b.iter(|| re.is_match(text))
+            });
+        }
+    }
+}
+
+/// 長い行の検索。上限を付けない Regex は開始位置を逆向きのプログラムで探し、
+/// 上限を付けたものは開始位置を順に試す
+fn long_line(c: &mut Criterion) {
+    let mut g = c.benchmark_group("Long Line");
+    g.measurement_time(Duration::from_secs(12));
+
+    let inputs = [
+        ("a*b", "a".repeat(3000)),
+        ("\\d+", format!("{}123", "x".repeat(3000))),
+        ("(\\d{4})-(\\d{2})", format!("{} 2024-01", "log line text ".repeat(200))),
+    ];
+    for (expr, text) in &inputs {
+        let fast = RegexBuilder::new(expr).build().unwrap();
+        let each_start = RegexBuilder::new(expr).step_budget(usize::MAX).build().unwrap();
+        for (name, re) in [("Reverse", &fast), ("Each Start", &each_start)] {
+            g.bench_with_input(format!("{name} {expr}"), text, |b, text| b.iter(|| re.find(text)));
+        }
+    }
+}
+
+criterion_group!(benches, width_first, depth_first, shared_regex, long_line);
 criterion_main!(benches);
@@ -0,0 +1,99 @@
+//! data/ にある Unicode のデータから、\p{...} で使う表を生成する。
+
+use std::{
+    collections::BTreeMap,
+    env,
+    error::Error,
+    fmt::Write as _,
+    fs,
+    path::Path,
+};
+
+type Table = BTreeMap<String, Vec<(u32, u32)>>;
+
+/// 2 文字の一般カテゴリをまとめた 1 文字のカテゴリ
+const GROUPS: &[(&str, &[&str])] = &[
+    ("L", &["Lu", "Ll", "Lt", "Lm", "Lo"]),
+    ("LC", &["Lu", "Ll", "Lt"]),
+    ("M", &["Mn", "Mc", "Me"]),
+    ("N", &["Nd", "Nl", "No"]),
+    ("P", &["Pc", "Pd", "Ps", "Pe", "Pi", "Pf", "Po"]),
+    ("S", &["Sm", "Sc", "Sk", "So"]),
+    ("Z", &["Zs", "Zl", "Zp"]),
+    ("C", &["Cc", "Cf", "Co", "Cn"]),
+];
+
+/// `XXXX..YYYY ; Name` 形式のファイルを読む
+fn read_table(path: &Path) -> Result<Table, Box<dyn Error>> {
+    let mut table = Table::new();
+    for line in fs::read_to_string(path)?.lines() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (range, name) = line.split_once(';').ok_or("missing ';'")?;
+        let (first, last) = match range.trim().split_once("..") {
+            Some((first, last)) => (first, last),
+            None => (range.trim(), range.trim()),
+        };
+        let first = u32::from_str_radix(first, 16)?;
+        let last = u32::from_str_radix(last, 16)?;
+        table.entry(name.trim().to_string()).or_default().push((first, last));
+    }
+    for ranges in table.values_mut() {
+        *ranges = merge(std::mem::take(ranges));
+    }
+    Ok(table)
+}
+
+/// 範囲を並べ替え、重なったり隣り合ったりしているものを 1 つにする
+fn merge(mut ranges: Vec<(u32, u32)>) -> Vec<(u32, u32)> {
+    ranges.sort_unstable();
+    let mut merged: Vec<(u32, u32)> = Vec::new();
+    for (first, last) in ranges {
+        match merged.last_mut() {
+            Some(prev) if first <= prev.1 + 1 => prev.1 = prev.1.max(last),
+            _ => merged.push((first, last)),
+        }
+    }
+    merged
+}
+
+fn write_table(out: &mut String, name: &str, table: &Table) -> Result<(), Box<dyn Error>> {
+    writeln!(out, "pub const {name}: &[(&str, &[(char, char)])] = &[")?;
+    for (key, ranges) in table {
+        write!(out, "    ({key:?}, &[")?;
+        for (first, last) in ranges {
+            write!(out, "('\\u{{{first:x}}}', '\\u{{{last:x}}}'), ")?;
+        }
+        writeln!(out, "]),")?;
+    }
+    writeln!(out, "];")?;
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let data = Path::new("data");
+    let gc_path = data.join("DerivedGeneralCategory.txt");
+    let script_path = data.join("Scripts.txt");
+    println!("cargo:rerun-if-changed={}", gc_path.display());
+    println!("cargo:rerun-if-changed={}", script_path.display());
+
+    let mut categories = read_table(&gc_path)?;
+    for (group, members) in GROUPS {
+        let ranges = members
+            .iter()
+            .flat_map(|m| categories.get(*m).cloned().unwrap_or_default())
+            .collect();
+        categories.insert(group.to_string(), merge(ranges));
+    }
+    let scripts = read_table(&script_path)?;
+
+    let mut out = String::new();
+    write_table(&mut out, "GENERAL_CATEGORIES", &categories)?;
+    write_table(&mut out, "SCRIPTS", &scripts)?;
+
+    let dst = Path::new(&env::var("OUT_DIR")?).join("unicode_tables.rs");
+    fs::write(dst, out)?;
+    Ok(())
+}
@@ -0,0 +1,63 @@
+//! `chap6` の正規表現をコンパイル時に作るマクロ。
+//!
+//! ```
+//! use chap6_macros::regex;
+//!
+//! let re = regex!("(\\d{4})-(\\d{2})");
+//! let m = re.find("date: 2024-01").unwrap().unwrap();
+//! assert_eq!(m.as_str(), "2024-01");
+//! ```
+//!
+//! パターンが正しくなければコンパイルエラーになる。
+//!
+//! ```compile_fail
+//! let re = chap6_macros::regex!("ab\\q");
+//! ```
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use syn::{parse_macro_input, LitStr};
+
+/// パターンをコンパイル時にパースしてプログラムにし、`&'static chap6::Regex` を返す式にする。
+///
+/// プログラムは `static` の配列として埋め込むので、実行時にパースやコード生成をせず、使い始めるときにメモリも確保しない。
+/// 評価は `chap6::Regex::new` と同じく深さ優先で、評価の上限はない
+#[proc_macro]
+pub fn regex(input: TokenStream) -> TokenStream {
+    let lit = parse_macro_input!(input as LitStr);
+    let pattern = lit.value();
+
+    let (code, names, reverse) = match chap6::__private::compile_to_rust(&pattern) {
+        Ok(rust) => rust,
+        Err(e) => {
+            let span = e.span().and_then(|span| subspan(&lit, span)).unwrap_or_else(|| lit.span());
+            return syn::Error::new(span, format!("invalid regex: {e}")).to_compile_error().into();
+        }
+    };
+    let parse = |s: &str| s.parse::<proc_macro2::TokenStream>();
+    let (code, names, reverse) = match (parse(&code), parse(&names), parse(&reverse)) {
+        (Ok(code), Ok(names), Ok(reverse)) => (code, names, reverse),
+        (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => {
+            return syn::Error::new(lit.span(), format!("internal error: {e}")).to_compile_error().into()
+        }
+    };
+
+    quote! {{
+        static RE: ::chap6::Regex = ::chap6::__private::regex(#lit, #code, #names, #reverse);
+        &RE
+    }}
+    .into()
+}
+
+/// パターン中のバイト範囲を、リテラルの中の位置にする。
+/// エスケープを含むリテラルでは位置がずれるのでリテラル全体を指す。
+/// 部分の位置は nightly のコンパイラでしか取れず、stable では None になる
+fn subspan(lit: &LitStr, span: std::ops::Range<usize>) -> Option<Span> {
+    let token = lit.token();
+    if token.to_string() != format!("\"{}\"", lit.value()) {
+        return None;
+    }
+    // 先頭の `"` の分ずらす
+    token.subspan(span.start + 1..span.end + 1)
+}
@@ -0,0 +1,50 @@
+//! `regex!` が埋め込んだプログラムを使い始めるときに、メモリを確保しないことを確かめる。
+
+use chap6_macros::regex;
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+};
+
+/// スレッドごとに確保の回数を数えるアロケータ
+struct CountingAlloc;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|n| n.set(n.get() + 1));
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.with(|n| n.set(n.get() + 1));
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+fn allocations(f: impl FnOnce()) -> usize {
+    let before = ALLOCATIONS.with(Cell::get);
+    f();
+    ALLOCATIONS.with(Cell::get) - before
+}
+
+#[test]
+fn test_embedded_program_does_not_allocate() {
+    let n = allocations(|| {
+        let re = regex!("^(?<level>error|warn|fatal): [^ab]\\p{Han}+(?<=\\b(?:USD|EUR) )\\d{2,4}$");
+        assert_eq!(re.capture_names().count(), 2);
+        let re = regex!("(\\d{4})-(\\d{2})");
+        assert_eq!(re.as_str(), "(\\d{4})-(\\d{2})");
+    });
+    assert_eq!(n, 0);
+}
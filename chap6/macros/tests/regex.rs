@@ -0,0 +1,42 @@
+use chap6::Regex;
+use chap6_macros::regex;
+
+#[test]
+fn test_same_program() {
+    // 埋め込んだプログラムは実行時にコンパイルしたものと同じ
+    let embedded = regex!("^(?<level>error|warn|fatal): (\\p{Han}+|x{2,}) (?>x+)[^ab]\\D\\d*\\b.$");
+    let compiled = Regex::new(embedded.as_str()).unwrap();
+    assert_eq!(format!("{embedded:?}"), format!("{compiled:?}"));
+
+    // 後読みと、開始位置を探す逆向きのプログラムも埋め込む
+    let embedded = regex!("(?<=\\$|\\b(?:USD|EUR) )(\\d+)(?<!0)\\b");
+    let compiled = Regex::new(embedded.as_str()).unwrap();
+    assert_eq!(format!("{embedded:?}"), format!("{compiled:?}"));
+    let text = format!("{} EUR 120 $35", "x".repeat(100));
+    assert_eq!(embedded.find(&text).unwrap().unwrap().as_str(), "35");
+    let embedded = regex!("^\\p{L}+ (\\d{2,4}|[ab]*)$");
+    let compiled = Regex::new(embedded.as_str()).unwrap();
+    assert_eq!(format!("{embedded:?}"), format!("{compiled:?}"));
+}
+
+#[test]
+fn test_match() {
+    let re = regex!("(\\d{4})-(\\d{2})");
+    let caps = re.captures("date: 2024-01").unwrap().unwrap();
+    assert_eq!(caps.get(0).unwrap().as_str(), "2024-01");
+    assert_eq!(caps.get(1).unwrap().range(), 6..10);
+    assert!(!re.is_match("2024").unwrap());
+
+    let re = regex!("(?<key>\\p{L}+)=(?<value>\\d+)");
+    let caps = re.captures("x: size=42").unwrap().unwrap();
+    assert_eq!(caps.name("key").unwrap().as_str(), "size");
+    assert_eq!(caps.name("value").unwrap().as_str(), "42");
+
+    // 同じ呼び出し箇所では同じ Regex を返す
+    let find = || regex!("a+").find("baa").unwrap().unwrap().range();
+    assert_eq!(find(), 1..3);
+    assert_eq!(find(), 1..3);
+
+    let re = regex!("'\n'");
+    assert!(re.is_match("'\n'").unwrap());
+}
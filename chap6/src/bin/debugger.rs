@@ -0,0 +1,263 @@
+//! 正規表現の評価を 1 命令ずつ追う REPL。
+//!
+//! ```text
+//! $ cargo run --bin debugger -- 'a(b|c)*d' abcbd
+//! > list
+//! > break 3
+//! > continue
+//! > step 2
+//! ```
+
+use chap6::{
+    debugger::{Debugger, Snapshot, StackEntry, Status},
+    DynError,
+};
+use std::{
+    env,
+    io::{self, BufRead, Write},
+};
+
+const HELP: &str = "\
+pattern <regex>    パターンを設定してやり直す
+input <text>       入力を設定してやり直す
+run                先頭からやり直す
+step [n] (s)       n 命令実行する (既定 1)
+continue (c)       終わるかブレークポイントまで実行する
+break <addr> (b)   ブレークポイントを置く
+delete <addr> (d)  ブレークポイントを消す
+list (l)           プログラムを表示する
+info (i)           pc, sp, レジスタ, キャプチャ, スタックを表示する
+help (h)           この説明
+quit (q)           終了";
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Pattern(String),
+    Input(String),
+    Run,
+    Step(usize),
+    Continue,
+    Break(usize),
+    Delete(usize),
+    List,
+    Info,
+    Help,
+    Quit,
+}
+
+fn parse_command(line: &str) -> Result<Command, String> {
+    let line = line.trim_start();
+    let (name, arg) = match line.split_once(' ') {
+        Some((name, arg)) => (name, arg.trim_start()),
+        None => (line.trim_end(), ""),
+    };
+    let addr = || arg.trim().parse::<usize>().map_err(|_| format!("invalid address: {arg:?}"));
+
+    match name {
+        "pattern" => Ok(Command::Pattern(arg.to_string())),
+        "input" => Ok(Command::Input(arg.to_string())),
+        "run" | "r" => Ok(Command::Run),
+        "step" | "s" if arg.trim().is_empty() => Ok(Command::Step(1)),
+        "step" | "s" => arg.trim().parse().map(Command::Step).map_err(|_| format!("invalid count: {arg:?}")),
+        "continue" | "c" => Ok(Command::Continue),
+        "break" | "b" => addr().map(Command::Break),
+        "delete" | "d" => addr().map(Command::Delete),
+        "list" | "l" => Ok(Command::List),
+        "info" | "i" => Ok(Command::Info),
+        "help" | "h" => Ok(Command::Help),
+        "quit" | "q" => Ok(Command::Quit),
+        _ => Err(format!("unknown command: {name:?} (help で一覧)")),
+    }
+}
+
+struct Session {
+    pattern: String,
+    input: String,
+    debugger: Option<Debugger>,
+}
+
+impl Session {
+    /// パターンか入力が変わったら作り直す。ブレークポイントは引き継ぐ
+    fn reload(&mut self) {
+        let breakpoints = self.debugger.iter().flat_map(|d| d.breakpoints()).collect::<Vec<usize>>();
+        self.debugger = match Debugger::new(&self.pattern, &self.input) {
+            Ok(mut debugger) => {
+                for pc in breakpoints {
+                    debugger.set_breakpoint(pc);
+                }
+                Some(debugger)
+            }
+            Err(e) => {
+                println!("{e}");
+                None
+            }
+        };
+    }
+
+    fn exec(&mut self, command: Command) -> Result<bool, DynError> {
+        match command {
+            Command::Pattern(pattern) => {
+                self.pattern = pattern;
+                self.reload();
+                return Ok(true);
+            }
+            Command::Input(input) => {
+                self.input = input;
+                self.reload();
+                return Ok(true);
+            }
+            Command::Help => {
+                println!("{HELP}");
+                return Ok(true);
+            }
+            Command::Quit => return Ok(false),
+            _ => (),
+        }
+
+        let debugger = if let Some(debugger) = &mut self.debugger {
+            debugger
+        } else {
+            println!("pattern を設定してください");
+            return Ok(true);
+        };
+        match command {
+            Command::Run => {
+                debugger.restart();
+                print_state(debugger);
+            }
+            Command::Step(n) => {
+                for _ in 0..n {
+                    if debugger.step()? != Status::Running {
+                        break;
+                    }
+                }
+                print_state(debugger);
+            }
+            Command::Continue => {
+                debugger.cont()?;
+                print_state(debugger);
+            }
+            Command::Break(pc) => {
+                if !debugger.set_breakpoint(pc) {
+                    println!("cannot set breakpoint at {pc:>04}");
+                }
+            }
+            Command::Delete(pc) => {
+                if !debugger.remove_breakpoint(pc) {
+                    println!("no breakpoint at {pc:>04}");
+                }
+            }
+            Command::List => print_program(debugger),
+            Command::Info => print_info(&debugger.snapshot()),
+            Command::Pattern(_) | Command::Input(_) | Command::Help | Command::Quit => unreachable!(),
+        }
+        Ok(true)
+    }
+}
+
+fn print_program(debugger: &Debugger) {
+    let pc = debugger.snapshot().thread.map(|(pc, _)| pc);
+    let breakpoints = debugger.breakpoints().collect::<Vec<usize>>();
+    for (n, inst) in debugger.program().iter().enumerate() {
+        let mark = if pc == Some(n) { '>' } else { ' ' };
+        let bp = if breakpoints.contains(&n) { '*' } else { ' ' };
+        println!("{mark}{bp}{n:>04}: {inst}");
+    }
+}
+
+/// 次に実行する命令と、入力のどこを読んでいるかを表示する
+fn print_state(debugger: &Debugger) {
+    let snapshot = debugger.snapshot();
+    let input = debugger.input().iter().collect::<String>();
+    match (debugger.status(), snapshot.thread) {
+        (Status::Matched, _) => {
+            let (start, end) = snapshot.captures[0].unwrap_or_default();
+            let matched = debugger.input()[start..end].iter().collect::<String>();
+            println!("matched: {matched:?} ({start}..{end})");
+        }
+        (Status::Failed, _) => println!("no match"),
+        (Status::Running, Some((pc, sp))) => {
+            println!("{pc:>04}: {}", debugger.program()[pc]);
+            println!("  {input}");
+            println!("  {}^ sp = {sp}, start = {}", " ".repeat(sp), snapshot.start);
+        }
+        (Status::Running, None) => println!("backtrack (stack: {})", snapshot.stack.len()),
+    }
+}
+
+fn print_info(snapshot: &Snapshot) {
+    match snapshot.thread {
+        Some((pc, sp)) => println!("pc = {pc:>04}, sp = {sp}, start = {}", snapshot.start),
+        None => println!("pc = -, sp = -, start = {}", snapshot.start),
+    }
+    println!("register = {:?}", snapshot.register);
+    for (i, capture) in snapshot.captures.iter().enumerate() {
+        match capture {
+            Some((start, end)) => println!("group {i} = {start}..{end}"),
+            None => println!("group {i} = None"),
+        }
+    }
+    println!("stack ({}):", snapshot.stack.len());
+    for entry in &snapshot.stack {
+        match entry {
+            StackEntry::Resume { pc, sp, register } => {
+                println!("  resume pc = {pc:>04}, sp = {sp}, register = {register:?}")
+            }
+            StackEntry::Barrier => println!("  barrier"),
+            StackEntry::Restore { slot, value } => println!("  restore slot {slot} = {value:?}"),
+        }
+    }
+}
+
+fn main() -> Result<(), DynError> {
+    let args: Vec<String> = env::args().collect();
+    let mut session = Session {
+        pattern: args.get(1).cloned().unwrap_or_default(),
+        input: args.get(2).cloned().unwrap_or_default(),
+        debugger: None,
+    };
+    if args.len() > 1 {
+        session.reload();
+    }
+
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("> ");
+        io::stdout().flush()?;
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => break,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        match parse_command(&line) {
+            Ok(command) => {
+                if !session.exec(command)? {
+                    break;
+                }
+            }
+            Err(e) => println!("{e}"),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{parse_command, Command};
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(parse_command("pattern a(b|c)*d"), Ok(Command::Pattern("a(b|c)*d".to_string())));
+        assert_eq!(parse_command("input  a b "), Ok(Command::Input("a b ".to_string())));
+        assert_eq!(parse_command("s"), Ok(Command::Step(1)));
+        assert_eq!(parse_command("step 10"), Ok(Command::Step(10)));
+        assert_eq!(parse_command("b 3"), Ok(Command::Break(3)));
+        assert_eq!(parse_command("delete 0003"), Ok(Command::Delete(3)));
+        assert_eq!(parse_command("c"), Ok(Command::Continue));
+        assert!(parse_command("break x").is_err());
+        assert!(parse_command("jump 3").is_err());
+    }
+}
@@ -0,0 +1,296 @@
+//! 名前付きグループで各行からフィールドを取り出し、CSV, TSV, JSON Lines で出力する。
+//!
+//! ```text
+//! $ cargo run --bin extract -- -f json '^(?<time>\S+) (?<level>\p{Lu}+) (?<message>.*)$' app.log
+//! {"time":"2024-01-02T03:04:05","level":"ERROR","message":"disk full"}
+//! ```
+//!
+//! 名前付きグループがなければ番号付きのグループを、グループがなければマッチ全体を出力する。
+//! 一度も通らなかったグループは、CSV と TSV では空、JSON では null になる。
+
+use chap6::{Captures, DynError, Regex};
+use std::{
+    env,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+};
+
+const USAGE: &str = "\
+usage: extract [options] <regex> [file...]
+
+  -f, --format <csv|tsv|json>  出力形式 (既定 csv)
+      --no-header              CSV と TSV で見出しの行を出さない
+      --skip                   マッチしない行を黙って読み飛ばす (既定)
+      --report                 マッチしない行を標準エラー出力に報告する
+
+file を省くか - を指定すると標準入力を読む。- で始まるパターンは -- の後ろに書く";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Csv,
+    Tsv,
+    /// 1 行に 1 つの JSON オブジェクト
+    Json,
+}
+
+#[derive(Debug, PartialEq)]
+struct Options {
+    format: Format,
+    header: bool,
+    /// マッチしない行を標準エラー出力に報告するか
+    report: bool,
+    pattern: String,
+    files: Vec<String>,
+}
+
+fn parse_format(value: &str) -> Result<Format, String> {
+    match value {
+        "csv" => Ok(Format::Csv),
+        "tsv" => Ok(Format::Tsv),
+        "json" => Ok(Format::Json),
+        _ => Err(format!("unknown format: {value:?}")),
+    }
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut format = Format::Csv;
+    let mut header = true;
+    let mut report = false;
+    let mut rest = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-f" | "--format" => {
+                let value = args.next().ok_or("--format needs a value")?;
+                format = parse_format(value)?;
+            }
+            "--no-header" => header = false,
+            "--skip" => report = false,
+            "--report" => report = true,
+            "--" => rest.extend(args.by_ref().cloned()),
+            arg if arg.starts_with("--format=") => format = parse_format(&arg["--format=".len()..])?,
+            arg if arg.starts_with('-') && arg != "-" => return Err(format!("unknown option: {arg}")),
+            _ => rest.push(arg.clone()),
+        }
+    }
+
+    let mut rest = rest.into_iter();
+    let pattern = rest.next().ok_or("no regex")?;
+    Ok(Options {
+        format,
+        header,
+        report,
+        pattern,
+        files: rest.collect(),
+    })
+}
+
+/// 出力するグループの番号と見出し
+fn columns(re: &Regex) -> Vec<(usize, String)> {
+    let names = re.capture_names().collect::<Vec<_>>();
+    let named = names
+        .iter()
+        .enumerate()
+        .filter_map(|(i, name)| name.map(|name| (i, name.to_string())))
+        .collect::<Vec<_>>();
+    if !named.is_empty() {
+        named
+    } else if names.len() > 1 {
+        (1..names.len()).map(|i| (i, i.to_string())).collect()
+    } else {
+        vec![(0, "0".to_string())]
+    }
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+/// TSV では区切りのタブと改行を書けないので、バックスラッシュでエスケープする
+fn tsv_field(s: &str) -> String {
+    let mut out = String::new();
+    for c in s.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\t' => out.push_str("\\t"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() && (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// 見出しの行。JSON では出さない
+fn header(format: Format, columns: &[(usize, String)]) -> Option<String> {
+    let names = columns.iter().map(|(_, name)| name.as_str());
+    match format {
+        Format::Csv => Some(names.map(csv_field).collect::<Vec<_>>().join(",")),
+        Format::Tsv => Some(names.map(tsv_field).collect::<Vec<_>>().join("\t")),
+        Format::Json => None,
+    }
+}
+
+fn record(format: Format, columns: &[(usize, String)], caps: &Captures) -> String {
+    let values = columns.iter().map(|(i, name)| (name, caps.get(*i).map(|m| m.as_str())));
+    match format {
+        Format::Csv => values.map(|(_, v)| csv_field(v.unwrap_or(""))).collect::<Vec<_>>().join(","),
+        Format::Tsv => values.map(|(_, v)| tsv_field(v.unwrap_or(""))).collect::<Vec<_>>().join("\t"),
+        Format::Json => {
+            let fields = values
+                .map(|(name, v)| format!("{}:{}", json_string(name), v.map_or("null".to_string(), json_string)))
+                .collect::<Vec<_>>();
+            format!("{{{}}}", fields.join(","))
+        }
+    }
+}
+
+/// `reader` の各行からフィールドを取り出して `out` に書く。`name` は報告に使う入力の名前
+fn extract<R: BufRead, W: Write>(
+    re: &Regex,
+    columns: &[(usize, String)],
+    options: &Options,
+    name: &str,
+    reader: R,
+    out: &mut W,
+) -> Result<(), DynError> {
+    for (n, line) in reader.lines().enumerate() {
+        let line = line?;
+        match re.captures(&line)? {
+            Some(caps) => writeln!(out, "{}", record(options.format, columns, &caps))?,
+            None if options.report => eprintln!("{name}:{}: no match: {line}", n + 1),
+            None => (),
+        }
+    }
+    Ok(())
+}
+
+fn main() -> Result<(), DynError> {
+    let args = env::args().skip(1).collect::<Vec<String>>();
+    let options = match parse_args(&args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{e}\n\n{USAGE}");
+            return Err("invalid arguments".into());
+        }
+    };
+    let re = Regex::new(&options.pattern)?;
+    let columns = columns(&re);
+
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    if options.header {
+        if let Some(header) = header(options.format, &columns) {
+            writeln!(out, "{header}")?;
+        }
+    }
+
+    let stdin = ["-".to_string()];
+    let files = if options.files.is_empty() { &stdin[..] } else { &options.files[..] };
+    for file in files {
+        if file == "-" {
+            extract(&re, &columns, &options, "<stdin>", io::stdin().lock(), &mut out)?;
+        } else {
+            let reader = BufReader::new(File::open(file).map_err(|e| format!("{file}: {e}"))?);
+            extract(&re, &columns, &options, file, reader, &mut out)?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{columns, extract, header, parse_args, Format, Options};
+    use chap6::Regex;
+
+    fn args(s: &[&str]) -> Vec<String> {
+        s.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let options = parse_args(&args(&["-f", "tsv", "--report", "(a)", "x.log", "-"])).unwrap();
+        assert_eq!(
+            options,
+            Options {
+                format: Format::Tsv,
+                header: true,
+                report: true,
+                pattern: "(a)".to_string(),
+                files: args(&["x.log", "-"]),
+            }
+        );
+        let options = parse_args(&args(&["--format=json", "--no-header", "--", "-a"])).unwrap();
+        assert_eq!((options.format, options.header, options.pattern.as_str()), (Format::Json, false, "-a"));
+        assert!(!parse_args(&args(&["--report", "--skip", "a"])).unwrap().report);
+
+        assert!(parse_args(&args(&[])).is_err());
+        assert!(parse_args(&args(&["-f", "xml", "a"])).is_err());
+        assert!(parse_args(&args(&["-x", "a"])).is_err());
+    }
+
+    #[test]
+    fn test_columns() {
+        let names = |expr: &str| columns(&Regex::new(expr).unwrap());
+        assert_eq!(names("(a)(?<x>b)(?<y>c)"), vec![(2, "x".to_string()), (3, "y".to_string())]);
+        assert_eq!(names("(a)(b)"), vec![(1, "1".to_string()), (2, "2".to_string())]);
+        assert_eq!(names("ab"), vec![(0, "0".to_string())]);
+    }
+
+    fn run(format: Format, expr: &str, input: &str) -> String {
+        let re = Regex::new(expr).unwrap();
+        let columns = columns(&re);
+        let options = Options {
+            format,
+            header: true,
+            report: false,
+            pattern: expr.to_string(),
+            files: vec![],
+        };
+        let mut out = header(format, &columns).map(|h| h + "\n").unwrap_or_default().into_bytes();
+        extract(&re, &columns, &options, "test", input.as_bytes(), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_extract() {
+        let expr = "^(?<level>\\p{Lu}+): (?<msg>[^;]*)(?:; code=(?<code>\\d+))?$";
+        let input = "ERROR: disk \"full\", retry; code=28\nnoise\nINFO: tab\there\n";
+        assert_eq!(
+            run(Format::Csv, expr, input),
+            "level,msg,code\nERROR,\"disk \"\"full\"\", retry\",28\nINFO,tab\there,\n"
+        );
+        assert_eq!(
+            run(Format::Tsv, expr, input),
+            "level\tmsg\tcode\nERROR\tdisk \"full\", retry\t28\nINFO\ttab\\there\t\n"
+        );
+        assert_eq!(
+            run(Format::Json, expr, input),
+            "{\"level\":\"ERROR\",\"msg\":\"disk \\\"full\\\", retry\",\"code\":\"28\"}\n\
+             {\"level\":\"INFO\",\"msg\":\"tab\\there\",\"code\":null}\n"
+        );
+    }
+}
@@ -0,0 +1,471 @@
+//! `s/pattern/replacement/flags` で各行を置換するストリームエディタ。
+//!
+//! ```text
+//! $ cargo run --bin sed -- 's/(\d{4})-(\d{2})/\2\/\1/g' app.log
+//! $ cargo run --bin sed -- -i.bak 's/ERROR/error/2' app.log
+//! ```
+//!
+//! 区切りには `/` 以外の文字も使える。パターンと置換文字列の中で区切りの文字を使うときは
+//! `\` を前に付ける。置換文字列では `&` と `\0` がマッチ全体、`\1` から `\9` がグループになる。
+//! 一度も通らなかったグループは空文字になる。
+
+use chap6::{DynError, Regex};
+use std::{
+    env, fs,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    process,
+};
+
+const USAGE: &str = "\
+usage: sed [options] s/pattern/replacement/flags [file...]
+
+  -n                   行を自動で出力しない。p フラグと組み合わせて置換した行だけを出す
+  -i[SUFFIX]           ファイルを書き換える。SUFFIX があれば元のファイルを FILE+SUFFIX に残す
+      --in-place[=SUFFIX]
+
+flags:
+  g    すべてのマッチを置換する
+  N    N 番目のマッチだけを置換する。g と一緒なら N 番目から後をすべて置換する
+  i    大文字と小文字を区別しない
+  p    置換した行を出力する
+
+file を省くか - を指定すると標準入力を読む";
+
+#[derive(Debug, PartialEq)]
+struct Options {
+    /// 行を自動で出力しないか
+    quiet: bool,
+    /// ファイルを書き換えるときのバックアップの接尾辞。空ならバックアップを残さない
+    in_place: Option<String>,
+    script: String,
+    files: Vec<String>,
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut quiet = false;
+    let mut in_place = None;
+    let mut rest = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-n" | "--quiet" => quiet = true,
+            "--in-place" => in_place = Some(String::new()),
+            "--" => rest.extend(args.by_ref().cloned()),
+            arg if arg.starts_with("--in-place=") => in_place = Some(arg["--in-place=".len()..].to_string()),
+            arg if arg.starts_with("-i") => in_place = Some(arg["-i".len()..].to_string()),
+            arg if arg.starts_with('-') && arg != "-" => return Err(format!("unknown option: {arg}")),
+            _ => rest.push(arg.clone()),
+        }
+    }
+
+    let mut rest = rest.into_iter();
+    let script = rest.next().ok_or("no script")?;
+    let files = rest.collect::<Vec<_>>();
+    if in_place.is_some() && (files.is_empty() || files.iter().any(|file| file == "-")) {
+        return Err("-i needs files to edit".to_string());
+    }
+    Ok(Options {
+        quiet,
+        in_place,
+        script,
+        files,
+    })
+}
+
+/// 置換文字列の部品
+#[derive(Debug, PartialEq)]
+enum Piece {
+    Literal(String),
+    /// グループの番号。0 はマッチ全体
+    Group(usize),
+}
+
+/// `s` コマンド
+#[derive(Debug)]
+struct Command {
+    regex: Regex,
+    replacement: Vec<Piece>,
+    /// 何番目のマッチから置換するか。1 始まり
+    occurrence: usize,
+    /// `occurrence` 番目から後のマッチをすべて置換するか
+    global: bool,
+    /// 置換した行を出力するか
+    print: bool,
+}
+
+/// パターンの中で特別な意味を持つ文字
+const META_CHARS: &[char] = &['\\', '(', ')', '|', '+', '*', '?', '.', '^', '$', '{', '}', '[', ']'];
+
+/// `s/pattern/replacement/flags` を 3 つに分ける。`\` と区切りの文字は区切りの文字そのものにする。
+/// ただし区切りの文字がパターンのメタ文字 (置換文字列では `&`) なら、`\` を残してただの文字として扱う
+fn split_command(script: &str) -> Result<(String, String, String), String> {
+    let mut chars = script.chars();
+    if chars.next() != Some('s') {
+        return Err(format!("unknown command: {script:?}"));
+    }
+    let delim = match chars.next() {
+        Some(c) if c != '\\' && c != '\n' => c,
+        _ => return Err("missing delimiter after s".to_string()),
+    };
+
+    let mut parts = Vec::new();
+    let mut part = String::new();
+    while parts.len() < 2 {
+        match chars.next() {
+            Some('\\') => match chars.next() {
+                Some(c) if c == delim => {
+                    let is_meta = if parts.is_empty() { META_CHARS.contains(&c) } else { c == '&' };
+                    if is_meta {
+                        part.push('\\');
+                    }
+                    part.push(c);
+                }
+                Some(c) => {
+                    part.push('\\');
+                    part.push(c);
+                }
+                None => part.push('\\'),
+            },
+            Some(c) if c == delim => parts.push(std::mem::take(&mut part)),
+            Some(c) => part.push(c),
+            None => return Err("unterminated s command".to_string()),
+        }
+    }
+    let replacement = parts.pop().unwrap();
+    let pattern = parts.pop().unwrap();
+    Ok((pattern, replacement, chars.collect()))
+}
+
+/// 置換文字列を部品に分ける。`groups` はグループ 0 を含むグループの数
+fn parse_replacement(replacement: &str, groups: usize) -> Result<Vec<Piece>, String> {
+    let mut pieces = Vec::new();
+    let mut literal = String::new();
+    let push_group = |pieces: &mut Vec<Piece>, literal: &mut String, i: usize| {
+        if !literal.is_empty() {
+            pieces.push(Piece::Literal(std::mem::take(literal)));
+        }
+        pieces.push(Piece::Group(i));
+    };
+
+    let mut chars = replacement.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '&' => push_group(&mut pieces, &mut literal, 0),
+            '\\' => match chars.next() {
+                Some(d) if d.is_ascii_digit() => {
+                    let i = d as usize - '0' as usize;
+                    if i >= groups {
+                        return Err(format!("invalid reference \\{i} on s command's replacement"));
+                    }
+                    push_group(&mut pieces, &mut literal, i);
+                }
+                Some('n') => literal.push('\n'),
+                Some('t') => literal.push('\t'),
+                Some(c) => literal.push(c),
+                None => literal.push('\\'),
+            },
+            c => literal.push(c),
+        }
+    }
+    if !literal.is_empty() {
+        pieces.push(Piece::Literal(literal));
+    }
+    Ok(pieces)
+}
+
+fn parse_command(script: &str) -> Result<Command, DynError> {
+    let (pattern, replacement, flags) = split_command(script)?;
+
+    let mut occurrence = None;
+    let mut global = false;
+    let mut case_insensitive = false;
+    let mut print = false;
+    let mut flags = flags.chars().peekable();
+    while let Some(c) = flags.next() {
+        match c {
+            'g' => global = true,
+            'i' | 'I' => case_insensitive = true,
+            'p' => print = true,
+            '0'..='9' if occurrence.is_none() => {
+                let mut n = c.to_string();
+                while let Some(d) = flags.next_if(char::is_ascii_digit) {
+                    n.push(d);
+                }
+                match n.parse::<usize>() {
+                    Ok(n) if n > 0 => occurrence = Some(n),
+                    _ => return Err(format!("invalid occurrence: {n}").into()),
+                }
+            }
+            c => return Err(format!("unknown option to s: {c:?}").into()),
+        }
+    }
+
+    let pattern = if case_insensitive { format!("(?i){pattern}") } else { pattern };
+    let regex = Regex::new(&pattern)?;
+    let groups = regex.capture_names().count();
+    Ok(Command {
+        regex,
+        replacement: parse_replacement(&replacement, groups)?,
+        occurrence: occurrence.unwrap_or(1),
+        global,
+        print,
+    })
+}
+
+impl Command {
+    /// 1 行を置換する。置換しなかったら None
+    fn substitute(&self, line: &str) -> Result<Option<String>, DynError> {
+        let all = self.regex.captures_all(line)?;
+        let targets = all.iter().skip(self.occurrence - 1).take(if self.global { usize::MAX } else { 1 });
+
+        let mut out = String::new();
+        let mut last = 0;
+        let mut replaced = false;
+        for caps in targets {
+            let m = caps.get(0).unwrap();
+            out.push_str(&line[last..m.start()]);
+            for piece in &self.replacement {
+                match piece {
+                    Piece::Literal(s) => out.push_str(s),
+                    Piece::Group(i) => out.push_str(caps.get(*i).map_or("", |m| m.as_str())),
+                }
+            }
+            last = m.end();
+            replaced = true;
+        }
+        if !replaced {
+            return Ok(None);
+        }
+        out.push_str(&line[last..]);
+        Ok(Some(out))
+    }
+}
+
+/// `reader` の各行を置換して `out` に書く。行末の改行はそのまま残す
+fn edit<R: BufRead, W: Write>(command: &Command, quiet: bool, mut reader: R, out: &mut W) -> Result<(), DynError> {
+    let mut buf = String::new();
+    loop {
+        buf.clear();
+        if reader.read_line(&mut buf)? == 0 {
+            return Ok(());
+        }
+        let line = buf.strip_suffix('\n').unwrap_or(&buf);
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        let newline = &buf[line.len()..];
+
+        let replaced = command.substitute(line)?;
+        let line = replaced.as_deref().unwrap_or(line);
+        // p フラグの出力が先で、自動の出力が後
+        if command.print && replaced.is_some() {
+            // 最後の行に改行がなくても、続けて出す行とは分ける
+            write!(out, "{line}{}", if newline.is_empty() && !quiet { "\n" } else { newline })?;
+        }
+        if !quiet {
+            write!(out, "{line}{newline}")?;
+        }
+    }
+}
+
+/// ファイルを置換した内容で書き換える。`suffix` が空でなければ元の内容を残す。
+/// 途中で止まっても元のファイルが壊れないように、同じディレクトリの一時ファイルに書いてから置き換える
+fn edit_in_place(command: &Command, quiet: bool, file: &str, suffix: &str) -> Result<(), DynError> {
+    let reader = BufReader::new(File::open(file).map_err(|e| format!("{file}: {e}"))?);
+    let mut edited = Vec::new();
+    edit(command, quiet, reader, &mut edited)?;
+    if !suffix.is_empty() {
+        let backup = format!("{file}{suffix}");
+        fs::copy(file, &backup).map_err(|e| format!("{backup}: {e}"))?;
+    }
+
+    let path = Path::new(file);
+    let name = match path.file_name() {
+        Some(name) => name.to_string_lossy(),
+        None => return Err(format!("{file}: not a file").into()),
+    };
+    let tmp = path.with_file_name(format!(".{name}.sed{}", process::id()));
+    let replace = || -> io::Result<()> {
+        fs::write(&tmp, &edited)?;
+        fs::set_permissions(&tmp, fs::metadata(file)?.permissions())?;
+        fs::rename(&tmp, file)
+    };
+    if let Err(e) = replace() {
+        let _ = fs::remove_file(&tmp);
+        return Err(format!("{file}: {e}").into());
+    }
+    Ok(())
+}
+
+fn main() -> Result<(), DynError> {
+    let args = env::args().skip(1).collect::<Vec<String>>();
+    let options = match parse_args(&args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{e}\n\n{USAGE}");
+            return Err("invalid arguments".into());
+        }
+    };
+    let command = parse_command(&options.script)?;
+
+    if let Some(suffix) = &options.in_place {
+        for file in &options.files {
+            edit_in_place(&command, options.quiet, file, suffix)?;
+        }
+        return Ok(());
+    }
+
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    let stdin = ["-".to_string()];
+    let files = if options.files.is_empty() { &stdin[..] } else { &options.files[..] };
+    for file in files {
+        if file == "-" {
+            edit(&command, options.quiet, io::stdin().lock(), &mut out)?;
+        } else {
+            let reader = BufReader::new(File::open(file).map_err(|e| format!("{file}: {e}"))?);
+            edit(&command, options.quiet, reader, &mut out)?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{edit, edit_in_place, parse_args, parse_command, parse_replacement, split_command, Options, Piece};
+    use std::{env, fs, process};
+
+    fn args(s: &[&str]) -> Vec<String> {
+        s.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(
+            parse_args(&args(&["-n", "-i.bak", "s/a/b/", "x.txt"])).unwrap(),
+            Options {
+                quiet: true,
+                in_place: Some(".bak".to_string()),
+                script: "s/a/b/".to_string(),
+                files: args(&["x.txt"]),
+            }
+        );
+        let options = parse_args(&args(&["--in-place", "s/a/b/", "x.txt", "y.txt"])).unwrap();
+        assert_eq!((options.quiet, options.in_place.as_deref()), (false, Some("")));
+        assert_eq!(parse_args(&args(&["--", "s/-/+/"])).unwrap().files, Vec::<String>::new());
+
+        assert!(parse_args(&args(&[])).is_err());
+        assert!(parse_args(&args(&["-x", "s/a/b/"])).is_err());
+        // 書き換えるファイルが要る
+        assert!(parse_args(&args(&["-i", "s/a/b/"])).is_err());
+        assert!(parse_args(&args(&["-i", "s/a/b/", "-"])).is_err());
+    }
+
+    #[test]
+    fn test_split_command() {
+        let split = split_command;
+        assert_eq!(split("s/a/b/g"), Ok(("a".to_string(), "b".to_string(), "g".to_string())));
+        assert_eq!(split("s/\\//\\\\/"), Ok(("/".to_string(), "\\\\".to_string(), "".to_string())));
+        assert_eq!(split("s,a\\,b,\\d,"), Ok(("a,b".to_string(), "\\d".to_string(), "".to_string())));
+        // メタ文字の区切りはエスケープしたまま残す
+        assert_eq!(split("s|a\\|b|x\\||"), Ok(("a\\|b".to_string(), "x|".to_string(), "".to_string())));
+        assert_eq!(split("s.a\\.b.x."), Ok(("a\\.b".to_string(), "x".to_string(), "".to_string())));
+        assert_eq!(split("s&a\\&b&x\\&&"), Ok(("a&b".to_string(), "x\\&".to_string(), "".to_string())));
+        assert_eq!(split("s,値,,"), Ok(("値".to_string(), "".to_string(), "".to_string())));
+
+        assert!(split("y/a/b/").is_err());
+        assert!(split("s").is_err());
+        assert!(split("s/a/b").is_err());
+        assert!(split("s\\a\\b\\").is_err());
+    }
+
+    #[test]
+    fn test_parse_replacement() {
+        assert_eq!(
+            parse_replacement("<&>\\2\\&\\\\\\n", 3),
+            Ok(vec![
+                Piece::Literal("<".to_string()),
+                Piece::Group(0),
+                Piece::Literal(">".to_string()),
+                Piece::Group(2),
+                Piece::Literal("&\\\n".to_string()),
+            ])
+        );
+        assert!(parse_replacement("\\3", 3).is_err());
+
+        assert!(parse_command("s/(a)/\\1/").is_ok());
+        assert!(parse_command("s/(a)/\\2/").is_err());
+        assert!(parse_command("s/a/b/0").is_err());
+        assert!(parse_command("s/a/b/1g2").is_err());
+        assert!(parse_command("s/a/b/x").is_err());
+        assert!(parse_command("s/(/b/").is_err());
+    }
+
+    fn sed(script: &str, quiet: bool, input: &str) -> String {
+        let command = parse_command(script).unwrap();
+        let mut out = Vec::new();
+        edit(&command, quiet, input.as_bytes(), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_substitute() {
+        let input = "a-a-a\nb\r\nA-a\n";
+        assert_eq!(sed("s/a/x/", false, input), "x-a-a\nb\r\nA-x\n");
+        assert_eq!(sed("s/a/x/g", false, input), "x-x-x\nb\r\nA-x\n");
+        assert_eq!(sed("s/a/x/2", false, input), "a-x-a\nb\r\nA-a\n");
+        assert_eq!(sed("s/a/x/2g", false, input), "a-x-x\nb\r\nA-a\n");
+        assert_eq!(sed("s/a/x/gi", false, input), "x-x-x\nb\r\nx-x\n");
+        assert_eq!(sed("s/a/x/3p", true, input), "a-a-x\n");
+        assert_eq!(sed("s/b/x/p", false, input), "a-a-a\nx\r\nx\r\nA-a\n");
+
+        // グループと & を使う。通らなかったグループは空になる
+        assert_eq!(
+            sed("s/(\\d{4})-(\\d{2})(-\\d{2})?/\\2\\/\\1[\\3]/g", false, "2024-01 2023-12-31"),
+            "01/2024[] 12/2023[-31]"
+        );
+        assert_eq!(sed("s/\\p{Katakana}+/「&」/", false, "ファイルがない"), "「ファイル」がない");
+        // 空文字へのマッチ
+        assert_eq!(sed("s/x*/-/g", false, "abc"), "-a-b-c-");
+        assert_eq!(sed("s/a*/x/g", false, "baaac"), "xbxcx");
+        // 最後の行に改行がなければ付けない
+        assert_eq!(sed("s/a/b/", false, "a\na"), "b\nb");
+        assert_eq!(sed("s/a/b/p", false, "a"), "b\nb");
+
+        // エスケープした区切りの文字はただの文字になる
+        assert_eq!(sed("s|a\\|b|<\\|>|g", false, "a|b ab b"), "<|> ab b");
+        assert_eq!(sed("s.a\\.b.x.", false, "acb a.b"), "acb x");
+        assert_eq!(sed("s&a&[\\&]&", false, "ba"), "b[&]");
+    }
+
+    #[test]
+    fn test_edit_in_place() {
+        let dir = env::temp_dir().join(format!("chap6-sed-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("input.txt");
+        let file = file.to_str().unwrap();
+        fs::write(file, "one two\nthree\n").unwrap();
+
+        let command = parse_command("s/t(\\p{L})/T\\1/g").unwrap();
+        edit_in_place(&command, false, file, ".bak").unwrap();
+        assert_eq!(fs::read_to_string(file).unwrap(), "one Two\nThree\n");
+        assert_eq!(fs::read_to_string(format!("{file}.bak")).unwrap(), "one two\nthree\n");
+
+        // 一時ファイルを残さず、元のファイルの権限を保つ
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(file, fs::Permissions::from_mode(0o640)).unwrap();
+            edit_in_place(&command, false, file, ".bak").unwrap();
+            assert_eq!(fs::metadata(file).unwrap().permissions().mode() & 0o777, 0o640);
+        }
+
+        // 接尾辞がなければバックアップを残さない
+        edit_in_place(&command, true, file, "").unwrap();
+        assert_eq!(fs::read_to_string(file).unwrap(), "");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,206 @@
+//! 深さ優先の評価器を 1 命令ずつ動かすデバッガ。
+//!
+//! `Regex` と同じく、入力の先頭から開始位置を 1 文字ずつずらしてマッチを試す。
+//! 位置はすべて入力の何文字目かで表す。
+
+use crate::{
+    engine::{self, DepthVm, Frame, Instruction, Step},
+    error::Error,
+};
+use std::collections::BTreeSet;
+
+/// 評価の状態
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Running,
+    Matched,
+    Failed,
+}
+
+/// バックトラック用のスタックの要素
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StackEntry {
+    /// 失敗したときに再開するスレッド
+    Resume { pc: usize, sp: usize, register: Vec<usize> },
+    /// アトミックグループの始まり
+    Barrier,
+    /// 戻ってきたときにキャプチャのスロットを元の値に戻す
+    Restore { slot: usize, value: Option<usize> },
+}
+
+/// ある時点の評価器の状態
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    /// マッチを試している開始位置
+    pub start: usize,
+    /// 次に実行するスレッドの (pc, sp)。失敗した直後でスタックから取り出す前なら None
+    pub thread: Option<(usize, usize)>,
+    pub register: Vec<usize>,
+    /// グループごとの (始まり, 終わり)。グループ 0 はマッチ全体
+    pub captures: Vec<Option<(usize, usize)>>,
+    /// 先頭が次に取り出される要素
+    pub stack: Vec<StackEntry>,
+}
+
+pub struct Debugger {
+    code: Vec<Instruction>,
+    line: Vec<char>,
+    start: usize,
+    vm: DepthVm,
+    status: Status,
+    breakpoints: BTreeSet<usize>,
+}
+
+impl Debugger {
+    pub fn new(pattern: &str, input: &str) -> Result<Self, Error> {
+        let code = engine::compile(pattern)?;
+        let vm = DepthVm::new(&code, 0);
+        Ok(Debugger {
+            code,
+            line: input.chars().collect(),
+            start: 0,
+            vm,
+            status: Status::Running,
+            breakpoints: BTreeSet::new(),
+        })
+    }
+
+    /// プログラムを 1 命令 1 行で返す
+    pub fn program(&self) -> Vec<String> {
+        self.code.iter().map(|i| i.to_string()).collect()
+    }
+
+    pub fn input(&self) -> &[char] {
+        &self.line
+    }
+
+    pub fn status(&self) -> Status {
+        self.status
+    }
+
+    /// 入力の先頭からやり直す。ブレークポイントは残す
+    pub fn restart(&mut self) {
+        self.start = 0;
+        self.vm = DepthVm::new(&self.code, 0);
+        self.status = Status::Running;
+    }
+
+    /// 命令を 1 つ実行する。開始位置で失敗したら次の開始位置に移る
+    pub fn step(&mut self) -> Result<Status, Error> {
+        if self.status != Status::Running {
+            return Ok(self.status);
+        }
+        match self.vm.step(&self.code, &self.line)? {
+            Step::Running => (),
+            Step::Matched => self.status = Status::Matched,
+            Step::Failed if self.start < self.line.len() => {
+                self.start += 1;
+                self.vm = DepthVm::new(&self.code, self.start);
+            }
+            Step::Failed => self.status = Status::Failed,
+        }
+        Ok(self.status)
+    }
+
+    /// 終わるか、ブレークポイントのある命令の直前まで実行する
+    pub fn cont(&mut self) -> Result<Status, Error> {
+        loop {
+            let status = self.step()?;
+            if status != Status::Running || self.vm.thread().is_some_and(|(pc, _, _)| self.breakpoints.contains(&pc)) {
+                return Ok(status);
+            }
+        }
+    }
+
+    /// ブレークポイントを置く。プログラムの外なら false
+    pub fn set_breakpoint(&mut self, pc: usize) -> bool {
+        pc < self.code.len() && self.breakpoints.insert(pc)
+    }
+
+    pub fn remove_breakpoint(&mut self, pc: usize) -> bool {
+        self.breakpoints.remove(&pc)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let start = self.start;
+        let (thread, register) = match self.vm.thread() {
+            Some((pc, sp, register)) => (Some((pc, sp)), register.to_vec()),
+            None => (None, vec![]),
+        };
+        let stack = self
+            .vm
+            .stack()
+            .into_iter()
+            .rev()
+            .map(|frame| match frame {
+                Frame::Resume((pc, sp, register)) => StackEntry::Resume {
+                    pc,
+                    sp,
+                    register: register.to_vec(),
+                },
+                Frame::Barrier => StackEntry::Barrier,
+                Frame::Restore(slot, value) => StackEntry::Restore { slot, value },
+            })
+            .collect();
+        let captures = self.vm.captures();
+        Snapshot {
+            start,
+            thread,
+            register,
+            captures,
+            stack,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Debugger, StackEntry, Status};
+
+    #[test]
+    fn test_step() {
+        // 0: capcher begin 0, 1: char a, 2: split 3, 5, 3: char b, 4: jump 2, 5: char c, ...
+        let mut dbg = Debugger::new("ab*c", "xabbc").unwrap();
+        assert_eq!(dbg.program()[2], "split 0003, 0005");
+
+        // 開始位置 0 では char a で失敗する
+        assert_eq!(dbg.step().unwrap(), Status::Running);
+        assert_eq!(dbg.snapshot().thread, Some((1, 0)));
+        dbg.step().unwrap();
+        assert_eq!(dbg.snapshot().thread, None);
+        dbg.step().unwrap();
+        assert_eq!(dbg.snapshot().start, 1);
+
+        assert!(dbg.set_breakpoint(2));
+        assert!(!dbg.set_breakpoint(100));
+        assert_eq!(dbg.cont().unwrap(), Status::Running);
+        let snapshot = dbg.snapshot();
+        assert_eq!(snapshot.thread, Some((2, 2)));
+        assert_eq!(snapshot.stack, vec![StackEntry::Restore { slot: 0, value: None }]);
+
+        dbg.step().unwrap();
+        let snapshot = dbg.snapshot();
+        assert_eq!(snapshot.thread, Some((3, 2)));
+        assert_eq!(snapshot.stack[0], StackEntry::Resume { pc: 5, sp: 2, register: vec![] });
+
+        assert!(dbg.remove_breakpoint(2));
+        assert_eq!(dbg.cont().unwrap(), Status::Matched);
+        assert_eq!(dbg.snapshot().captures, vec![Some((1, 5))]);
+        assert_eq!(dbg.step().unwrap(), Status::Matched);
+
+        dbg.restart();
+        assert_eq!(dbg.snapshot().start, 0);
+        assert_eq!(dbg.cont().unwrap(), Status::Matched);
+    }
+
+    #[test]
+    fn test_failed() {
+        let mut dbg = Debugger::new("a{2}", "aba").unwrap();
+        assert_eq!(dbg.cont().unwrap(), Status::Failed);
+        assert_eq!(dbg.snapshot().start, 3);
+    }
+}
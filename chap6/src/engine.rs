@@ -1,24 +1,67 @@
+pub(crate) mod assertion;
 mod codegen;
+mod derivative;
+pub(crate) mod emit;
 mod evaluator;
+mod glob;
+#[cfg(test)]
+pub(crate) mod oracle;
 mod parser;
+mod printer;
+pub(crate) mod reverse;
+mod simplifier;
+pub(crate) mod trie;
+pub(crate) mod unicode;
 
-use crate::helper::DynError;
-use std::fmt::{self, Display};
+use crate::{
+    error::{Error, ErrorKind},
+    helper::DynError,
+};
+use std::{
+    borrow::Cow,
+    fmt::{self, Display},
+    ops::Range,
+};
 
-#[derive(Debug, PartialEq)]
+pub(crate) use evaluator::{
+    eval_captures, eval_captures_with, eval_longest, eval_partial, leftmost_start, CaptureSpans, DepthVm, Frame, Partial,
+    Scratch, Step,
+};
+pub(crate) use codegen::CompileLimits;
+pub use derivative::DerivativeRegex;
+pub use evaluator::{EvalError, EvalLimits};
+
+/// 評価器が実行する命令。
+/// 可変長の中身は `Cow` で持ち、`regex!` が埋め込んだプログラムは静的な配列をそのまま指す
+#[derive(Debug, Clone, PartialEq)]
 pub enum Instruction {
     Char(char),
-    UnmatchChars(Vec<char>),
+    UnmatchChars(Cow<'static, [char]>),
     Caret,
     Doller,
     Match,
+    /// 字句解析器の規則の番号を付けた match
+    MatchRule(usize),
     Jump(usize),
-    Split(usize, usize, (i32, Option<i32>), i32),
-    Descrement(usize),
+    Split(usize, usize),
+    CounterReset(usize),
+    CounterSplit(usize, usize, (usize, usize), usize),
+    CounterIncrement(usize),
     AnyNumber,
     NotNumber,
     CapcherBegin(i32),
     CapcherEnd(i32),
+    MatchChars(Cow<'static, [char]>),
+    Property(unicode::Property),
+    NotProperty(unicode::Property),
+    AnyChar,
+    AtomicBegin,
+    AtomicEnd,
+    Trie(trie::Trie),
+    /// 文字を読まずに位置を調べる
+    Assertion(assertion::Assertion),
+    /// 文字を読まずに、直前の文字列を逆向きのプログラムで調べる
+    LookBehind(reverse::LookBehind),
 }
 
 impl Display for Instruction {
@@ -29,26 +72,44 @@ impl Display for Instruction {
             Instruction::Caret => write!(f, "caret"),
             Instruction::Doller => write!(f, "doller"),
             Instruction::Match => write!(f, "match"),
+            Instruction::MatchRule(rule) => write!(f, "match rule {}", rule),
             Instruction::Jump(addr) => write!(f, "jump {:>04}", addr),
-            Instruction::Split(addr1, addr2, count, is_register_idx_increment) => {
+            Instruction::Split(addr1, addr2) => write!(f, "split {:>04}, {:>04}", addr1, addr2),
+            Instruction::CounterReset(idx) => write!(f, "counter reset {}", idx),
+            Instruction::CounterSplit(addr1, addr2, count, idx) => {
                 write!(
                     f,
-                    "split {:>04}, {:>04}, {:?}, {}",
-                    addr1, addr2, count, is_register_idx_increment
+                    "counter split {:>04}, {:>04}, {:?}, {}",
+                    addr1, addr2, count, idx
                 )
             }
-            Instruction::Descrement(idx) => write!(f, "decrement {}", idx),
+            Instruction::CounterIncrement(idx) => write!(f, "counter increment {}", idx),
             Instruction::AnyNumber => write!(f, "any number"),
             Instruction::NotNumber => write!(f, "not number"),
             Instruction::CapcherBegin(idx) => write!(f, "capcher begin {}", idx),
             Instruction::CapcherEnd(idx) => write!(f, "capcher end {}", idx),
+            Instruction::MatchChars(c) => write!(f, "match char {:?}", c),
+            Instruction::Property(p) => write!(f, "property {}", p.name),
+            Instruction::NotProperty(p) => write!(f, "not property {}", p.name),
+            Instruction::AnyChar => write!(f, "any char"),
+            Instruction::AtomicBegin => write!(f, "atomic begin"),
+            Instruction::AtomicEnd => write!(f, "atomic end"),
+            Instruction::Trie(t) => write!(f, "trie {} literals", t.literal_count()),
+            Instruction::Assertion(a) => write!(f, "assertion {}", a),
+            Instruction::LookBehind(l) => write!(
+                f,
+                "{} {} instructions, up to {} chars",
+                if l.is_negated() { "not look behind" } else { "look behind" },
+                l.code().len(),
+                l.max_len()
+            ),
         }
     }
 }
 
 pub fn print(expr: &str) -> Result<(), DynError> {
     println!("expr: {expr}");
-    let ast = parser::parse(expr)?;
+    let ast = check_depth(parser::parse(expr)?, &CompileLimits::default())?;
     println!("AST: {:?}", ast);
 
     println!();
@@ -61,9 +122,164 @@ pub fn print(expr: &str) -> Result<(), DynError> {
     Ok(())
 }
 
-pub fn do_matching(expr: &str, line: &str, index: usize, is_depth: bool) -> Result<(bool, Vec<String>), DynError> {
-    let ast = parser::parse(expr)?;
+/// パターン全体をグループ 0 で囲んでコンパイルする。マッチした範囲はグループ 0 の位置でわかる
+pub(crate) fn compile(expr: &str) -> Result<Vec<Instruction>, Error> {
+    Ok(compile_with_names(expr, &CompileLimits::default())?.code)
+}
+
+/// コンパイルしたパターン
+pub(crate) struct Program {
+    pub code: Vec<Instruction>,
+    /// i 番目がグループ i の名前で、名前のないグループは None
+    pub names: Vec<Option<String>>,
+    /// マッチの始まりを探す逆向きのプログラム。逆向きにできないパターンや
+    /// アトミックグループを含むパターンでは None
+    pub reverse: Option<Vec<Instruction>>,
+}
+
+/// `compile` に上限を付け、グループの名前と逆向きのプログラムも返す
+pub(crate) fn compile_with_names(expr: &str, limits: &CompileLimits) -> Result<Program, Error> {
+    let ast = check_depth(parser::AST::Chapcher(Box::new(parse(expr)?)), limits)?;
+    let code = codegen::get_code_with_limits(&ast, limits)?;
+    let mut names = Vec::new();
+    capture_names(&ast, &mut names);
+    // 逆向きのプログラムは速く探すためだけに使うので、作れなくてもエラーにしない
+    let reverse = codegen::get_reverse_code(&ast, limits).ok();
+    Ok(Program { code, names, reverse })
+}
+
+/// AST の深さが上限以下ならそのまま返す。深すぎる AST は再帰せずに捨てる。
+/// AST をたどる処理はどれも再帰するので、パースの直後に調べる
+fn check_depth(ast: parser::AST, limits: &CompileLimits) -> Result<parser::AST, Error> {
+    match codegen::check_depth(&ast, limits.max_depth) {
+        Ok(()) => Ok(ast),
+        Err(e) => {
+            parser::dispose(ast);
+            Err(e.into())
+        }
+    }
+}
+
+/// グループを開き括弧の順 (番号の順) にたどって名前を集める
+fn capture_names(ast: &parser::AST, names: &mut Vec<Option<String>>) {
+    use parser::AST;
+    match ast {
+        AST::Chapcher(e) => {
+            names.push(None);
+            capture_names(e, names);
+        }
+        AST::NamedChapcher(name, e) => {
+            names.push(Some(name.clone()));
+            capture_names(e, names);
+        }
+        AST::Plus(e)
+        | AST::Star(e)
+        | AST::Question(e)
+        | AST::Counter(e, _)
+        | AST::Atomic(e)
+        | AST::LookBehind(e)
+        | AST::NotLookBehind(e) => capture_names(e, names),
+        AST::Or(e1, e2) => {
+            capture_names(e1, names);
+            capture_names(e2, names);
+        }
+        AST::Seq(v) => v.iter().for_each(|e| capture_names(e, names)),
+        _ => (),
+    }
+}
+
+/// 字句解析器の規則をまとめてコンパイルする。規則 i にマッチすると match rule i に着く
+pub(crate) fn compile_rules(exprs: &[&str]) -> Result<Vec<Instruction>, Error> {
+    let mut rules = Vec::new();
+    for (i, expr) in exprs.iter().enumerate() {
+        let ast = parse(expr).and_then(|ast| check_depth(ast, &CompileLimits::default()));
+        rules.push(ast.map_err(|e| e.context(&format!("rule {i}")))?);
+    }
+    let code = codegen::get_rules_code(&rules)?;
+    // 最長一致は Pike VM で探すので、アトミックグループは使えない
+    if code.contains(&Instruction::AtomicBegin) {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            "lexer: atomic group is not supported".to_string(),
+            None,
+        ));
+    }
+    Ok(code)
+}
+
+/// グロブを、パス全体にマッチするプログラムにする。同じ意味の正規表現も返す
+pub(crate) fn compile_glob(pattern: &str) -> Result<(String, Vec<Instruction>), Error> {
+    let ast = glob::to_ast(pattern).map_err(|e| {
+        Error::new(ErrorKind::Syntax, e.to_string(), char_span(pattern, e.position()))
+    })?;
+    let ast = check_depth(ast, &CompileLimits::default())?;
+    let regex = printer::to_pattern(&ast);
+    let code = codegen::get_code(&parser::AST::Chapcher(Box::new(ast)))?;
+    Ok((regex, code))
+}
+
+fn parse(expr: &str) -> Result<parser::AST, Error> {
+    parser::parse(expr).map_err(|e| syntax_error(expr, e))
+}
+
+/// パースエラーの位置 (何文字目か) をパターン中のバイト範囲にする
+fn syntax_error(expr: &str, e: parser::ParseError) -> Error {
+    let span = e.position().and_then(|pos| char_span(expr, pos));
+    Error::new(ErrorKind::Syntax, e.to_string(), span)
+}
+
+/// `pos` 文字目のバイト範囲
+fn char_span(expr: &str, pos: usize) -> Option<Range<usize>> {
+    expr.char_indices().nth(pos).map(|(start, c)| start..start + c.len_utf8())
+}
+
+impl From<codegen::CodeGenError> for Error {
+    fn from(e: codegen::CodeGenError) -> Self {
+        let kind = if e.is_limit() {
+            ErrorKind::LimitExceeded
+        } else if e.is_unsupported() {
+            ErrorKind::Unsupported
+        } else {
+            ErrorKind::Compile
+        };
+        Error::new(kind, e.to_string(), None)
+    }
+}
+
+impl From<EvalError> for Error {
+    fn from(e: EvalError) -> Self {
+        let kind = match e {
+            EvalError::BudgetExceeded => ErrorKind::BudgetExceeded,
+            EvalError::Timeout => ErrorKind::Timeout,
+            _ => ErrorKind::Internal,
+        };
+        Error::new(kind, e.to_string(), None)
+    }
+}
+
+/// パターンを簡約した正規形の文字列にする。同じ言語を表すパターンを保存するときの表記をそろえるのに使う
+pub fn normalize(expr: &str) -> Result<String, DynError> {
+    let ast = check_depth(parser::parse(expr)?, &CompileLimits::default())?;
+    Ok(printer::to_pattern(&simplifier::simplify(ast)))
+}
+
+/// `line` の `start` 文字目 (検索の開始位置) からマッチを試す。
+/// `start` より前の文字は `^` や `\b` の判定にだけ使う
+pub fn do_matching(expr: &str, line: &str, start: usize, is_depth: bool) -> Result<(bool, Vec<Option<String>>), DynError> {
+    do_matching_with_limits(expr, line, start, is_depth, &EvalLimits::default())
+}
+
+/// `do_matching` に評価の上限を付けたもの。
+/// 上限に達すると `EvalError::BudgetExceeded` か `EvalError::Timeout` を返す。
+pub fn do_matching_with_limits(
+    expr: &str,
+    line: &str,
+    start: usize,
+    is_depth: bool,
+    limits: &EvalLimits,
+) -> Result<(bool, Vec<Option<String>>), DynError> {
+    let ast = check_depth(parser::parse(expr)?, &CompileLimits::default())?;
     let code = codegen::get_code(&ast)?;
     let line = line.chars().collect::<Vec<char>>();
-    Ok(evaluator::eval(&code, &line, index, is_depth)?)
+    Ok(evaluator::eval(&code, &line, start, is_depth, limits)?)
 }
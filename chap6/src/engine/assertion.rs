@@ -0,0 +1,84 @@
+//! 文字を読まずに、入力の位置だけを調べる `\A` `\z` `\Z` `\b` `\B`。
+
+use std::fmt::{self, Display};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Assertion {
+    /// `\A` 入力の先頭
+    StartText,
+    /// `\z` 入力の末尾
+    EndText,
+    /// `\Z` 入力の末尾か、末尾の改行の直前
+    EndTextNewline,
+    /// `\b` 単語の文字とそれ以外の文字の境目
+    WordBoundary,
+    /// `\B` 単語の境目でないところ
+    NotWordBoundary,
+}
+
+/// 単語の文字。英数字は Unicode の文字も含む
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+impl Assertion {
+    /// `\` の後ろに書く文字
+    pub fn escape(&self) -> char {
+        match self {
+            Assertion::StartText => 'A',
+            Assertion::EndText => 'z',
+            Assertion::EndTextNewline => 'Z',
+            Assertion::WordBoundary => 'b',
+            Assertion::NotWordBoundary => 'B',
+        }
+    }
+
+    /// 入力 `line` の `sp` の位置で成り立つか。
+    /// `sp` は検索の開始位置からではなく、入力全体の何文字目か
+    pub fn is_match(&self, line: &[char], sp: usize) -> bool {
+        let is_boundary = || {
+            let before = sp.checked_sub(1).and_then(|i| line.get(i)).is_some_and(|c| is_word_char(*c));
+            let after = line.get(sp).is_some_and(|c| is_word_char(*c));
+            before != after
+        };
+        match self {
+            Assertion::StartText => sp == 0,
+            Assertion::EndText => sp == line.len(),
+            Assertion::EndTextNewline => sp == line.len() || (sp + 1 == line.len() && line[sp] == '\n'),
+            Assertion::WordBoundary => is_boundary(),
+            Assertion::NotWordBoundary => !is_boundary(),
+        }
+    }
+
+    /// 入力の終わりで成り立たなくても、入力を足せば成り立つかもしれないか
+    pub fn looks_ahead(&self) -> bool {
+        matches!(self, Assertion::WordBoundary | Assertion::NotWordBoundary)
+    }
+}
+
+impl Display for Assertion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\\{}", self.escape())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Assertion;
+
+    #[test]
+    fn test_is_match() {
+        let line = "ab c\n".chars().collect::<Vec<char>>();
+        let positions = |a: Assertion| (0..=line.len()).filter(|sp| a.is_match(&line, *sp)).collect::<Vec<_>>();
+        assert_eq!(positions(Assertion::StartText), vec![0]);
+        assert_eq!(positions(Assertion::EndText), vec![5]);
+        assert_eq!(positions(Assertion::EndTextNewline), vec![4, 5]);
+        assert_eq!(positions(Assertion::WordBoundary), vec![0, 2, 3, 4]);
+        assert_eq!(positions(Assertion::NotWordBoundary), vec![1, 5]);
+
+        let line = "値_1 あ".chars().collect::<Vec<char>>();
+        assert!(!Assertion::WordBoundary.is_match(&line, 1));
+        assert!(Assertion::WordBoundary.is_match(&line, 3));
+        assert!(!Assertion::WordBoundary.is_match(&[], 0));
+    }
+}
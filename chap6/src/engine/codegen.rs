@@ -1,31 +1,111 @@
-use super::{parser::AST, Instruction};
+use super::{
+    assertion::Assertion,
+    parser::AST,
+    reverse::{self, LookBehind},
+    trie::Trie,
+    unicode::Property,
+    Instruction,
+};
 use crate::helper::safe_add;
 use std::{
     error::Error,
     fmt::{self, Display},
 };
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum CodeGenError {
     PCOverFlow,
     FailStar,
     FailOr,
     FailQuestion,
     FailCounter,
+    /// AST が上限より深い。値は上限
+    TooDeep(usize),
+    /// プログラムが上限の命令数を超える。値は上限
+    TooManyInstructions(usize),
+    /// `{n,m}` の回数が上限を超える。値は回数と上限
+    CounterTooLarge(usize, usize),
+    /// 後読みの中身が読む文字数に上限がない
+    UnboundedLookBehind,
+    /// 逆向きのプログラムにできない。値は使えなかったもの
+    NotReversible(&'static str),
+}
+
+impl CodeGenError {
+    /// `CompileLimits` の上限を超えたエラーか
+    pub fn is_limit(&self) -> bool {
+        matches!(
+            self,
+            CodeGenError::TooDeep(_) | CodeGenError::TooManyInstructions(_) | CodeGenError::CounterTooLarge(_, _)
+        )
+    }
+
+    /// この実装では扱えない書き方のエラーか
+    pub fn is_unsupported(&self) -> bool {
+        matches!(self, CodeGenError::UnboundedLookBehind | CodeGenError::NotReversible(_))
+    }
 }
 
 impl Display for CodeGenError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "CodeGenError: {:?}", self)
+        match self {
+            CodeGenError::TooDeep(limit) => write!(f, "CodeGenError: pattern is nested deeper than {limit}"),
+            CodeGenError::TooManyInstructions(limit) => {
+                write!(f, "CodeGenError: program exceeds {limit} instructions")
+            }
+            CodeGenError::CounterTooLarge(count, limit) => {
+                write!(f, "CodeGenError: repetition count {count} exceeds {limit}")
+            }
+            CodeGenError::UnboundedLookBehind => write!(f, "CodeGenError: lookbehind must have a bounded length"),
+            CodeGenError::NotReversible(what) => write!(f, "CodeGenError: {what} cannot be used in lookbehind"),
+            _ => write!(f, "CodeGenError: {:?}", self),
+        }
     }
 }
 
 impl Error for CodeGenError {}
 
-#[derive(Default, Debug)]
+/// これより多くの繰り返しが必要な `{n,m}` はレジスタを使ったループにする
+const UNROLL_LIMIT: usize = 16;
+
+/// コンパイルの上限。信頼できないパターンでメモリやスタックを使い切らないようにする
+#[derive(Debug, Clone, Copy)]
+pub struct CompileLimits {
+    /// AST の深さの上限。グループや繰り返しを 1 つ入れ子にするごとに深くなる
+    pub max_depth: usize,
+    /// プログラムの命令数の上限
+    pub max_insts: usize,
+    /// `{n,m}` の n と m の上限
+    pub max_count: usize,
+}
+
+impl Default for CompileLimits {
+    fn default() -> Self {
+        CompileLimits {
+            max_depth: 500,
+            max_insts: 100_000,
+            max_count: 65_535,
+        }
+    }
+}
+
+#[derive(Debug)]
 struct Generator {
     pc: usize,
     insts: Vec<Instruction>,
+    unroll_limit: usize,
+    limits: CompileLimits,
+}
+
+impl Default for Generator {
+    fn default() -> Self {
+        Generator {
+            pc: 0,
+            insts: Vec::new(),
+            unroll_limit: UNROLL_LIMIT,
+            limits: CompileLimits::default(),
+        }
+    }
 }
 
 impl Generator {
@@ -38,8 +118,49 @@ impl Generator {
         Ok(())
     }
 
+    /// 規則を優先度の高い順に並べ、それぞれの終わりに規則の番号を付けた match を置く
+    fn gen_rules(&mut self, rules: &[AST]) -> Result<(), CodeGenError> {
+        // カウンタのレジスタは規則をまたいで振る
+        let mut register_idx = 0;
+        let mut register_match_str_idx = 0;
+
+        //     split L1, L2
+        // L1: 規則 0 のコード
+        //     match rule 0
+        // L2: split L2', L3
+        // L2': 規則 1 のコード
+        //     match rule 1
+        // L3: 規則 2 のコード
+        //     match rule 2
+        let (last, rules) = rules.split_last().ok_or(CodeGenError::FailOr)?;
+        for (rule, ast) in rules.iter().enumerate() {
+            let split_addr = self.pc;
+            self.inc_pc()?;
+            self.insts.push(Instruction::Split(self.pc, 0));
+
+            self.gen_expr(ast, &mut register_idx, &mut register_match_str_idx)?;
+            self.inc_pc()?;
+            self.insts.push(Instruction::MatchRule(rule));
+
+            if let Some(Instruction::Split(_, l2)) = self.insts.get_mut(split_addr) {
+                *l2 = self.pc;
+            } else {
+                return Err(CodeGenError::FailOr);
+            }
+        }
+        self.gen_expr(last, &mut register_idx, &mut register_match_str_idx)?;
+        self.inc_pc()?;
+        self.insts.push(Instruction::MatchRule(rules.len()));
+        Ok(())
+    }
+
     fn inc_pc(&mut self) -> Result<(), CodeGenError> {
-        safe_add(&mut self.pc, &1, || CodeGenError::PCOverFlow)
+        safe_add(&mut self.pc, &1, || CodeGenError::PCOverFlow)?;
+        // 展開した繰り返しが大きくなりすぎる前に止める
+        if self.pc > self.limits.max_insts {
+            return Err(CodeGenError::TooManyInstructions(self.limits.max_insts));
+        }
+        Ok(())
     }
 
     fn gen_expr(&mut self, ast: &AST, register_idx: &mut i32, register_match_str_idx: &mut i32) -> Result<(), CodeGenError> {
@@ -56,7 +177,15 @@ impl Generator {
             AST::AnyNumber => self.get_number()?,
             AST::NotNumber => self.get_not_number()?,
             AST::Counter(e, count) => self.gen_counter(e, *count, register_idx, register_match_str_idx)?,
-            AST::Chapcher(e) => self.gen_capcher(e, register_idx, register_match_str_idx)?,
+            AST::Chapcher(e) | AST::NamedChapcher(_, e) => self.gen_capcher(e, register_idx, register_match_str_idx)?,
+            AST::MatchChars(c) => self.gen_match_chars(c.to_vec())?,
+            AST::Property(p) => self.gen_property(*p)?,
+            AST::NotProperty(p) => self.gen_not_property(*p)?,
+            AST::AnyChar => self.gen_any_char()?,
+            AST::Atomic(e) => self.gen_atomic(e, register_idx, register_match_str_idx)?,
+            AST::Assertion(a) => self.gen_assertion(*a)?,
+            AST::LookBehind(e) => self.gen_look_behind(e, false, register_match_str_idx)?,
+            AST::NotLookBehind(e) => self.gen_look_behind(e, true, register_match_str_idx)?,
         }
         Ok(())
     }
@@ -90,7 +219,35 @@ impl Generator {
     }
 
     fn gen_unmacth_chars(&mut self, c: Vec<char>) -> Result<(), CodeGenError> {
-        let inst = Instruction::UnmatchChars(c);
+        let inst = Instruction::UnmatchChars(c.into());
+        self.insts.push(inst);
+        self.inc_pc()?;
+        Ok(())
+    }
+
+    fn gen_match_chars(&mut self, c: Vec<char>) -> Result<(), CodeGenError> {
+        let inst = Instruction::MatchChars(c.into());
+        self.insts.push(inst);
+        self.inc_pc()?;
+        Ok(())
+    }
+
+    fn gen_property(&mut self, p: Property) -> Result<(), CodeGenError> {
+        let inst = Instruction::Property(p);
+        self.insts.push(inst);
+        self.inc_pc()?;
+        Ok(())
+    }
+
+    fn gen_not_property(&mut self, p: Property) -> Result<(), CodeGenError> {
+        let inst = Instruction::NotProperty(p);
+        self.insts.push(inst);
+        self.inc_pc()?;
+        Ok(())
+    }
+
+    fn gen_any_char(&mut self) -> Result<(), CodeGenError> {
+        let inst = Instruction::AnyChar;
         self.insts.push(inst);
         self.inc_pc()?;
         Ok(())
@@ -103,6 +260,12 @@ impl Generator {
         Ok(())
     }
 
+    fn gen_assertion(&mut self, assertion: Assertion) -> Result<(), CodeGenError> {
+        self.insts.push(Instruction::Assertion(assertion));
+        self.inc_pc()?;
+        Ok(())
+    }
+
     fn gen_doller(&mut self) -> Result<(), CodeGenError> {
         let inst = Instruction::Doller;
         self.insts.push(inst);
@@ -111,46 +274,99 @@ impl Generator {
     }
 
     fn gen_or(&mut self, e1: &AST, e2: &AST, register_idx: &mut i32, register_match_str_idx: &mut i32) -> Result<(), CodeGenError> {
-        let split_addr = self.pc;
-        self.inc_pc()?;
-        let split = Instruction::Split(self.pc, 0, (-1, None), -1);
-        self.insts.push(split);
+        // a|b|c は Or(a, Or(b, c)) になっているので、選択肢を平らに並べ直す
+        let mut alternatives = vec![e1];
+        let mut rest = e2;
+        while let AST::Or(e1, e2) = rest {
+            alternatives.push(e1);
+            rest = e2;
+        }
+        alternatives.push(rest);
+
+        // 文字列だけの選択肢が MIN_TRIE_LITERALS 個以上続くところは 1 つのトライ木にまとめる
+        let mut groups = Vec::new();
+        let mut literals = Vec::new();
+        for e in alternatives {
+            match literal(e) {
+                Some(l) => literals.push((e, l)),
+                None => {
+                    flush_literals(&mut groups, &mut literals);
+                    groups.push(Alternative::Expr(e));
+                }
+            }
+        }
+        flush_literals(&mut groups, &mut literals);
 
-        self.gen_expr(e1, register_idx, register_match_str_idx)?;
+        //     split L1, L2
+        // L1: e1 のコード
+        //     jump L4
+        // L2: split L2', L3
+        // L2': e2 のコード
+        //     jump L4
+        // L3: e3 のコード
+        // L4:
+        let mut jmp_addrs = Vec::new();
+        let last = groups.pop().ok_or(CodeGenError::FailOr)?;
+        for group in groups {
+            let split_addr = self.pc;
+            self.inc_pc()?;
+            let split = Instruction::Split(self.pc, 0);
+            self.insts.push(split);
 
-        let jmp_addr = self.pc;
-        self.insts.push(Instruction::Jump(0));
+            self.gen_alternative(group, register_idx, register_match_str_idx)?;
 
-        self.inc_pc()?;
-        if let Some(Instruction::Split(_, l2, (-1, None), -1)) = self.insts.get_mut(split_addr) {
-            *l2 = self.pc;
-        } else {
-            return Err(CodeGenError::FailOr);
+            jmp_addrs.push(self.pc);
+            self.insts.push(Instruction::Jump(0));
+
+            self.inc_pc()?;
+            if let Some(Instruction::Split(_, l2)) = self.insts.get_mut(split_addr) {
+                *l2 = self.pc;
+            } else {
+                return Err(CodeGenError::FailOr);
+            }
         }
 
-        self.gen_expr(e2, register_idx, register_match_str_idx)?;
+        self.gen_alternative(last, register_idx, register_match_str_idx)?;
 
-        if let Some(Instruction::Jump(l3)) = self.insts.get_mut(jmp_addr) {
-            *l3 = self.pc;
-        } else {
-            return Err(CodeGenError::FailOr);
+        for jmp_addr in jmp_addrs {
+            if let Some(Instruction::Jump(l4)) = self.insts.get_mut(jmp_addr) {
+                *l4 = self.pc;
+            } else {
+                return Err(CodeGenError::FailOr);
+            }
         }
 
         Ok(())
     }
 
+    fn gen_alternative(
+        &mut self,
+        alternative: Alternative,
+        register_idx: &mut i32,
+        register_match_str_idx: &mut i32,
+    ) -> Result<(), CodeGenError> {
+        match alternative {
+            Alternative::Expr(e) => self.gen_expr(e, register_idx, register_match_str_idx),
+            Alternative::Trie(trie) => {
+                let inst = Instruction::Trie(trie);
+                self.insts.push(inst);
+                self.inc_pc()
+            }
+        }
+    }
+
     fn gen_question(&mut self, e: &AST, register_idx: &mut i32, register_match_str_idx: &mut i32) -> Result<(), CodeGenError> {
         // split L1, L2
         let split_addr = self.pc;
         self.inc_pc()?;
-        let split = Instruction::Split(self.pc, 0, (-1, None), -1); // self.pcがL1。L2を仮に0と設定
+        let split = Instruction::Split(self.pc, 0); // self.pcがL1。L2を仮に0と設定
         self.insts.push(split);
 
         // L1: eのコード
         self.gen_expr(e, register_idx, register_match_str_idx)?;
 
         // L2の値を設定
-        if let Some(Instruction::Split(_, l2, (-1, None), -1)) = self.insts.get_mut(split_addr) {
+        if let Some(Instruction::Split(_, l2)) = self.insts.get_mut(split_addr) {
             *l2 = self.pc;
             Ok(())
         } else {
@@ -165,7 +381,7 @@ impl Generator {
 
         // split L1, L2
         self.inc_pc()?;
-        let split = Instruction::Split(l1, self.pc, (-1, None), -1); // self.pcがL2
+        let split = Instruction::Split(l1, self.pc); // self.pcがL2
         self.insts.push(split);
 
         Ok(())
@@ -175,7 +391,7 @@ impl Generator {
         // L1: split L2, L3
         let l1 = self.pc;
         self.inc_pc()?;
-        let split = Instruction::Split(self.pc, 0, (-1, None), -1); // self.pcがL2。L3を仮に0と設定
+        let split = Instruction::Split(self.pc, 0); // self.pcがL2。L3を仮に0と設定
         self.insts.push(split);
 
         // L2: eのコード
@@ -186,7 +402,7 @@ impl Generator {
         self.insts.push(Instruction::Jump(l1));
 
         // L3の値を設定
-        if let Some(Instruction::Split(_, l3, _, _)) = self.insts.get_mut(l1) {
+        if let Some(Instruction::Split(_, l3)) = self.insts.get_mut(l1) {
             *l3 = self.pc;
             Ok(())
         } else {
@@ -201,31 +417,121 @@ impl Generator {
         register_idx: &mut i32,
         register_match_str_idx: &mut i32,
     ) -> Result<(), CodeGenError> {
-        // L1: split L2, L3
-        println!("e: {:?}, count: {:?}, register_idx: {}", e, count, register_idx);
+        let (min, max) = count;
+        let limit = self.limits.max_count;
+        if let Some(count) = [Some(min), max].into_iter().flatten().find(|&n| n > limit) {
+            return Err(CodeGenError::CounterTooLarge(count, limit));
+        }
+
+        // e は何度も出力するが、キャプチャやレジスタの番号はどのコピーでも同じものを使う
+        let start = (*register_idx, *register_match_str_idx);
+        let mut end = start;
+
+        if max == Some(0) {
+            // e{0} はコードを出さないが、後ろのキャプチャの番号はずらさない
+            end.1 += count_capchers(e);
+        } else if max.unwrap_or(min + 1) <= self.unroll_limit {
+            self.gen_counter_unrolled(e, min, max, start, &mut end)?;
+        } else {
+            // e{n,} は e{n}e* として扱う
+            self.gen_copy(start, &mut end, |g, r, m| {
+                g.gen_counter_loop(e, min, max.unwrap_or(min), r, m)
+            })?;
+            if max.is_none() {
+                self.gen_copy(start, &mut end, |g, r, m| g.gen_star(e, r, m))?;
+            }
+        }
+
+        *register_idx = end.0;
+        *register_match_str_idx = end.1;
+        Ok(())
+    }
+
+    fn gen_copy<F>(&mut self, start: (i32, i32), end: &mut (i32, i32), f: F) -> Result<(), CodeGenError>
+    where
+        F: FnOnce(&mut Self, &mut i32, &mut i32) -> Result<(), CodeGenError>,
+    {
+        let (mut r, mut m) = start;
+        f(self, &mut r, &mut m)?;
+        *end = (end.0.max(r), end.1.max(m));
+        Ok(())
+    }
+
+    fn gen_counter_unrolled(
+        &mut self,
+        e: &AST,
+        min: usize,
+        max: Option<usize>,
+        start: (i32, i32),
+        end: &mut (i32, i32),
+    ) -> Result<(), CodeGenError> {
+        // e が min 回
+        for _ in 0..min {
+            self.gen_copy(start, end, |g, r, m| g.gen_expr(e, r, m))?;
+        }
+
+        let max = if let Some(max) = max {
+            max
+        } else {
+            return self.gen_copy(start, end, |g, r, m| g.gen_star(e, r, m));
+        };
+
+        // 残りは (e(e(e)?)?)? の形にして、どこで抜けても同じ L2 に飛ぶ
+        //     split L1, L2
+        // L1: e のコード
+        //     split L1', L2
+        // ...
+        // L2:
+        let mut split_addrs = Vec::new();
+        for _ in min..max {
+            split_addrs.push(self.pc);
+            self.inc_pc()?;
+            self.insts.push(Instruction::Split(self.pc, 0));
+            self.gen_copy(start, end, |g, r, m| g.gen_expr(e, r, m))?;
+        }
+        for addr in split_addrs {
+            if let Some(Instruction::Split(_, l2)) = self.insts.get_mut(addr) {
+                *l2 = self.pc;
+            } else {
+                return Err(CodeGenError::FailCounter);
+            }
+        }
+        Ok(())
+    }
+
+    fn gen_counter_loop(
+        &mut self,
+        e: &AST,
+        min: usize,
+        max: usize,
+        register_idx: &mut i32,
+        register_match_str_idx: &mut i32,
+    ) -> Result<(), CodeGenError> {
+        let reg = *register_idx as usize;
+        *register_idx += 1;
+
+        //     counter reset R
+        // L1: counter split L2, L3, (min, max), R
+        // L2: e のコード
+        //     counter increment R
+        //     jump L1
+        // L3:
+        self.inc_pc()?;
+        self.insts.push(Instruction::CounterReset(reg));
+
         let l1 = self.pc;
         self.inc_pc()?;
-        let split = Instruction::Split(
-            self.pc,
-            0,
-            (count.0 as i32, count.1.map(|c| c as i32)),
-            *register_idx,
-        ); // self.pcがL2。L3を仮に0と設定
+        let split = Instruction::CounterSplit(self.pc, 0, (min, max), reg); // self.pcがL2。L3を仮に0と設定
         self.insts.push(split);
-        let decrement = Instruction::Descrement(*register_idx as usize);
-        *register_idx += 1;
-        self.insts.push(decrement);
-        self.inc_pc()?;
 
-        // L2: eのコード
         self.gen_expr(e, register_idx, register_match_str_idx)?;
 
-        // jump L1
+        self.inc_pc()?;
+        self.insts.push(Instruction::CounterIncrement(reg));
         self.inc_pc()?;
         self.insts.push(Instruction::Jump(l1));
 
-        // L3の値を設定
-        if let Some(Instruction::Split(_, l3, _, _)) = self.insts.get_mut(l1) {
+        if let Some(Instruction::CounterSplit(_, l3, _, _)) = self.insts.get_mut(l1) {
             *l3 = self.pc;
             Ok(())
         } else {
@@ -233,6 +539,34 @@ impl Generator {
         }
     }
 
+    fn gen_atomic(
+        &mut self,
+        e: &AST,
+        register_idx: &mut i32,
+        register_match_str_idx: &mut i32,
+    ) -> Result<(), CodeGenError> {
+        // atomic begin でバックトラック用のスタックに印を付け、
+        // atomic end でその印より上に積まれた分岐を捨てる
+        self.insts.push(Instruction::AtomicBegin);
+        self.inc_pc()?;
+
+        self.gen_expr(e, register_idx, register_match_str_idx)?;
+
+        self.insts.push(Instruction::AtomicEnd);
+        self.inc_pc()?;
+        Ok(())
+    }
+
+    fn gen_look_behind(&mut self, e: &AST, negate: bool, register_match_str_idx: &mut i32) -> Result<(), CodeGenError> {
+        // 中のグループは記録しないが、後ろのグループの番号はずらさない
+        *register_match_str_idx += count_capchers(e);
+        let max_len = reverse::max_len(e).ok_or(CodeGenError::UnboundedLookBehind)?;
+        // 中身は逆向きにした別のプログラムにして、今の位置から左へ評価する
+        let code = get_code_with_limits(&reverse::reverse(e)?, &self.limits)?;
+        self.insts.push(Instruction::LookBehind(LookBehind::new(code.into(), max_len, negate)));
+        self.inc_pc()
+    }
+
     fn gen_capcher(
         &mut self,
         e: &AST,
@@ -254,15 +588,132 @@ impl Generator {
     }
 }
 
+/// これ以上の数の文字列が選択肢に並んでいたらトライ木にする
+const MIN_TRIE_LITERALS: usize = 3;
+
+enum Alternative<'a> {
+    Expr(&'a AST),
+    Trie(Trie),
+}
+
+/// 文字だけからなる式ならその文字列を返す
+fn literal(ast: &AST) -> Option<Vec<char>> {
+    match ast {
+        AST::Char(c) => Some(vec![*c]),
+        AST::Seq(v) if !v.is_empty() => v
+            .iter()
+            .map(|e| match e {
+                AST::Char(c) => Some(*c),
+                _ => None,
+            })
+            .collect(),
+        _ => None,
+    }
+}
+
+/// 続いた文字列の選択肢をトライ木にする。幅優先の評価器で優先度を守れないトライ木になるなら、
+/// 1 つずつ split で選ぶ
+fn flush_literals<'a>(groups: &mut Vec<Alternative<'a>>, literals: &mut Vec<(&'a AST, Vec<char>)>) {
+    if literals.len() >= MIN_TRIE_LITERALS {
+        let trie = Trie::new(literals.iter().map(|(_, l)| l.clone()).collect());
+        if trie.is_nested() {
+            groups.push(Alternative::Trie(trie));
+            literals.clear();
+            return;
+        }
+    }
+    groups.extend(literals.drain(..).map(|(e, _)| Alternative::Expr(e)));
+}
+
+fn count_capchers(ast: &AST) -> i32 {
+    match ast {
+        AST::Chapcher(e) | AST::NamedChapcher(_, e) => 1 + count_capchers(e),
+        AST::Plus(e)
+        | AST::Star(e)
+        | AST::Question(e)
+        | AST::Counter(e, _)
+        | AST::Atomic(e)
+        | AST::LookBehind(e)
+        | AST::NotLookBehind(e) => count_capchers(e),
+        AST::Or(e1, e2) => count_capchers(e1) + count_capchers(e2),
+        AST::Seq(v) => v.iter().map(count_capchers).sum(),
+        _ => 0,
+    }
+}
+
+/// AST の深さが `max_depth` 以下か調べる。再帰しないので、深すぎる AST でもスタックを使い切らない
+pub fn check_depth(ast: &AST, max_depth: usize) -> Result<(), CodeGenError> {
+    let mut stack = vec![(ast, 1)];
+    while let Some((ast, depth)) = stack.pop() {
+        if depth > max_depth {
+            return Err(CodeGenError::TooDeep(max_depth));
+        }
+        match ast {
+            AST::Plus(e)
+            | AST::Star(e)
+            | AST::Question(e)
+            | AST::Counter(e, _)
+            | AST::Chapcher(e)
+            | AST::NamedChapcher(_, e)
+            | AST::Atomic(e)
+            | AST::LookBehind(e)
+            | AST::NotLookBehind(e) => stack.push((e, depth + 1)),
+            AST::Or(e1, e2) => stack.extend([(&**e1, depth + 1), (&**e2, depth + 1)]),
+            AST::Seq(v) => stack.extend(v.iter().map(|e| (e, depth + 1))),
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
 pub fn get_code(ast: &AST) -> Result<Vec<Instruction>, CodeGenError> {
+    get_code_with_limits(ast, &CompileLimits::default())
+}
+
+/// `limits` の上限を付けてプログラムにする。AST の深さは `check_depth` で先に調べておく
+pub fn get_code_with_limits(ast: &AST, limits: &CompileLimits) -> Result<Vec<Instruction>, CodeGenError> {
+    let mut generator = Generator {
+        limits: *limits,
+        ..Generator::default()
+    };
+    generator.gen_code(ast)?;
+    Ok(generator.insts)
+}
+
+/// 逆順にした入力の先頭から、元の AST にマッチする範囲を読むプログラムにする。
+/// 終わりは `get_rules_code` と同じ match rule 0 なので、`eval_longest` で最も遠い位置を探せる
+pub fn get_reverse_code(ast: &AST, limits: &CompileLimits) -> Result<Vec<Instruction>, CodeGenError> {
+    let mut generator = Generator {
+        limits: *limits,
+        ..Generator::default()
+    };
+    generator.gen_rules(&[reverse::reverse(ast)?])?;
+    Ok(generator.insts)
+}
+
+/// 字句解析器の規則をまとめて 1 つのプログラムにする
+pub fn get_rules_code(rules: &[AST]) -> Result<Vec<Instruction>, CodeGenError> {
     let mut generator = Generator::default();
+    generator.gen_rules(rules)?;
+    Ok(generator.insts)
+}
+
+#[cfg(test)]
+pub(super) fn get_code_with_unroll_limit(ast: &AST, unroll_limit: usize) -> Result<Vec<Instruction>, CodeGenError> {
+    let mut generator = Generator {
+        unroll_limit,
+        ..Generator::default()
+    };
     generator.gen_code(ast)?;
     Ok(generator.insts)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{get_code, AST};
+    use super::{
+        check_depth, get_code, get_code_with_limits, get_reverse_code, get_rules_code, CodeGenError, CompileLimits, Trie,
+        AST,
+    };
 
     use crate::engine::Instruction::*;
     #[test]
@@ -278,8 +729,8 @@ mod tests {
         .unwrap(); //"a(bc|de)*f"
         assert_eq!(instructions, vec![
             Char('a'),
-            Split(2, 9, (-1, None), -1),
-            Split(3, 6, (-1, None), -1),
+            Split(2, 9),
+            Split(3, 6),
             Char('b'),
             Char('c'),
             Jump(8),
@@ -304,8 +755,8 @@ mod tests {
         .unwrap(); //"a(bc|de)?f"
         assert_eq!(instructions, vec![
             Char('a'),
-            Split(2, 8, (-1, None), -1),
-            Split(3, 6, (-1, None), -1),
+            Split(2, 8),
+            Split(3, 6),
             Char('b'),
             Char('c'),
             Jump(8),
@@ -329,13 +780,13 @@ mod tests {
         .unwrap(); //"a(bc|de)+f"
         assert_eq!(instructions, vec![
             Char('a'),
-            Split(2, 5, (-1, None), -1),
+            Split(2, 5),
             Char('b'),
             Char('c'),
             Jump(7),
             Char('d'),
             Char('e'),
-            Split(1, 8, (-1, None), -1),
+            Split(1, 8),
             Char('f'),
             Match,
         ]);
@@ -353,10 +804,8 @@ mod tests {
         assert_eq!(instructions, vec![
             Char('a'),
             Char('b'),
-            Split(3, 6, (2, Some(2)), 0),
-            Descrement(0),
-            UnmatchChars(vec!['c','d']),
-            Jump(2),
+            UnmatchChars(vec!['c','d'].into()),
+            UnmatchChars(vec!['c','d'].into()),
             Match
         ]);
     }
@@ -368,13 +817,14 @@ mod tests {
             AST::Counter(Box::new(AST::Char('d')), (1, Some(3))),
             AST::Char('f'),
         ]))
-        .unwrap(); //"ad{3}f"
+        .unwrap(); //"ad{1,3}f"
         assert_eq!(instructions, vec![
             Char('a'),
-            Split(2, 5, (1, Some(3)), 0),
-            Descrement(0),
             Char('d'),
-            Jump(1),
+            Split(3, 6),
+            Char('d'),
+            Split(5, 6),
+            Char('d'),
             Char('f'),
             Match,
         ]);
@@ -411,20 +861,222 @@ mod tests {
             Char('b'),
             CapcherBegin(0),
             CapcherBegin(1),
-            Split(5, 8, (2, Some(2)), 0),
-            Descrement(0),
             AnyNumber,
-            Jump(4),
+            AnyNumber,
             CapcherEnd(1),
             Char('-'),
             CapcherBegin(2),
-            Split(12, 15, (2, Some(2)), 1),
-            Descrement(1),
             AnyNumber,
+            AnyNumber,
+            CapcherEnd(2),
+            CapcherEnd(0),
+            Match,
+        ]);
+    }
+
+    #[test]
+    fn test_counter_loop() {
+        let instructions = get_code(&AST::Seq(vec![
+            AST::Char('a'),
+            AST::Counter(Box::new(AST::Char('d')), (20, Some(30))),
+            AST::Counter(Box::new(AST::Char('e')), (20, None)),
+        ]))
+        .unwrap(); //"ad{20,30}e{20,}"
+        assert_eq!(instructions, vec![
+            Char('a'),
+            CounterReset(0),
+            CounterSplit(3, 6, (20, 30), 0),
+            Char('d'),
+            CounterIncrement(0),
+            Jump(2),
+            CounterReset(1),
+            CounterSplit(8, 11, (20, 20), 1),
+            Char('e'),
+            CounterIncrement(1),
+            Jump(7),
+            Split(12, 14),
+            Char('e'),
             Jump(11),
+            Match,
+        ]);
+    }
+
+    #[test]
+    fn test_counter_capcher() {
+        let instructions = get_code(&AST::Seq(vec![
+            AST::Counter(Box::new(AST::Chapcher(Box::new(AST::Char('a')))), (0, Some(0))),
+            AST::Counter(Box::new(AST::Chapcher(Box::new(AST::Char('b')))), (2, Some(2))),
+            AST::Chapcher(Box::new(AST::Char('c'))),
+        ]))
+        .unwrap(); //"(a){0}(b){2}(c)"
+        assert_eq!(instructions, vec![
+            CapcherBegin(1),
+            Char('b'),
+            CapcherEnd(1),
+            CapcherBegin(1),
+            Char('b'),
+            CapcherEnd(1),
+            CapcherBegin(2),
+            Char('c'),
             CapcherEnd(2),
+            Match,
+        ]);
+    }
+
+    #[test]
+    fn test_atomic() {
+        let instructions = get_code(&AST::Seq(vec![
+            AST::Atomic(Box::new(AST::Star(Box::new(AST::Char('a'))))),
+            AST::Char('b'),
+        ]))
+        .unwrap(); //"a*+b"
+        assert_eq!(instructions, vec![
+            AtomicBegin,
+            Split(2, 4),
+            Char('a'),
+            Jump(1),
+            AtomicEnd,
+            Char('b'),
+            Match,
+        ]);
+    }
+
+    #[test]
+    fn test_trie() {
+        let literal = |s: &str| AST::Seq(s.chars().map(AST::Char).collect());
+        let instructions = get_code(&AST::Or(
+            Box::new(literal("error")),
+            Box::new(AST::Or(
+                Box::new(literal("warn")),
+                Box::new(AST::Or(
+                    Box::new(literal("fatal")),
+                    Box::new(AST::Or(
+                        Box::new(AST::Seq(vec![AST::Plus(Box::new(AST::Char('x')))])),
+                        Box::new(AST::Or(Box::new(literal("y")), Box::new(literal("z")))),
+                    )),
+                )),
+            )),
+        ))
+        .unwrap(); //"error|warn|fatal|x+|y|z"
+        let trie = Trie::new(vec![
+            "error".chars().collect(),
+            "warn".chars().collect(),
+            "fatal".chars().collect(),
+        ]);
+        assert_eq!(instructions, vec![
+            Split(1, 3),
+            Trie(trie),
+            Jump(11),
+            Split(4, 7),
+            Char('x'),
+            Split(4, 6),
+            Jump(11),
+            Split(8, 10),
+            Char('y'),
+            Jump(11),
+            Char('z'),
+            Match,
+        ]);
+
+        // 終わる選択肢の優先度が、同じ接頭辞を持つ選択肢の間に挟まるならトライ木にしない
+        let instructions = get_code(&AST::Or(
+            Box::new(literal("abc")),
+            Box::new(AST::Or(Box::new(literal("a")), Box::new(literal("abd")))),
+        ))
+        .unwrap(); //"abc|a|abd"
+        assert!(!instructions.iter().any(|i| matches!(i, Trie(_))));
+        let instructions = get_code(&AST::Or(
+            Box::new(literal("a")),
+            Box::new(AST::Or(Box::new(literal("abc")), Box::new(literal("abd")))),
+        ))
+        .unwrap(); //"a|abc|abd"
+        assert!(instructions.iter().any(|i| matches!(i, Trie(_))));
+    }
+
+    #[test]
+    fn test_rules() {
+        let instructions = get_rules_code(&[
+            AST::Char('a'),
+            AST::Seq(vec![AST::Char('b'), AST::Char('c')]),
+            AST::Plus(Box::new(AST::Char('d'))),
+        ])
+        .unwrap(); // a, bc, d+
+        assert_eq!(instructions, vec![
+            Split(1, 3),
+            Char('a'),
+            MatchRule(0),
+            Split(4, 7),
+            Char('b'),
+            Char('c'),
+            MatchRule(1),
+            Char('d'),
+            Split(7, 9),
+            MatchRule(2),
+        ]);
+    }
+
+    #[test]
+    fn test_look_behind() {
+        let capture = |c: char| AST::Chapcher(Box::new(AST::Char(c)));
+        let look_behind = |e: AST| AST::LookBehind(Box::new(e));
+        let instructions = get_code(&AST::Seq(vec![
+            capture('a'),
+            look_behind(AST::Seq(vec![AST::Char('b'), capture('c')])),
+            capture('d'),
+        ]))
+        .unwrap(); // (a)(?<=b(c))(d)
+        assert_eq!(instructions, vec![
+            CapcherBegin(0),
+            Char('a'),
             CapcherEnd(0),
+            LookBehind(crate::engine::reverse::LookBehind::new(vec![Char('c'), Char('b'), Match].into(), 2, false)),
+            CapcherBegin(2),
+            Char('d'),
+            CapcherEnd(2),
             Match,
         ]);
+
+        let unbounded = look_behind(AST::Plus(Box::new(AST::Char('a'))));
+        assert_eq!(get_code(&unbounded), Err(CodeGenError::UnboundedLookBehind));
+        let atomic = look_behind(AST::Atomic(Box::new(AST::Char('a'))));
+        assert_eq!(get_code(&atomic), Err(CodeGenError::NotReversible("atomic group")));
+        let nested = look_behind(look_behind(AST::Char('a')));
+        assert_eq!(get_code(&nested), Err(CodeGenError::NotReversible("lookbehind")));
+
+        let reversed = get_reverse_code(&AST::Seq(vec![AST::Caret, capture('a'), AST::Char('b')]), &Default::default());
+        assert_eq!(reversed, Ok(vec![Char('b'), Char('a'), Doller, MatchRule(0)]));
+    }
+
+    #[test]
+    fn test_limits() {
+        // a+ の中の (b|c)
+        let ast = AST::Plus(Box::new(AST::Chapcher(Box::new(AST::Or(
+            Box::new(AST::Char('b')),
+            Box::new(AST::Char('c')),
+        )))));
+        assert_eq!(check_depth(&ast, 4), Ok(()));
+        assert_eq!(check_depth(&ast, 3), Err(CodeGenError::TooDeep(3)));
+
+        let limits = CompileLimits {
+            max_insts: 8,
+            ..CompileLimits::default()
+        };
+        assert_eq!(get_code_with_limits(&ast, &limits).map(|code| code.len()), Ok(8));
+        let limits = CompileLimits {
+            max_insts: 7,
+            ..CompileLimits::default()
+        };
+        assert_eq!(get_code_with_limits(&ast, &limits), Err(CodeGenError::TooManyInstructions(7)));
+
+        let limits = CompileLimits {
+            max_count: 10,
+            ..CompileLimits::default()
+        };
+        let counter = |count| AST::Counter(Box::new(AST::Char('a')), count);
+        assert!(get_code_with_limits(&counter((10, None)), &limits).is_ok());
+        assert_eq!(
+            get_code_with_limits(&counter((2, Some(11))), &limits),
+            Err(CodeGenError::CounterTooLarge(11, 10))
+        );
     }
 }
@@ -0,0 +1,752 @@
+//! Brzozowski の微分による照合。
+//!
+//! 正規表現 r の文字 c による微分 d(r, c) は、r が表す文字列のうち c で始まるものから
+//! 先頭の c を取り除いた文字列の集合を表す。入力を 1 文字ずつ微分していき、
+//! 最後に空文字列を受理するかを見ればマッチするかがわかる。
+//!
+//! 微分した式を状態とみなすと DFA になる。式はスマートコンストラクタで正規化するので
+//! 状態の数は有限で、遷移は使ったものだけを遅延して作る。
+//! コード生成を経ないので、共通部分 (and) や補集合 (not) もそのまま扱える。
+//!
+//! キャプチャは区別せず、`^` `\A` と `$` `\z` は選択肢の先頭と末尾にあるものだけを扱う。
+//! `\Z` `\b` `\B` は使えない。
+//! アトミックグループは言語そのものを変えるので使えない。後読みも使えない。
+//! パターンの AST の節点の数には、コード生成の命令の数と同じ上限がある。
+//!
+//! DFA を入力なしに辿れば、言語が空か、2 つのパターンが同じ文字列にマッチするかも調べられる
+//! (`language` モジュール)。受け付けたパターンの言語が `Regex::is_match` が true になる文字列の
+//! 集合と同じことは、ランダムなパターンで `Regex` と突き合わせて確かめている。
+
+mod language;
+
+use super::{assertion::Assertion, parser::AST, unicode::Property, CompileLimits};
+use crate::error::{Error, ErrorKind};
+use std::{
+    cell::RefCell,
+    cmp::Ordering,
+    collections::{hash_map::DefaultHasher, HashMap},
+    fmt,
+    hash::{Hash, Hasher},
+    rc::{Rc, Weak},
+};
+
+/// 1 文字を読む式
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Class {
+    /// ソート済みの文字の集合
+    Chars(Vec<char>),
+    NotChars(Vec<char>),
+    Digit,
+    NotDigit,
+    Property(Property),
+    NotProperty(Property),
+    Any,
+}
+
+impl Class {
+    fn chars(mut chars: Vec<char>) -> Self {
+        chars.sort_unstable();
+        chars.dedup();
+        Class::Chars(chars)
+    }
+
+    fn not_chars(mut chars: Vec<char>) -> Self {
+        chars.sort_unstable();
+        chars.dedup();
+        Class::NotChars(chars)
+    }
+
+    pub fn contains(&self, c: char) -> bool {
+        match self {
+            Class::Chars(chars) => chars.binary_search(&c).is_ok(),
+            Class::NotChars(chars) => chars.binary_search(&c).is_err(),
+            Class::Digit => c.is_ascii_digit(),
+            Class::NotDigit => !c.is_ascii_digit(),
+            Class::Property(p) => p.contains(c),
+            Class::NotProperty(p) => !p.contains(c),
+            Class::Any => true,
+        }
+    }
+}
+
+/// 式の形。子は `Re` なので、比べるのもハッシュを取るのも子の番号を見るだけで済む
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub enum Kind {
+    /// 何にもマッチしない
+    Empty,
+    /// 空文字列だけにマッチする
+    Epsilon,
+    Class(Class),
+    /// 右結合。左は Seq にならない
+    Seq(Re, Re),
+    /// 番号の順に並べた、重複のない 2 つ以上の選択肢
+    Or(Vec<Re>),
+    /// 番号の順に並べた、重複のない 2 つ以上の式の共通部分
+    And(Vec<Re>),
+    Not(Re),
+    Star(Re),
+    /// r{min,max}。{0,0} {1,1} {0,} は作らない
+    Counter(Re, usize, Option<usize>),
+}
+
+impl Kind {
+    /// 子を取り出して、自分は Empty になる
+    fn take_children(&mut self) -> Vec<Re> {
+        match std::mem::replace(self, Kind::Empty) {
+            Kind::Empty | Kind::Epsilon | Kind::Class(_) => Vec::new(),
+            Kind::Seq(r1, r2) => vec![r1, r2],
+            Kind::Or(v) | Kind::And(v) => v,
+            Kind::Not(r) | Kind::Star(r) | Kind::Counter(r, _, _) => vec![r],
+        }
+    }
+
+    fn nullable(&self) -> bool {
+        match self {
+            Kind::Empty | Kind::Class(_) => false,
+            Kind::Epsilon | Kind::Star(_) => true,
+            Kind::Seq(r1, r2) => r1.nullable() && r2.nullable(),
+            Kind::Or(v) => v.iter().any(Re::nullable),
+            Kind::And(v) => v.iter().all(Re::nullable),
+            Kind::Not(r) => !r.nullable(),
+            Kind::Counter(r, min, _) => *min == 0 || r.nullable(),
+        }
+    }
+}
+
+struct Node {
+    /// 作った順の番号。同じ形の式は同じ節点になるので、番号が同じなら同じ式
+    id: u64,
+    nullable: bool,
+    kind: Kind,
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        // 長い連接を子から順に再帰して捨てるとスタックが溢れるので、
+        // 最後の参照になった子孫を 1 つの列に集めて捨てる
+        let mut children = self.kind.take_children();
+        while let Some(Re(child)) = children.pop() {
+            if let Ok(mut node) = Rc::try_unwrap(child) {
+                children.append(&mut node.kind.take_children());
+            }
+        }
+    }
+}
+
+/// 作った節点の表。同じ形の式を作ろうとしたら、前に作った節点を返す (hash consing)
+#[derive(Default)]
+struct Interner {
+    /// 形のハッシュ値ごとの節点。捨てられた節点は `purge` で取り除くまで残る
+    nodes: HashMap<u64, Vec<Weak<Node>>>,
+    /// `nodes` に入っている節点の数
+    len: usize,
+    /// `len` がこれを超えたら `purge` する
+    purge_at: usize,
+    next_id: u64,
+}
+
+/// `purge` するまでに溜める節点の数の最小値
+const MIN_PURGE_AT: usize = 1024;
+
+impl Interner {
+    fn purge(&mut self) {
+        self.nodes.retain(|_, nodes| {
+            nodes.retain(|node| node.strong_count() > 0);
+            !nodes.is_empty()
+        });
+        self.len = self.nodes.values().map(Vec::len).sum();
+        self.purge_at = (self.len * 2).max(MIN_PURGE_AT);
+    }
+}
+
+thread_local! {
+    static INTERNER: RefCell<Interner> = RefCell::new(Interner::default());
+}
+
+/// 微分で扱う式。スマートコンストラクタを通して作り、いつも正規形に保つ。
+///
+/// 同じ形の式はスレッドの中で 1 つの節点を共有するので、比較とハッシュは節点の番号だけで済む。
+/// 式がどれだけ大きくても、DFA の状態を引くのに式全体をなめない
+#[derive(Clone)]
+pub struct Re(Rc<Node>);
+
+impl PartialEq for Re {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.id == other.0.id
+    }
+}
+
+impl Eq for Re {}
+
+impl Hash for Re {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.id.hash(state);
+    }
+}
+
+impl PartialOrd for Re {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Re {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.id.cmp(&other.0.id)
+    }
+}
+
+impl fmt::Debug for Re {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.kind.fmt(f)
+    }
+}
+
+impl Re {
+    fn intern(kind: Kind) -> Re {
+        let mut hasher = DefaultHasher::new();
+        kind.hash(&mut hasher);
+        let hash = hasher.finish();
+        INTERNER.with(|interner| {
+            let mut interner = interner.borrow_mut();
+            if interner.len >= interner.purge_at {
+                interner.purge();
+            }
+            let interner = &mut *interner;
+            let nodes = interner.nodes.entry(hash).or_default();
+            // 同じ式を作っては捨てるのを繰り返すと、捨てられた節点が同じ場所に溜まるので、引くたびに除く
+            let len = nodes.len();
+            nodes.retain(|node| node.strong_count() > 0);
+            interner.len -= len - nodes.len();
+            if let Some(node) = nodes.iter().filter_map(Weak::upgrade).find(|node| node.kind == kind) {
+                return Re(node);
+            }
+            let node = Rc::new(Node {
+                id: interner.next_id,
+                nullable: kind.nullable(),
+                kind,
+            });
+            nodes.push(Rc::downgrade(&node));
+            interner.next_id += 1;
+            interner.len += 1;
+            Re(node)
+        })
+    }
+
+    pub fn kind(&self) -> &Kind {
+        &self.0.kind
+    }
+
+    pub fn empty() -> Re {
+        Re::intern(Kind::Empty)
+    }
+
+    pub fn epsilon() -> Re {
+        Re::intern(Kind::Epsilon)
+    }
+
+    pub fn class(class: Class) -> Re {
+        Re::intern(Kind::Class(class))
+    }
+
+    /// すべての文字列
+    pub fn any_string() -> Re {
+        Re::intern(Kind::Not(Re::empty()))
+    }
+
+    fn is_empty(&self) -> bool {
+        matches!(self.kind(), Kind::Empty)
+    }
+
+    pub fn seq(r1: Re, r2: Re) -> Re {
+        match (r1.kind(), r2.kind()) {
+            (Kind::Empty, _) | (_, Kind::Empty) => return Re::empty(),
+            (Kind::Epsilon, _) => return r2,
+            (_, Kind::Epsilon) => return r1,
+            _ => (),
+        }
+        // 左が連接なら右結合に組み直す。左の要素を並べてから右から繋ぐので、長い連接でも再帰しない
+        let mut heads = Vec::new();
+        let mut left = r1;
+        while let Kind::Seq(a, b) = left.kind() {
+            heads.push(a.clone());
+            left = b.clone();
+        }
+        let mut r = Re::intern(Kind::Seq(left, r2));
+        while let Some(head) = heads.pop() {
+            r = Re::intern(Kind::Seq(head, r));
+        }
+        r
+    }
+
+    pub fn or(alternatives: Vec<Re>) -> Re {
+        let mut flat = Vec::new();
+        for r in alternatives {
+            match r.kind() {
+                Kind::Or(v) => flat.extend(v.iter().cloned()),
+                Kind::Empty => (),
+                _ => flat.push(r),
+            }
+        }
+        flat.sort_unstable();
+        flat.dedup();
+        if flat.contains(&Re::any_string()) {
+            return Re::any_string();
+        }
+        match flat.len() {
+            0 => Re::empty(),
+            1 => flat.pop().unwrap(),
+            _ => Re::intern(Kind::Or(flat)),
+        }
+    }
+
+    pub fn and(rs: Vec<Re>) -> Re {
+        let any = Re::any_string();
+        let mut flat = Vec::new();
+        for r in rs {
+            match r.kind() {
+                Kind::And(v) => flat.extend(v.iter().cloned()),
+                _ if r == any => (),
+                _ => flat.push(r),
+            }
+        }
+        flat.sort_unstable();
+        flat.dedup();
+        if flat.iter().any(Re::is_empty) {
+            return Re::empty();
+        }
+        match flat.len() {
+            0 => any,
+            1 => flat.pop().unwrap(),
+            _ => Re::intern(Kind::And(flat)),
+        }
+    }
+
+    #[allow(clippy::should_implement_trait)]
+    pub fn not(r: Re) -> Re {
+        match r.kind() {
+            Kind::Not(r) => r.clone(),
+            _ => Re::intern(Kind::Not(r)),
+        }
+    }
+
+    pub fn star(r: Re) -> Re {
+        match r.kind() {
+            Kind::Empty | Kind::Epsilon => Re::epsilon(),
+            Kind::Star(_) => r,
+            _ => Re::intern(Kind::Star(r)),
+        }
+    }
+
+    pub fn counter(r: Re, min: usize, max: Option<usize>) -> Re {
+        match (r.kind(), min, max) {
+            (_, 0, Some(0)) => Re::epsilon(),
+            (Kind::Epsilon, _, _) => Re::epsilon(),
+            (Kind::Empty, 0, _) => Re::epsilon(),
+            (Kind::Empty, _, _) => Re::empty(),
+            (_, 1, Some(1)) => r,
+            (_, 0, None) => Re::star(r),
+            _ => Re::intern(Kind::Counter(r, min, max)),
+        }
+    }
+
+    /// 空文字列にマッチするか。節点を作るときに求めてある
+    pub fn nullable(&self) -> bool {
+        self.0.nullable
+    }
+
+    /// 文字 c による微分
+    pub fn derive(&self, c: char) -> Re {
+        match self.kind() {
+            Kind::Empty | Kind::Epsilon => Re::empty(),
+            Kind::Class(class) => {
+                if class.contains(c) {
+                    Re::epsilon()
+                } else {
+                    Re::empty()
+                }
+            }
+            Kind::Seq(..) => {
+                // d(r1 r2) = d(r1) r2 | (r1 が空文字列にマッチするなら) d(r2)。
+                // 連接は右に長く伸びるので、r2 の微分は再帰せずに右へ辿って求める
+                let mut alternatives = Vec::new();
+                let mut r = self;
+                while let Kind::Seq(r1, r2) = r.kind() {
+                    alternatives.push(Re::seq(r1.derive(c), r2.clone()));
+                    if !r1.nullable() {
+                        return Re::or(alternatives);
+                    }
+                    r = r2;
+                }
+                alternatives.push(r.derive(c));
+                Re::or(alternatives)
+            }
+            Kind::Or(v) => Re::or(v.iter().map(|r| r.derive(c)).collect()),
+            Kind::And(v) => Re::and(v.iter().map(|r| r.derive(c)).collect()),
+            Kind::Not(r) => Re::not(r.derive(c)),
+            Kind::Star(r) => Re::seq(r.derive(c), self.clone()),
+            Kind::Counter(r, min, max) => {
+                // {0,0} は作らないので max は 1 以上
+                let rest = Re::counter(r.clone(), min.saturating_sub(1), max.map(|max| max - 1));
+                Re::seq(r.derive(c), rest)
+            }
+        }
+    }
+}
+
+fn unsupported(what: &str) -> Error {
+    Error::new(ErrorKind::Unsupported, format!("derivative: {what} is not supported"), None)
+}
+
+/// AST の節点の数。深さは `check_depth` で抑えてあるので再帰してよい
+fn size(ast: &AST) -> usize {
+    1 + match ast {
+        AST::Plus(e)
+        | AST::Star(e)
+        | AST::Question(e)
+        | AST::Counter(e, _)
+        | AST::Chapcher(e)
+        | AST::NamedChapcher(_, e)
+        | AST::Atomic(e)
+        | AST::LookBehind(e)
+        | AST::NotLookBehind(e) => size(e),
+        AST::Or(e1, e2) => size(e1) + size(e2),
+        AST::Seq(v) => v.iter().map(size).sum(),
+        _ => 0,
+    }
+}
+
+/// AST を式にする。キャプチャは区別しない
+fn from_ast(ast: &AST) -> Result<Re, Error> {
+    Ok(match ast {
+        AST::Char(c) => Re::class(Class::chars(vec![*c])),
+        AST::MatchChars(chars) => Re::class(Class::chars(chars.clone())),
+        AST::UnmatchChars(chars) => Re::class(Class::not_chars(chars.clone())),
+        AST::AnyNumber => Re::class(Class::Digit),
+        AST::NotNumber => Re::class(Class::NotDigit),
+        AST::Property(p) => Re::class(Class::Property(*p)),
+        AST::NotProperty(p) => Re::class(Class::NotProperty(*p)),
+        AST::AnyChar => Re::class(Class::Any),
+        AST::Plus(e) => {
+            let r = from_ast(e)?;
+            Re::seq(r.clone(), Re::star(r))
+        }
+        AST::Star(e) => Re::star(from_ast(e)?),
+        AST::Question(e) => Re::or(vec![from_ast(e)?, Re::epsilon()]),
+        AST::Counter(e, (min, max)) => Re::counter(from_ast(e)?, *min, *max),
+        AST::Or(e1, e2) => Re::or(vec![from_ast(e1)?, from_ast(e2)?]),
+        AST::Seq(v) => {
+            let mut r = Re::epsilon();
+            for e in v.iter().rev() {
+                r = Re::seq(from_ast(e)?, r);
+            }
+            r
+        }
+        AST::Chapcher(e) | AST::NamedChapcher(_, e) => from_ast(e)?,
+        AST::Caret => return Err(unsupported("^ not at the start of an alternative")),
+        AST::Doller => return Err(unsupported("$ not at the end of an alternative")),
+        AST::Atomic(_) => return Err(unsupported("atomic group")),
+        AST::LookBehind(_) | AST::NotLookBehind(_) => return Err(unsupported("lookbehind")),
+        AST::Assertion(Assertion::StartText) => return Err(unsupported("\\A not at the start of an alternative")),
+        AST::Assertion(Assertion::EndText) => return Err(unsupported("\\z not at the end of an alternative")),
+        AST::Assertion(a) => return Err(unsupported(&a.to_string())),
+    })
+}
+
+/// パターンを、どこかにマッチを含む文字列の集合 (`Regex::is_match` が true になる文字列) にする。
+/// 選択肢の先頭の `^` と末尾の `$` は、その側に任意の文字列を足さないことを表す
+fn from_pattern(ast: &AST) -> Result<Re, Error> {
+    let mut alternatives = Vec::new();
+    let mut stack = vec![ast];
+    while let Some(ast) = stack.pop() {
+        if let AST::Or(e1, e2) = ast {
+            stack.push(e2);
+            stack.push(e1);
+            continue;
+        }
+        let mut seq = match ast {
+            AST::Seq(v) => &v[..],
+            ast => std::slice::from_ref(ast),
+        };
+        let mut start = Re::any_string();
+        let mut end = Re::any_string();
+        if let [AST::Caret | AST::Assertion(Assertion::StartText), rest @ ..] = seq {
+            start = Re::epsilon();
+            seq = rest;
+        }
+        if let [rest @ .., AST::Doller | AST::Assertion(Assertion::EndText)] = seq {
+            end = Re::epsilon();
+            seq = rest;
+        }
+        let mut r = end;
+        for e in seq.iter().rev() {
+            r = Re::seq(from_ast(e)?, r);
+        }
+        alternatives.push(Re::seq(start, r));
+    }
+    Ok(Re::or(alternatives))
+}
+
+/// 微分した式を状態とする遅延 DFA
+#[derive(Debug, Default)]
+struct Dfa {
+    states: Vec<Re>,
+    ids: HashMap<Re, usize>,
+    transitions: HashMap<(usize, char), usize>,
+}
+
+impl Dfa {
+    fn state(&mut self, r: Re) -> usize {
+        if let Some(id) = self.ids.get(&r) {
+            return *id;
+        }
+        self.states.push(r.clone());
+        self.ids.insert(r, self.states.len() - 1);
+        self.states.len() - 1
+    }
+
+    fn next(&mut self, state: usize, c: char) -> usize {
+        if let Some(next) = self.transitions.get(&(state, c)) {
+            return *next;
+        }
+        let next = self.state(self.states[state].derive(c));
+        self.transitions.insert((state, c), next);
+        next
+    }
+}
+
+/// 微分で照合する正規表現。`and` `or` `not` で組み合わせられる。
+///
+/// `is_match` は `Regex::is_match` と同じく、入力のどこかにマッチがあるかを返す。
+/// 遷移を内部に覚えていくので `Sync` ではない。
+#[derive(Debug)]
+pub struct DerivativeRegex {
+    re: Re,
+    dfa: RefCell<Dfa>,
+}
+
+impl DerivativeRegex {
+    pub fn new(pattern: &str) -> Result<Self, Error> {
+        let limits = CompileLimits::default();
+        let ast = super::check_depth(super::parse(pattern)?, &limits)?;
+        // 式はコード生成しないので、命令の数の上限を AST の大きさに当てる
+        if size(&ast) > limits.max_insts {
+            return Err(Error::new(
+                ErrorKind::LimitExceeded,
+                format!("derivative: pattern has more than {} nodes", limits.max_insts),
+                None,
+            ));
+        }
+        Ok(Self::from_re(from_pattern(&ast)?))
+    }
+
+    fn from_re(re: Re) -> Self {
+        let mut dfa = Dfa::default();
+        dfa.state(re.clone());
+        DerivativeRegex {
+            re,
+            dfa: RefCell::new(dfa),
+        }
+    }
+
+    /// 両方にマッチする入力にマッチする
+    pub fn and(&self, other: &DerivativeRegex) -> Self {
+        Self::from_re(Re::and(vec![self.re.clone(), other.re.clone()]))
+    }
+
+    /// どちらかにマッチする入力にマッチする
+    pub fn or(&self, other: &DerivativeRegex) -> Self {
+        Self::from_re(Re::or(vec![self.re.clone(), other.re.clone()]))
+    }
+
+    /// マッチしない入力にマッチする
+    #[allow(clippy::should_implement_trait)]
+    pub fn not(&self) -> Self {
+        Self::from_re(Re::not(self.re.clone()))
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        let mut dfa = self.dfa.borrow_mut();
+        let mut state = 0;
+        for c in text.chars() {
+            state = dfa.next(state, c);
+            // 何にもマッチしない状態からは抜け出せない
+            if dfa.states[state].is_empty() {
+                return false;
+            }
+        }
+        dfa.states[state].nullable()
+    }
+
+    /// マッチする最短の文字列。同じ長さのものが複数あればどれか 1 つ。何にもマッチしなければ None
+    pub fn shortest_match(&self) -> Result<Option<String>, Error> {
+        language::shortest(&self.re)
+    }
+
+    /// どんな入力にもマッチしないか
+    pub fn is_empty(&self) -> Result<bool, Error> {
+        Ok(self.shortest_match()?.is_none())
+    }
+
+    /// マッチする入力が、すべて `other` にもマッチするか
+    pub fn is_subset(&self, other: &DerivativeRegex) -> Result<bool, Error> {
+        self.and(&other.not()).is_empty()
+    }
+
+    /// `other` とマッチする入力が同じか
+    pub fn is_equivalent(&self, other: &DerivativeRegex) -> Result<bool, Error> {
+        Ok(self.counterexample(other)?.is_none())
+    }
+
+    /// どちらか一方だけにマッチする最短の入力。マッチする入力が同じなら None
+    pub fn counterexample(&self, other: &DerivativeRegex) -> Result<Option<String>, Error> {
+        let only_self = Re::and(vec![self.re.clone(), Re::not(other.re.clone())]);
+        let only_other = Re::and(vec![Re::not(self.re.clone()), other.re.clone()]);
+        language::shortest(&Re::or(vec![only_self, only_other]))
+    }
+
+    /// これまでに作った DFA の状態の数
+    pub fn state_count(&self) -> usize {
+        self.dfa.borrow().states.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DerivativeRegex, Re};
+    use crate::{error::ErrorKind, Regex};
+
+    /// `alphabet` の文字からなる長さ `max_len` までの文字列すべて
+    pub(super) fn strings(alphabet: &[char], max_len: usize) -> Vec<String> {
+        let mut all = vec![String::new()];
+        let mut last = vec![String::new()];
+        for _ in 0..max_len {
+            last = last
+                .iter()
+                .flat_map(|s| alphabet.iter().map(move |c| format!("{s}{c}")))
+                .collect();
+            all.extend(last.iter().cloned());
+        }
+        all
+    }
+
+    #[test]
+    fn test_smart_constructors() {
+        let a = || Re::class(super::Class::chars(vec!['a']));
+        assert_eq!(Re::seq(Re::epsilon(), a()), a());
+        assert_eq!(Re::seq(a(), Re::empty()), Re::empty());
+        assert_eq!(Re::or(vec![a(), Re::empty(), a()]), a());
+        assert_eq!(Re::or(vec![a(), Re::any_string()]), Re::any_string());
+        assert_eq!(Re::and(vec![a(), Re::any_string()]), a());
+        assert_eq!(Re::and(vec![a(), Re::empty()]), Re::empty());
+        assert_eq!(Re::not(Re::not(a())), a());
+        assert_eq!(Re::star(Re::star(a())), Re::star(a()));
+        assert_eq!(Re::counter(a(), 0, None), Re::star(a()));
+        assert_eq!(Re::counter(a(), 1, Some(1)), a());
+        assert_eq!(Re::counter(a(), 0, Some(0)), Re::epsilon());
+    }
+
+    /// 深さ優先の評価器と同じ結果になるか、短い入力をすべて試して確かめる
+    #[test]
+    fn test_cross_check() {
+        let patterns = [
+            "abc|def",
+            "[abc]*",
+            "[ab|cd]+",
+            "abc?",
+            "a.c",
+            "^abcd",
+            "abcd$",
+            "ab[cd|ef]$",
+            "a\\d+b",
+            "ad{2}b",
+            "ab[cd|ef]{1,3}b",
+            "a{2,}b",
+            "^a[^b]c$",
+            "^(a|b)*c$",
+            "^[a?]{3}b",
+            "^$",
+            "a|^b|c$",
+            "(?i)A[b|C]",
+            "\\p{Lu}a",
+            "\\Aab|b\\z",
+        ];
+        let alphabet = ['a', 'b', 'c', 'A', 'C', '1'];
+        let inputs = strings(&alphabet, 5);
+        for pattern in patterns {
+            let derivative = DerivativeRegex::new(pattern).unwrap();
+            let regex = Regex::new(pattern).unwrap();
+            for input in &inputs {
+                assert_eq!(derivative.is_match(input), regex.is_match(input).unwrap(), "{pattern} on {input:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_and_not() {
+        // a を含み、b を含まない
+        let a = DerivativeRegex::new("a").unwrap();
+        let b = DerivativeRegex::new("b").unwrap();
+        let r = a.and(&b.not());
+        assert!(r.is_match("cac"));
+        assert!(!r.is_match("cab"));
+        assert!(!r.is_match("cc"));
+        assert!(a.or(&b).is_match("b"));
+
+        // 偶数長で、3 の倍数長でない
+        let even = DerivativeRegex::new("^[..]*$").unwrap();
+        let three = DerivativeRegex::new("^[...]*$").unwrap();
+        let r = even.and(&three.not());
+        let lens = (0..10).filter(|n| r.is_match(&"x".repeat(*n))).collect::<Vec<usize>>();
+        assert_eq!(lens, vec![2, 4, 8]);
+    }
+
+    #[test]
+    fn test_lazy_dfa() {
+        // 状態は使った分だけ作られ、長い入力でも増えない
+        let r = DerivativeRegex::new("^[ab]*abb$").unwrap();
+        assert!(r.is_match(&("ab".repeat(10_000) + "abb")));
+        assert!(!r.is_match(&"ab".repeat(10_000)));
+        assert!(r.state_count() <= 6);
+    }
+
+    #[test]
+    fn test_unsupported() {
+        assert_eq!(DerivativeRegex::new("a^b").unwrap_err().kind(), ErrorKind::Unsupported);
+        assert_eq!(DerivativeRegex::new("(?>a*)b").unwrap_err().kind(), ErrorKind::Unsupported);
+        assert_eq!(DerivativeRegex::new("\\bab").unwrap_err().kind(), ErrorKind::Unsupported);
+        assert_eq!(DerivativeRegex::new("(").unwrap_err().kind(), ErrorKind::Syntax);
+
+        // 深すぎるパターンは Re にする前にエラーになる
+        let deep = format!("{}a{}", "(".repeat(100_000), ")".repeat(100_000));
+        assert_eq!(DerivativeRegex::new(&deep).unwrap_err().kind(), ErrorKind::LimitExceeded);
+    }
+
+    #[test]
+    fn test_long_pattern() {
+        // 長い連接を作る・比べる・捨てるのが再帰しないことを、小さいスタックで確かめる
+        let handle = std::thread::Builder::new()
+            .stack_size(2 << 20)
+            .spawn(|| {
+                let text = "a".repeat(99_000);
+                let r = DerivativeRegex::new(&text).unwrap();
+                assert!(!r.is_match("b"));
+                drop(r);
+                let r = DerivativeRegex::new(&format!("^{text}$")).unwrap();
+                assert!(r.is_match(&text));
+                assert!(!r.is_match(&text[1..]));
+                drop(r);
+
+                // 比較とハッシュが式の大きさによらないので、長いリテラルでも速い
+                let text = "abcdefghij".repeat(100);
+                let r = DerivativeRegex::new(&text).unwrap();
+                assert!(r.is_match(&text));
+                assert!(!r.is_match(&text[1..]));
+
+                let long = "a".repeat(100_001);
+                assert_eq!(DerivativeRegex::new(&long).unwrap_err().kind(), ErrorKind::LimitExceeded);
+            })
+            .unwrap();
+        handle.join().unwrap();
+    }
+}
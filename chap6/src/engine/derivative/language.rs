@@ -0,0 +1,277 @@
+//! 微分で作る DFA を辿って、言語が空か、包含・等価かを調べる。
+//!
+//! 文字は無数にあるが、式に現れる 1 文字の式 (`Class`) が区別しない文字どうしは
+//! 微分しても同じ状態に移る。そこで文字全体を、どの `Class` に含まれるかが同じ文字の
+//! まとまりに分け、まとまりごとに代表の文字を 1 つ選んで遷移を作る。
+//! 状態の数は有限なので、幅優先で到達できる状態をすべて辿れる。
+//! 幅優先なので、受理する状態に最初に着いた経路が最短の文字列になる。
+
+use super::{Class, Kind, Re};
+use crate::error::{Error, ErrorKind};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+
+/// 辿る DFA の状態の数の上限
+const MAX_STATES: usize = 100_000;
+
+impl Class {
+    /// 含まれるかどうかが変わる位置 (その位置の文字から含まれ方が変わる)
+    fn boundaries(&self, out: &mut BTreeSet<u32>) {
+        match self {
+            Class::Chars(chars) | Class::NotChars(chars) => {
+                for c in chars {
+                    out.insert(*c as u32);
+                    out.insert(*c as u32 + 1);
+                }
+            }
+            Class::Digit | Class::NotDigit => {
+                out.insert('0' as u32);
+                out.insert('9' as u32 + 1);
+            }
+            Class::Property(p) | Class::NotProperty(p) => {
+                for (first, last) in p.ranges() {
+                    out.insert(*first as u32);
+                    out.insert(*last as u32 + 1);
+                }
+            }
+            Class::Any => (),
+        }
+    }
+}
+
+/// 式に現れる 1 文字の式を集める。共有している部分式は 1 度だけ見る
+fn classes(r: &Re, out: &mut BTreeSet<Class>) {
+    let mut visited = HashSet::new();
+    let mut stack = vec![r];
+    while let Some(r) = stack.pop() {
+        if !visited.insert(r) {
+            continue;
+        }
+        match r.kind() {
+            Kind::Empty | Kind::Epsilon => (),
+            Kind::Class(class) => {
+                out.insert(class.clone());
+            }
+            Kind::Seq(r1, r2) => stack.extend([r1, r2]),
+            Kind::Or(v) | Kind::And(v) => stack.extend(v),
+            Kind::Not(r) | Kind::Star(r) | Kind::Counter(r, _, _) => stack.push(r),
+        }
+    }
+}
+
+/// 表示して読みにくい文字は、代表に選ぶのを後回しにする
+fn preference(c: char) -> (bool, char) {
+    (c.is_control() || c.is_whitespace(), c)
+}
+
+/// 区間 [lo, hi) の中で代表にする文字。サロゲートしかなければ None
+fn pick(lo: u32, hi: u32) -> Option<char> {
+    // なるべく表示できる ASCII の文字を選ぶ
+    let preferred = lo.max('!' as u32);
+    let c = if preferred < hi { preferred } else { lo };
+    // サロゲートは文字ではないので、その後ろから選ぶ
+    let c = if (0xD800..0xE000).contains(&c) { 0xE000 } else { c };
+    if c < hi {
+        char::from_u32(c)
+    } else {
+        None
+    }
+}
+
+/// r の微分を区別するのに十分な文字を返す。
+/// どの `Class` に含まれるかが同じ文字のまとまりごとに 1 つ選ぶ
+fn alphabet(r: &Re) -> Vec<char> {
+    let mut set = BTreeSet::new();
+    classes(r, &mut set);
+    let classes = set.into_iter().collect::<Vec<Class>>();
+
+    let mut boundaries = BTreeSet::from([0, char::MAX as u32 + 1]);
+    for class in &classes {
+        class.boundaries(&mut boundaries);
+    }
+    let boundaries = boundaries.into_iter().collect::<Vec<u32>>();
+
+    let mut representatives: HashMap<Vec<bool>, char> = HashMap::new();
+    for w in boundaries.windows(2) {
+        let c = if let Some(c) = pick(w[0], w[1]) {
+            c
+        } else {
+            continue;
+        };
+        let key = classes.iter().map(|class| class.contains(c)).collect();
+        let entry = representatives.entry(key).or_insert(c);
+        if preference(c) < preference(*entry) {
+            *entry = c;
+        }
+    }
+    let mut alphabet = representatives.into_values().collect::<Vec<char>>();
+    alphabet.sort_unstable_by_key(|c| preference(*c));
+    alphabet
+}
+
+/// r にマッチする最短の文字列。何にもマッチしなければ None
+pub(super) fn shortest(r: &Re) -> Result<Option<String>, Error> {
+    let alphabet = alphabet(r);
+    let mut states = vec![r.clone()];
+    let mut ids = HashMap::from([(r.clone(), 0)]);
+    // 状態ごとに、どの状態からどの文字で来たか
+    let mut parents: Vec<Option<(usize, char)>> = vec![None];
+    let mut queue = VecDeque::from([0]);
+
+    while let Some(state) = queue.pop_front() {
+        if states[state].nullable() {
+            let mut path = Vec::new();
+            let mut current = state;
+            while let Some((parent, c)) = parents[current] {
+                path.push(c);
+                current = parent;
+            }
+            return Ok(Some(path.into_iter().rev().collect()));
+        }
+        for &c in &alphabet {
+            let next = states[state].derive(c);
+            // 何にもマッチしない状態から先は辿らない
+            if next.is_empty() || ids.contains_key(&next) {
+                continue;
+            }
+            if states.len() >= MAX_STATES {
+                return Err(Error::new(
+                    ErrorKind::BudgetExceeded,
+                    format!("derivative: more than {MAX_STATES} DFA states"),
+                    None,
+                ));
+            }
+            ids.insert(next.clone(), states.len());
+            states.push(next);
+            parents.push(Some((state, c)));
+            queue.push_back(states.len() - 1);
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::alphabet;
+    use crate::{
+        engine::{
+            derivative::{from_pattern, tests::strings, DerivativeRegex},
+            oracle::{random_ast, Xor64},
+            printer::to_pattern,
+        },
+        Regex,
+    };
+
+    #[test]
+    fn test_alphabet() {
+        let alphabet = |pattern: &str| alphabet(&from_pattern(&crate::engine::parse(pattern).unwrap()).unwrap());
+        // a, b, それ以外
+        assert_eq!(alphabet("a|b").len(), 3);
+        assert!(alphabet("a|b").contains(&'!'));
+        // 数字とそれ以外
+        assert_eq!(alphabet("\\d+").len(), 2);
+        // 数字, 数字でない大文字, それ以外
+        assert_eq!(alphabet("\\d\\p{Lu}").len(), 3);
+        assert_eq!(alphabet("\\d|\\D").len(), 2);
+    }
+
+    #[test]
+    fn test_shortest_match() {
+        let shortest = |pattern: &str| DerivativeRegex::new(pattern).unwrap().shortest_match().unwrap();
+        assert_eq!(shortest("^\\d{3}-\\d{4}$"), Some("000-0000".to_string()));
+        assert_eq!(shortest("^ab+c$"), Some("abc".to_string()));
+        assert_eq!(shortest("^(?:error|warn)$"), Some("warn".to_string()));
+        assert_eq!(shortest("x*"), Some("".to_string()));
+        assert_eq!(shortest("^[^a]$"), Some("!".to_string()));
+    }
+
+    #[test]
+    fn test_empty() {
+        let re = |pattern: &str| DerivativeRegex::new(pattern).unwrap();
+        assert!(!re("a").is_empty().unwrap());
+        assert!(re("^a$").and(&re("^b$")).is_empty().unwrap());
+        assert!(re("^\\d+$").and(&re("^\\D+$")).is_empty().unwrap());
+        assert!(re("x*").not().is_empty().unwrap());
+        assert!(!re("^\\p{Lu}+$").and(&re("^[^A]+$")).is_empty().unwrap());
+    }
+
+    #[test]
+    fn test_subset_and_equivalence() {
+        let re = |pattern: &str| DerivativeRegex::new(pattern).unwrap();
+
+        // 先に置いた広いルールが、後のルールを覆ってしまう
+        let rules = ["disk", "(?i)error", "^ERROR: disk full$", "timeout \\d+s"];
+        let shadowed = |i: usize| (0..i).find(|&j| re(rules[i]).is_subset(&re(rules[j])).unwrap());
+        assert_eq!(shadowed(1), None);
+        assert_eq!(shadowed(2), Some(0));
+        assert_eq!(shadowed(3), None);
+        assert!(!re("disk").is_subset(&re("^ERROR: disk full$")).unwrap());
+
+        // どこかにマッチすればよいので、a+ と a は同じ
+        assert!(re("a+").is_equivalent(&re("a")).unwrap());
+        assert!(re("^(a|b)*$").is_equivalent(&re("^(a*b*)*$")).unwrap());
+        assert!(re("^.$").is_equivalent(&re("^(?:\\p{Lu}|\\P{Lu})$")).unwrap());
+        assert!(re("^\\d{2,3}$").is_equivalent(&re("^\\d\\d\\d?$")).unwrap());
+        assert!(!re("^a*$").is_equivalent(&re("^a+$")).unwrap());
+        assert_eq!(re("^a*$").counterexample(&re("^a+$")).unwrap(), Some("".to_string()));
+        assert_eq!(re("^a{2,}$").counterexample(&re("^a{3,}$")).unwrap(), Some("aa".to_string()));
+
+        // 最短の反例は、どちらか一方だけにマッチする
+        let (a, b) = (re("error"), re("(?i)error"));
+        let example = a.counterexample(&b).unwrap().unwrap();
+        assert_eq!(example.len(), 5);
+        assert!(!a.is_match(&example) && b.is_match(&example));
+        assert_eq!(a.is_subset(&b), Ok(true));
+    }
+
+    /// 言語の操作は、受け付けたパターンについては `Regex::is_match` が true になる文字列の集合を扱う。
+    /// ランダムなパターンで、マッチするか、最短のマッチ、反例と包含が `Regex` の結果と矛盾しないかを確かめる。
+    /// `DerivativeRegex` が受け付けないパターン (後読みや `\b`、選択肢の途中の `^` など) は飛ばす
+    #[test]
+    fn test_agrees_with_regex() {
+        let mut rng = Xor64::new(39);
+        let inputs = strings(&['a', 'b', '1', ' '], 4);
+        let mut accepted = Vec::new();
+        for _ in 0..2000 {
+            let pattern = to_pattern(&random_ast(&mut rng, 3));
+            let derivative = if let Ok(derivative) = DerivativeRegex::new(&pattern) {
+                derivative
+            } else {
+                continue;
+            };
+            let regex = Regex::new(&pattern).unwrap();
+            let matches = inputs.iter().map(|input| regex.is_match(input).unwrap()).collect::<Vec<bool>>();
+            for (input, expected) in inputs.iter().zip(&matches) {
+                assert_eq!(derivative.is_match(input), *expected, "{pattern} on {input:?}");
+            }
+
+            // 最短のマッチには Regex もマッチし、それより短い入力には Regex もマッチしない
+            match derivative.shortest_match().unwrap() {
+                Some(shortest) => {
+                    assert!(regex.is_match(&shortest).unwrap(), "{pattern}: {shortest:?}");
+                    let len = shortest.chars().count();
+                    let shorter = inputs.iter().zip(&matches).filter(|(input, _)| input.chars().count() < len);
+                    assert!(shorter.clone().all(|(_, is_match)| !is_match), "{pattern}: {shortest:?}");
+                }
+                None => assert!(matches.iter().all(|is_match| !is_match), "{pattern}"),
+            }
+            accepted.push((pattern, derivative, matches));
+        }
+        assert!(accepted.len() >= 500, "only {} patterns accepted", accepted.len());
+
+        // 反例には片方の Regex だけがマッチする。包含していれば、入力でも片方だけにマッチするものはない
+        for pair in accepted.windows(2) {
+            let ((p1, d1, m1), (p2, d2, m2)) = (&pair[0], &pair[1]);
+            match d1.counterexample(d2).unwrap() {
+                Some(example) => assert_ne!(
+                    Regex::new(p1).unwrap().is_match(&example).unwrap(),
+                    Regex::new(p2).unwrap().is_match(&example).unwrap(),
+                    "{p1} and {p2}: {example:?}"
+                ),
+                None => assert_eq!(m1, m2, "{p1} and {p2}"),
+            }
+            if d1.is_subset(d2).unwrap() {
+                assert!(m1.iter().zip(m2).all(|(a, b)| !a || *b), "{p1} in {p2}");
+            }
+        }
+    }
+}
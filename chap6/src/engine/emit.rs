@@ -0,0 +1,167 @@
+//! コンパイルしたプログラムを、それを表す Rust の式にする。
+//!
+//! `chap6-macros` の `regex!` がコンパイル時にパターンをプログラムにし、
+//! この式を埋め込む。実行時にはパースもコード生成もしない。
+//! 命令の列やトライ木は `static` の配列になるので、埋め込んだプログラムを使い始めるときにメモリを確保しない。
+//! 式の中のパスは `::chap6::__private` から始まる。
+
+use super::{trie::Trie, Instruction};
+
+const PATH: &str = "::chap6::__private";
+
+/// `items` を並べた `static` の配列を作り、その参照を返すブロック式
+fn static_slice(ty: &str, items: &[String]) -> String {
+    format!("{{ static ITEMS: [{ty}; {}] = [{}]; &ITEMS }}", items.len(), items.join(", "))
+}
+
+fn option_to_rust(n: Option<usize>) -> String {
+    match n {
+        Some(n) => format!("::std::option::Option::Some({n})"),
+        None => "::std::option::Option::None".to_string(),
+    }
+}
+
+fn trie_to_rust(t: &Trie) -> String {
+    let nodes = t
+        .nodes()
+        .iter()
+        .map(|n| {
+            let (first, end) = n.next();
+            format!("{PATH}::TrieNode::new(({first}, {end}), {}, {})", option_to_rust(n.accept()), n.min_accept())
+        })
+        .collect::<Vec<_>>();
+    let edges = t.edges().iter().map(|e| format!("{e:?}")).collect::<Vec<_>>();
+    format!(
+        "{PATH}::trie({}, &[{}], {})",
+        static_slice(&format!("{PATH}::TrieNode"), &nodes),
+        edges.join(", "),
+        t.literal_count()
+    )
+}
+
+/// `&'static [Instruction]` の式
+pub fn to_rust(code: &[Instruction]) -> String {
+    let insts = code
+        .iter()
+        .map(|inst| {
+            let inst = match inst {
+                Instruction::Char(c) => format!("Char({c:?})"),
+                Instruction::UnmatchChars(chars) => format!("UnmatchChars(::std::borrow::Cow::Borrowed(&{chars:?}))"),
+                Instruction::Caret => "Caret".to_string(),
+                Instruction::Doller => "Doller".to_string(),
+                Instruction::Match => "Match".to_string(),
+                Instruction::MatchRule(rule) => format!("MatchRule({rule})"),
+                Instruction::Jump(addr) => format!("Jump({addr})"),
+                Instruction::Split(addr1, addr2) => format!("Split({addr1}, {addr2})"),
+                Instruction::CounterReset(idx) => format!("CounterReset({idx})"),
+                Instruction::CounterSplit(addr1, addr2, (min, max), idx) => {
+                    format!("CounterSplit({addr1}, {addr2}, ({min}, {max}), {idx})")
+                }
+                Instruction::CounterIncrement(idx) => format!("CounterIncrement({idx})"),
+                Instruction::AnyNumber => "AnyNumber".to_string(),
+                Instruction::NotNumber => "NotNumber".to_string(),
+                Instruction::CapcherBegin(idx) => format!("CapcherBegin({idx})"),
+                Instruction::CapcherEnd(idx) => format!("CapcherEnd({idx})"),
+                Instruction::MatchChars(chars) => format!("MatchChars(::std::borrow::Cow::Borrowed(&{chars:?}))"),
+                Instruction::Property(p) => {
+                    let (table, i) = p.position();
+                    format!("Property({PATH}::property({PATH}::Table::{table:?}, {i}))")
+                }
+                Instruction::NotProperty(p) => {
+                    let (table, i) = p.position();
+                    format!("NotProperty({PATH}::property({PATH}::Table::{table:?}, {i}))")
+                }
+                Instruction::AnyChar => "AnyChar".to_string(),
+                Instruction::AtomicBegin => "AtomicBegin".to_string(),
+                Instruction::AtomicEnd => "AtomicEnd".to_string(),
+                Instruction::Assertion(a) => format!("Assertion({PATH}::Assertion::{a:?})"),
+                Instruction::Trie(t) => format!("Trie({})", trie_to_rust(t)),
+                Instruction::LookBehind(l) => format!(
+                    "LookBehind({PATH}::look_behind({}, {}, {}))",
+                    to_rust(l.code()),
+                    l.max_len(),
+                    l.is_negated()
+                ),
+            };
+            format!("{PATH}::Instruction::{inst}")
+        })
+        .collect::<Vec<_>>();
+    static_slice(&format!("{PATH}::Instruction"), &insts)
+}
+
+/// 逆向きのプログラムがあればそれを指す `Option<&'static [Instruction]>` の式
+pub fn reverse_to_rust(reverse: Option<&[Instruction]>) -> String {
+    match reverse {
+        Some(code) => format!("::std::option::Option::Some({})", to_rust(code)),
+        None => "::std::option::Option::None".to_string(),
+    }
+}
+
+/// グループの名前を並べた `&[Option<&str>]` の式
+pub fn names_to_rust(names: &[Option<String>]) -> String {
+    let names = names
+        .iter()
+        .map(|name| match name {
+            Some(name) => format!("::std::option::Option::Some({name:?})"),
+            None => "::std::option::Option::None".to_string(),
+        })
+        .collect::<Vec<_>>();
+    format!("&[{}]", names.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{names_to_rust, reverse_to_rust, to_rust};
+    use crate::engine::{compile, Instruction};
+
+    #[test]
+    fn test_to_rust() {
+        let code = compile("a\\d{100}").unwrap();
+        let rust = to_rust(&code);
+        assert!(rust.starts_with("{ static ITEMS: [::chap6::__private::Instruction; "));
+        assert!(rust.contains("] = [::chap6::__private::Instruction::CapcherBegin(0), "));
+        assert!(rust.contains("Instruction::Char('a'), "));
+        assert!(rust.contains("Instruction::CounterSplit("));
+        assert!(rust.ends_with("]; &ITEMS }"));
+
+        let rust = to_rust(&[
+            Instruction::MatchChars(vec!['\'', '\n'].into()),
+            Instruction::Property(crate::engine::unicode::lookup("Han").unwrap()),
+            Instruction::Trie(crate::engine::trie::Trie::new(vec![vec!['a'], vec!['b', 'c']])),
+        ]);
+        let (table, i) = crate::engine::unicode::lookup("Han").unwrap().position();
+        assert_eq!(
+            rust,
+            format!(
+                "{{ static ITEMS: [::chap6::__private::Instruction; 3] = [\
+                 ::chap6::__private::Instruction::MatchChars(::std::borrow::Cow::Borrowed(&['\\'', '\\n'])), \
+                 ::chap6::__private::Instruction::Property(::chap6::__private::property(::chap6::__private::Table::{table:?}, {i})), \
+                 ::chap6::__private::Instruction::Trie(::chap6::__private::trie(\
+                 {{ static ITEMS: [::chap6::__private::TrieNode; 4] = [\
+                 ::chap6::__private::TrieNode::new((0, 2), ::std::option::Option::None, 0), \
+                 ::chap6::__private::TrieNode::new((2, 2), ::std::option::Option::Some(0), 0), \
+                 ::chap6::__private::TrieNode::new((2, 3), ::std::option::Option::None, 1), \
+                 ::chap6::__private::TrieNode::new((3, 3), ::std::option::Option::Some(1), 1)]; &ITEMS }}, \
+                 &[('a', 1), ('b', 2), ('c', 3)], 2))]; &ITEMS }}"
+            )
+        );
+
+        let rust = to_rust(&compile("(?<!a)b").unwrap());
+        assert!(rust.contains(
+            "Instruction::LookBehind(::chap6::__private::look_behind(\
+             { static ITEMS: [::chap6::__private::Instruction; 2] = [\
+             ::chap6::__private::Instruction::Char('a'), ::chap6::__private::Instruction::Match]; &ITEMS }, 1, true)), "
+        ));
+        assert_eq!(reverse_to_rust(None), "::std::option::Option::None");
+        assert_eq!(
+            reverse_to_rust(Some(&[Instruction::MatchRule(0)])),
+            "::std::option::Option::Some({ static ITEMS: [::chap6::__private::Instruction; 1] = [\
+             ::chap6::__private::Instruction::MatchRule(0)]; &ITEMS })"
+        );
+
+        assert_eq!(
+            names_to_rust(&[None, Some("年".to_string())]),
+            "&[::std::option::Option::None, ::std::option::Option::Some(\"年\")]"
+        );
+    }
+}
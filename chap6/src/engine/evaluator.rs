@@ -1,9 +1,14 @@
-use super::Instruction;
+mod backtrack;
+mod state_set;
+
+use super::{reverse::LookBehind, trie::Trie, Instruction};
+use state_set::StateSet;
 use crate::helper::safe_add;
 use std::{
-    collections::{HashSet, VecDeque},
     error::Error,
     fmt::{self, Display},
+    mem,
+    time::{Duration, Instant},
 };
 
 #[derive(Debug)]
@@ -12,258 +17,1475 @@ pub enum EvalError {
     SPOverFlow,
     InvalidPC,
     InvalidContext,
+    BudgetExceeded,
+    Timeout,
 }
 
 impl Display for EvalError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "CodeGenError: {:?}", self)
+        write!(f, "EvalError: {:?}", self)
     }
 }
 
 impl Error for EvalError {}
 
+/// 深さ優先探索の実行上限。
+///
+/// `step_budget` は実行する命令数の上限、`timeout` は評価開始からの経過時間の上限。
+/// どちらも `None` なら無制限。
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EvalLimits {
+    pub step_budget: Option<usize>,
+    pub timeout: Option<Duration>,
+}
+
+// Instant::now() は安くないので、時計はこの命令数ごとにしか見ない
+const CLOCK_CHECK_INTERVAL: usize = 1024;
+
+#[derive(Default)]
+struct Watchdog {
+    steps: usize,
+    step_budget: Option<usize>,
+    deadline: Option<Instant>,
+}
+
+impl Watchdog {
+    fn new(limits: &EvalLimits) -> Self {
+        Watchdog {
+            steps: 0,
+            step_budget: limits.step_budget,
+            deadline: limits.timeout.map(|t| Instant::now() + t),
+        }
+    }
+
+    fn tick(&mut self) -> Result<(), EvalError> {
+        safe_add(&mut self.steps, &1, || EvalError::BudgetExceeded)?;
+        if let Some(budget) = self.step_budget {
+            if self.steps > budget {
+                return Err(EvalError::BudgetExceeded);
+            }
+        }
+        if let Some(deadline) = self.deadline {
+            if self.steps.is_multiple_of(CLOCK_CHECK_INTERVAL) && Instant::now() >= deadline {
+                return Err(EvalError::Timeout);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// バックトラック用のスタックに積むもの
+#[derive(Debug, Clone)]
+pub enum Frame<T> {
+    /// 失敗したときに再開するスレッド
+    Resume(T),
+    /// アトミックグループの始まり。atomic end でこれより上の分岐をまとめて捨てる
+    Barrier,
+    /// 戻ってきたときにキャプチャの位置 (スロット番号, 元の値) を元に戻す
+    Restore(usize, Option<usize>),
+}
+
+/// atomic end。直近の Barrier までの分岐を捨て、捨てた Resume の数を返す。
+/// キャプチャの巻き戻しは、グループの後ろで失敗したときに要るので残す
+fn cut<T>(stack: &mut Vec<Frame<T>>) -> usize {
+    let from = stack.iter().rposition(|frame| matches!(frame, Frame::Barrier)).unwrap_or(0);
+    // Restore だけを順序を変えずに前へ詰める
+    let mut kept = from;
+    let mut dropped = 0;
+    for i in from..stack.len() {
+        if matches!(stack[i], Frame::Restore(..)) {
+            stack.swap(kept, i);
+            kept += 1;
+        } else if matches!(stack[i], Frame::Resume(_)) {
+            dropped += 1;
+        }
+    }
+    stack.truncate(kept);
+    dropped
+}
+
+/// キャプチャの位置。グループ i の始まりが 2 * i 番目、終わりが 2 * i + 1 番目
+type Slots = Vec<Option<usize>>;
+
+/// capcher begin/end の命令なら、書き込むスロットの番号を返す
+fn capcher_slot(inst: &Instruction) -> Option<usize> {
+    match inst {
+        Instruction::CapcherBegin(idx) if *idx >= 0 => Some(*idx as usize * 2),
+        Instruction::CapcherEnd(idx) if *idx >= 0 => Some(*idx as usize * 2 + 1),
+        _ => None,
+    }
+}
+
+/// スロットに sp を書き込み、戻ってきたときに元に戻せるようにしておく
+fn save<T>(slots: &mut Slots, stack: &mut Vec<Frame<T>>, slot: usize, sp: usize) {
+    stack.push(Frame::Restore(slot, slots[slot]));
+    slots[slot] = Some(sp);
+}
+
+/// グループごとの (始まり, 終わり)。通らなかったグループは None
+pub type CaptureSpans = Vec<Option<(usize, usize)>>;
+
+/// スロットをグループごとの (始まり, 終わり) にする。通らなかったグループは None
+fn to_captures(slots: &[Option<usize>]) -> CaptureSpans {
+    slots
+        .chunks(2)
+        .map(|s| match s {
+            [Some(start), Some(end)] => Some((*start, *end)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// `to_captures` と同じだが、`captures` の領域を使い回す
+fn write_captures(slots: &[Option<usize>], captures: &mut CaptureSpans) {
+    captures.clear();
+    captures.extend(slots.chunks(2).map(|s| match s {
+        [Some(start), Some(end)] => Some((*start, *end)),
+        _ => None,
+    }));
+}
+
+/// 評価器が使う作業領域。同じ `Scratch` で評価を繰り返すと、確保済みの領域を使い回す。
+/// どの評価器も、前の評価より長い入力や多くの状態を扱わない限り、2 回目以降はメモリを確保しない
+#[derive(Default)]
+pub struct Scratch {
+    backtrack: backtrack::BacktrackScratch,
+    depth: DepthVm,
+    width: WidthScratch,
+    behind: BehindScratch,
+}
+
+/// 1 文字を読む命令が文字 `c` を受理するか
+fn is_char_match(inst: &Instruction, c: &char) -> bool {
+    match inst {
+        Instruction::Char(i) => i == c,
+        Instruction::UnmatchChars(i) => !i.contains(c),
+        Instruction::AnyNumber => c.is_ascii_digit(),
+        Instruction::NotNumber => !c.is_ascii_digit(),
+        Instruction::MatchChars(i) => i.contains(c),
+        Instruction::Property(p) => p.contains(*c),
+        Instruction::NotProperty(p) => !p.contains(*c),
+        Instruction::AnyChar => true,
+        _ => false,
+    }
+}
+
 fn eval_depth(
     inst: &[Instruction],
     line: &[char],
-    index: usize,
-    mut pc: usize,
-    mut sp: usize,
-    mut register: Vec<(i32, Option<i32>)>,
-    cache: &mut HashSet<(usize, usize)>,
-    matched_str: &mut Vec<(usize, usize)>,
+    start: usize,
+    limits: &EvalLimits,
+    scratch: &mut Scratch,
+    captures: &mut CaptureSpans,
 ) -> Result<bool, EvalError> {
-    println!(
-        "eval_depth:: inst: {:?}, line: {:?}, index: {}, pc: {}, sp: {}",
-        inst, line, index, pc, sp
-    );
+    let vm = &mut scratch.depth;
+    vm.reset(inst, start, limits);
     loop {
+        match vm.step(inst, line)? {
+            Step::Running => (),
+            Step::Matched => {
+                write_captures(&vm.slots, captures);
+                return Ok(true);
+            }
+            Step::Failed => return Ok(false),
+        }
+    }
+}
+
+/// バックトラック用のスタックに積むスレッド (pc, sp, register)
+pub type DepthThread<'a> = (usize, usize, &'a [usize]);
+
+/// 1 命令ずつ実行した結果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    Running,
+    Matched,
+    Failed,
+}
+
+/// 深さ優先の評価器。1 命令ずつ進められるので、デバッガからも使う
+#[derive(Default)]
+pub struct DepthVm {
+    /// 一度通って失敗した状態 (pc, sp, グループに入った回, register)。カウンタの値も状態の一部なのでキーに含める
+    cache: StateSet<3>,
+    /// スタックにある Barrier ごとに、そのアトミックグループに入ったのが何回目か。
+    /// 1 から数え、グループの外の状態は 0 回目として覚える
+    atomics: Vec<usize>,
+    /// これまでにアトミックグループに入った回数
+    entered: usize,
+    /// バックトラック用のスタック。Split のたびに (pc, sp) を積む
+    stack: Vec<Frame<(usize, usize)>>,
+    /// スタックの Resume ごとのカウンタの値。積んだ順に詰めて並べる
+    stack_registers: Vec<usize>,
+    slots: Slots,
+    /// 実行中のスレッドの (pc, sp)。失敗したら None にして、次の step でスタックから取り出す
+    thread: Option<(usize, usize)>,
+    /// 実行中のスレッドのカウンタの値
+    register: Vec<usize>,
+    /// トライ木の命令でマッチした選択肢
+    ends: Vec<(usize, usize)>,
+    behind: BehindScratch,
+    watchdog: Watchdog,
+    /// 入力の終わりで、もっと文字があれば先に進めたスレッドがあったか
+    hungry: bool,
+}
+
+impl DepthVm {
+    /// 入力の `start` 文字目からマッチを試す。実行する命令の数に上限はない
+    pub fn new(inst: &[Instruction], start: usize) -> Self {
+        let mut vm = DepthVm::default();
+        vm.reset(inst, start, &EvalLimits::default());
+        vm
+    }
+
+    /// `new` と同じ状態に戻し、`limits` を上限にする。確保済みの領域はそのまま使う
+    fn reset(&mut self, inst: &[Instruction], start: usize, limits: &EvalLimits) {
+        let registers = count_registers(inst);
+        self.cache.clear(registers);
+        self.atomics.clear();
+        self.entered = 0;
+        self.stack.clear();
+        self.stack_registers.clear();
+        self.slots.clear();
+        self.slots.resize(count_capchers(inst) * 2, None);
+        self.thread = Some((0, start));
+        self.register.clear();
+        self.register.resize(registers, 0);
+        self.watchdog = Watchdog::new(limits);
+        self.hungry = false;
+    }
+
+    /// 入力の終わりで文字を待ったスレッドがあったか。
+    /// 入力を足せばマッチするかもしれないことを表す
+    pub fn needs_more_input(&self) -> bool {
+        self.hungry
+    }
+
+    /// 次に実行するスレッド
+    pub fn thread(&self) -> Option<DepthThread<'_>> {
+        self.thread.map(|(pc, sp)| (pc, sp, &self.register[..]))
+    }
+
+    /// バックトラック用のスタック。最後の要素が先に取り出される
+    pub fn stack(&self) -> Vec<Frame<DepthThread<'_>>> {
+        let width = self.register.len();
+        let mut offset = 0;
+        self.stack
+            .iter()
+            .map(|frame| match frame {
+                Frame::Resume((pc, sp)) => {
+                    let register = &self.stack_registers[offset..offset + width];
+                    offset += width;
+                    Frame::Resume((*pc, *sp, register))
+                }
+                Frame::Barrier => Frame::Barrier,
+                Frame::Restore(slot, old) => Frame::Restore(*slot, *old),
+            })
+            .collect()
+    }
+
+    /// グループごとのキャプチャの位置
+    pub fn captures(&self) -> CaptureSpans {
+        to_captures(&self.slots)
+    }
+
+    /// 実行中のスレッドを、カウンタの値はそのままで (pc, sp) から再開できるように積む
+    fn push_resume(&mut self, pc: usize, sp: usize) {
+        self.stack.push(Frame::Resume((pc, sp)));
+        self.stack_registers.extend_from_slice(&self.register);
+    }
+
+    /// 状態 (pc, sp, register) に初めて来たなら覚えて true を返す。
+    ///
+    /// アトミックグループの中の状態は、グループに入るたびに別のものとして覚える。
+    /// atomic end はグループに入ってから積んだ分岐を捨てるので、グループの中の状態から先の結果は、
+    /// その状態に来るまでに積んだ分岐で変わる。同じ回の中なら、atomic end に着いた時点でその回の分岐は
+    /// すべて捨てられるので、覚えた失敗が別の道筋で間違いになることはない
+    fn visit(&mut self, pc: usize, sp: usize) -> bool {
+        let atomic = self.atomics.last().copied().unwrap_or(0);
+        self.cache.insert([pc, sp, atomic], &self.register)
+    }
+
+    /// 命令を 1 つ実行する。実行中のスレッドが失敗していたら、先にスタックから次のスレッドを取り出す
+    pub fn step(&mut self, inst: &[Instruction], line: &[char]) -> Result<Step, EvalError> {
+        self.watchdog.tick()?;
+        if self.thread.is_none() {
+            while let Some(frame) = self.stack.pop() {
+                match frame {
+                    Frame::Resume(thread) => {
+                        let n = self.stack_registers.len() - self.register.len();
+                        self.register.copy_from_slice(&self.stack_registers[n..]);
+                        self.stack_registers.truncate(n);
+                        self.thread = Some(thread);
+                        break;
+                    }
+                    Frame::Barrier => {
+                        self.atomics.pop();
+                    }
+                    Frame::Restore(slot, old) => self.slots[slot] = old,
+                }
+            }
+        }
+        let (mut pc, mut sp) = if let Some(thread) = self.thread.take() {
+            thread
+        } else {
+            return Ok(Step::Failed);
+        };
+
         let next = if let Some(i) = inst.get(pc) {
             i
         } else {
             return Err(EvalError::InvalidPC);
         };
-        println!(
-            "next: {:?}, pc: {}, sp: {}, register: {:?}",
-            next, pc, sp, register
-        );
 
         match next {
-            Instruction::Char(c) => {
-                if let Some(sp_c) = line.get(sp) {
-                    if c == sp_c || *c == '.' {
-                        safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
-                        safe_add(&mut sp, &1, || EvalError::SPOverFlow)?;
-                    } else {
-                        return Ok(false);
-                    }
-                } else {
-                    return Ok(false);
-                }
-            }
-            Instruction::UnmatchChars(c) => {
-                if let Some(sp_c) = line.get(sp) {
-                    if !c.contains(sp_c) {
-                        safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
-                        safe_add(&mut sp, &1, || EvalError::SPOverFlow)?;
-                    } else {
-                        return Ok(false);
-                    }
+            Instruction::Char(_)
+            | Instruction::UnmatchChars(_)
+            | Instruction::AnyNumber
+            | Instruction::NotNumber
+            | Instruction::MatchChars(_)
+            | Instruction::Property(_)
+            | Instruction::NotProperty(_)
+            | Instruction::AnyChar => {
+                if !line.get(sp).is_some_and(|sp_c| is_char_match(next, sp_c)) {
+                    self.hungry |= sp >= line.len();
+                    return Ok(Step::Running);
                 }
+                safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                safe_add(&mut sp, &1, || EvalError::SPOverFlow)?;
             }
-            Instruction::AnyNumber => {
-                if let Some(sp_c) = line.get(sp) {
-                    if "0123456789".chars().collect::<Vec<_>>().contains(sp_c) {
-                        safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
-                        safe_add(&mut sp, &1, || EvalError::SPOverFlow)?;
-                    } else {
-                        return Ok(false);
-                    }
-                } else {
-                    return Ok(false);
+            Instruction::Caret => {
+                if sp != 0 {
+                    return Ok(Step::Running);
                 }
+                safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
             }
-            Instruction::NotNumber => {
-                if let Some(sp_c) = line.get(sp) {
-                    if !"0123456789".chars().collect::<Vec<_>>().contains(sp_c) {
-                        safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
-                        safe_add(&mut sp, &1, || EvalError::SPOverFlow)?;
-                    } else {
-                        return Ok(false);
-                    }
-                } else {
-                    return Ok(false);
+            Instruction::Doller => {
+                if sp != line.len() {
+                    return Ok(Step::Running);
                 }
+                safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
             }
-            Instruction::Caret => {
-                if sp != 0 || index != 0 {
-                    return Ok(false);
+            Instruction::Assertion(a) => {
+                if !a.is_match(line, sp) {
+                    self.hungry |= sp >= line.len() && a.looks_ahead();
+                    return Ok(Step::Running);
                 }
                 safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
             }
-            Instruction::Doller => {
-                if sp != line.len() {
-                    return Ok(false);
+            Instruction::LookBehind(l) => {
+                if !look_behind(l, line, sp, &mut self.watchdog, &mut self.behind)? {
+                    self.hungry |= sp >= line.len() && l.looks_ahead();
+                    return Ok(Step::Running);
                 }
                 safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
             }
-            Instruction::Match => {
-                return Ok(register.iter().all(|counter| {
-                    counter.0 <= 0 && (counter.1.is_none() || counter.1.unwrap() >= 0)
-                }))
+            Instruction::Match | Instruction::MatchRule(_) => {
+                self.thread = Some((pc, sp));
+                return Ok(Step::Matched);
             }
             Instruction::Jump(addr) => {
-                if cache.contains(&(*addr, sp)) {
-                    return Ok(false);
+                if !self.visit(pc, sp) {
+                    return Ok(Step::Running);
                 }
-                cache.insert((*addr, sp));
                 pc = *addr
             }
-            Instruction::Split(addr1, addr2, count, register_idx) => {
-                if *register_idx >= 0 {
-                    if let Some(_) = register.get_mut(*register_idx as usize) {
-                    } else {
-                        register.push(*count);
-                    }
-                }
-                if eval_depth(inst, line, index, *addr1, sp, register.clone(), cache, matched_str)?
-                    || eval_depth(inst, line, index, *addr2, sp, register, cache, matched_str)?
-                {
-                    return Ok(true);
-                } else {
-                    return Ok(false);
+            Instruction::Split(addr1, addr2) => {
+                if !self.visit(pc, sp) {
+                    return Ok(Step::Running);
                 }
+                // addr1 を先に試し、失敗したら addr2 に戻ってくる
+                self.push_resume(*addr2, sp);
+                pc = *addr1;
+            }
+            Instruction::CounterReset(idx) => {
+                self.register[*idx] = 0;
+                safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
             }
-            Instruction::Descrement(idx) => {
-                if let Some(c) = register[*idx].1 {
-                    if c == 0 {
-                        return Ok(false);
+            Instruction::CounterSplit(addr1, addr2, (min, max), idx) => {
+                let count = self.register[*idx];
+                if count < *min {
+                    pc = *addr1;
+                } else if count >= *max {
+                    pc = *addr2;
+                } else {
+                    if !self.visit(pc, sp) {
+                        return Ok(Step::Running);
                     }
-                    register[*idx].1 = Some(c - 1);
+                    self.push_resume(*addr2, sp);
+                    pc = *addr1;
                 }
-                register[*idx].0 = register[*idx].0 - 1;
+            }
+            Instruction::CounterIncrement(idx) => {
+                self.register[*idx] += 1;
                 safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
             }
-            Instruction::CapcherBegin(register_idx) => {
-                if *register_idx >= 0 {
-                    if let Some(_) = matched_str.get_mut(*register_idx as usize) {
-                    } else {
-                        matched_str.push((sp, 0));
-                    }
+            Instruction::Trie(trie) => {
+                if !self.visit(pc, sp) {
+                    return Ok(Step::Running);
+                }
+                self.hungry |= trie.needs_more(line, sp);
+                trie.matches(line, sp, &mut self.ends);
+                let first = if let Some((_, end)) = self.ends.first() {
+                    *end
+                } else {
+                    return Ok(Step::Running);
+                };
+                // 優先度の高い選択肢から試すので、低いものから順に積む
+                for i in (1..self.ends.len()).rev() {
+                    self.push_resume(pc + 1, self.ends[i].1);
                 }
                 safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                sp = first;
             }
-            Instruction::CapcherEnd(register_idx) => {
-                matched_str.get_mut(*register_idx as usize).unwrap().1 = sp;
+            Instruction::AtomicBegin => {
+                self.stack.push(Frame::Barrier);
+                self.entered += 1;
+                self.atomics.push(self.entered);
+                safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+            }
+            Instruction::AtomicEnd => {
+                let dropped = cut(&mut self.stack);
+                self.atomics.pop();
+                self.stack_registers.truncate(self.stack_registers.len() - dropped * self.register.len());
+                safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+            }
+            Instruction::CapcherBegin(_) | Instruction::CapcherEnd(_) => {
+                if let Some(slot) = capcher_slot(next) {
+                    save(&mut self.slots, &mut self.stack, slot, sp);
+                }
                 safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
             }
         }
+
+        self.thread = Some((pc, sp));
+        Ok(Step::Running)
     }
 }
 
-fn eval_width(inst: &[Instruction], line: &[char]) -> Result<bool, EvalError> {
-    let mut ctx = VecDeque::new();
-    let mut pc = 0;
-    let mut sp = 0;
+/// Pike VM のスレッド (pc, node, register, slots)。カウンタとキャプチャの位置はスレッドごとに持つ。
+/// `node` はトライ木の命令を途中まで読んだときの節点で、それ以外の命令では `Trie::ROOT`
+type Thread<'a> = (usize, usize, &'a [usize], &'a [Option<usize>]);
 
-    loop {
-        let next = if let Some(i) = inst.get(pc) {
-            i
+/// ある sp にいるスレッドの優先度順の並び。同じ状態のスレッドは優先度の高いほうだけ残す。
+/// スレッドごとのカウンタとキャプチャの位置は、スレッドの順に 1 つの配列に詰めて持つ
+#[derive(Default)]
+struct ThreadList {
+    /// スレッドごとの (pc, node)
+    threads: Vec<(usize, usize)>,
+    registers: Vec<usize>,
+    slots: Slots,
+    /// 1 つのスレッドが持つ (レジスタの数, スロットの数)
+    width: (usize, usize),
+    seen: StateSet<2>,
+    /// レジスタを使わず、トライ木の途中でもない状態は pc だけで決まるので、ハッシュを使わずに覚える
+    seen_pcs: Vec<bool>,
+    /// `seen_pcs` で印を付けた pc。消すときに全体をなめないようにする
+    marked: Vec<usize>,
+}
+
+impl ThreadList {
+    /// 空にして、1 つのスレッドが持つレジスタとスロットの数を決める
+    fn clear(&mut self, registers: usize, slots: usize) {
+        self.threads.clear();
+        self.registers.clear();
+        self.slots.clear();
+        self.width = (registers, slots);
+        self.seen.clear(registers);
+        for pc in self.marked.drain(..) {
+            self.seen_pcs[pc] = false;
+        }
+    }
+
+    /// `i` 番目のスレッド
+    fn get(&self, i: usize) -> Thread<'_> {
+        let (registers, slots) = self.width;
+        let (pc, node) = self.threads[i];
+        (
+            pc,
+            node,
+            &self.registers[i * registers..(i + 1) * registers],
+            &self.slots[i * slots..(i + 1) * slots],
+        )
+    }
+
+    fn push(&mut self, (pc, node, register, slots): Thread) {
+        self.threads.push((pc, node));
+        self.registers.extend_from_slice(register);
+        self.slots.extend_from_slice(slots);
+    }
+
+    fn insert(&mut self, pc: usize, node: usize, register: &[usize]) -> bool {
+        if !register.is_empty() || node != Trie::ROOT {
+            return self.seen.insert([pc, node], register);
+        }
+        if pc >= self.seen_pcs.len() {
+            self.seen_pcs.resize(pc + 1, false);
+        }
+        if self.seen_pcs[pc] {
+            false
         } else {
-            return Err(EvalError::InvalidPC);
-        };
-        match next {
-            Instruction::Char(c) => {
-                if let Some(sp_c) = line.get(sp) {
-                    if c == sp_c || *c == '.' {
-                        safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
-                        safe_add(&mut sp, &1, || EvalError::SPOverFlow)?;
-                    } else {
-                        if ctx.is_empty() {
-                            return Ok(false);
-                        } else {
-                            pop_ctx(&mut pc, &mut sp, &mut ctx)?;
+            self.seen_pcs[pc] = true;
+            self.marked.push(pc);
+            true
+        }
+    }
+}
+
+/// `add_thread` が文字を読まない命令を辿るときのスタック。
+/// 後で辿る分岐の pc と、そのときのカウンタとキャプチャの位置を詰めて積む
+#[derive(Default)]
+struct Pending {
+    pcs: Vec<usize>,
+    registers: Vec<usize>,
+    slots: Slots,
+    /// 今辿っているスレッドのカウンタの値
+    register: Vec<usize>,
+    /// 今辿っているスレッドのキャプチャの位置
+    current_slots: Slots,
+}
+
+impl Pending {
+    /// 空にして、1 つのスレッドが持つレジスタとスロットの数を決める
+    fn clear(&mut self, registers: usize, slots: usize) {
+        self.pcs.clear();
+        self.registers.clear();
+        self.slots.clear();
+        self.register.clear();
+        self.register.resize(registers, 0);
+        self.current_slots.clear();
+        self.current_slots.resize(slots, None);
+    }
+
+    /// `pc` から、カウンタの値 `register` とキャプチャの位置 `slots` で辿るスレッドを積む
+    fn push(&mut self, pc: usize, register: &[usize], slots: &[Option<usize>]) {
+        self.pcs.push(pc);
+        self.registers.extend_from_slice(register);
+        self.slots.extend_from_slice(slots);
+    }
+
+    /// カウンタがすべて 0 で、キャプチャのない始まりのスレッドを積む
+    fn push_start(&mut self, pc: usize) {
+        self.pcs.push(pc);
+        self.registers.resize(self.registers.len() + self.register.len(), 0);
+        self.slots.resize(self.slots.len() + self.current_slots.len(), None);
+    }
+
+    /// 今辿っているスレッドを、`pc` から辿り直せるように積む
+    fn fork(&mut self, pc: usize) {
+        self.pcs.push(pc);
+        self.registers.extend_from_slice(&self.register);
+        self.slots.extend_from_slice(&self.current_slots);
+    }
+
+    /// 最後に積んだスレッドを取り出して、今辿っているスレッドにする
+    fn pop(&mut self) -> Option<usize> {
+        let pc = self.pcs.pop()?;
+        let n = self.registers.len() - self.register.len();
+        self.register.copy_from_slice(&self.registers[n..]);
+        self.registers.truncate(n);
+        let n = self.slots.len() - self.current_slots.len();
+        self.current_slots.copy_from_slice(&self.slots[n..]);
+        self.slots.truncate(n);
+        Some(pc)
+    }
+}
+
+/// Pike VM の作業領域
+#[derive(Default)]
+struct WidthScratch {
+    /// 今の sp と次の sp のスレッド。中身は捨てて使い回す
+    lists: [ThreadList; 2],
+    pending: Pending,
+    /// `run` で見つけた最優先のマッチのキャプチャの位置
+    matched: Slots,
+}
+
+struct PikeVm<'a> {
+    inst: &'a [Instruction],
+    line: &'a [char],
+    /// マッチを試し始める位置
+    start: usize,
+    /// これより先の文字は読まない。残りの文字は `$` や `\b` の判定にだけ使う
+    end: usize,
+    watchdog: &'a mut Watchdog,
+    /// 後読みを評価する作業領域。後読みの中身を評価するときは、中に後読みがないので None
+    behind: Option<&'a mut BehindScratch>,
+    /// 入力の終わりで文字を待ったスレッドがあったか
+    hungry: bool,
+}
+
+impl PikeVm<'_> {
+    /// sp を 1 つずつ進めながら、その位置にいるスレッドをまとめて動かす。
+    /// マッチしたら、それより優先度の高いスレッドがなくなるまで続けて最優先のマッチを探し、
+    /// 見つかれば true を返して、そのキャプチャの位置を `scratch.matched` に入れる
+    fn run(&mut self, scratch: &mut WidthScratch) -> Result<bool, EvalError> {
+        let WidthScratch { lists, pending, matched } = scratch;
+        // 中身ではなく参照を入れ替えて、どの評価も lists[0] から始める
+        let [mut threads, mut next_threads] = lists.each_mut();
+        let (registers, slots) = (count_registers(self.inst), count_capchers(self.inst) * 2);
+        threads.clear(registers, slots);
+        pending.clear(registers, slots);
+        pending.push_start(0);
+        self.add_thread(self.start, threads, pending)?;
+
+        let mut found = false;
+        for sp in self.start..=self.end {
+            if threads.threads.is_empty() {
+                break;
+            }
+            next_threads.clear(registers, slots);
+            for i in 0..threads.threads.len() {
+                self.watchdog.tick()?;
+                let (pc, node, register, thread_slots) = threads.get(i);
+                let sp_c = self.line[..self.end].get(sp);
+                let inst = &self.inst[pc];
+                self.hungry |= sp_c.is_none() && !matches!(inst, Instruction::Match | Instruction::MatchRule(_));
+                match inst {
+                    Instruction::Match | Instruction::MatchRule(_) => {
+                        // 残りのスレッドはこれより優先度が低い
+                        matched.clear();
+                        matched.extend_from_slice(thread_slots);
+                        found = true;
+                        break;
+                    }
+                    Instruction::Trie(trie) => {
+                        if let Some(next) = sp_c.and_then(|c| trie.next(node, *c)) {
+                            let thread = (pc, next, register, thread_slots);
+                            self.step_trie(sp, next_threads, pending, trie, thread)?;
                         }
                     }
-                } else {
-                    if ctx.is_empty() {
-                        return Ok(false);
-                    } else {
-                        pop_ctx(&mut pc, &mut sp, &mut ctx)?;
+                    inst => {
+                        if sp_c.is_some_and(|c| is_char_match(inst, c)) {
+                            pending.push(pc + 1, register, thread_slots);
+                            self.add_thread(sp + 1, next_threads, pending)?;
+                        }
                     }
                 }
             }
-            Instruction::UnmatchChars(_) => {
-                todo!()
+            mem::swap(&mut threads, &mut next_threads);
+        }
+
+        Ok(found)
+    }
+
+    /// 規則ごとのマッチのうち最長のものを (終わりの位置, 規則の番号) で返す。
+    /// 同じ長さなら番号の小さい規則を選ぶ。スレッドがなくなるまで入力を読む
+    fn run_longest(&mut self, scratch: &mut WidthScratch) -> Result<Option<(usize, usize)>, EvalError> {
+        let WidthScratch { lists, pending, .. } = scratch;
+        let [mut threads, mut next_threads] = lists.each_mut();
+        // キャプチャは記録しない
+        let registers = count_registers(self.inst);
+        threads.clear(registers, 0);
+        pending.clear(registers, 0);
+        pending.push_start(0);
+        self.add_thread(self.start, threads, pending)?;
+
+        let mut longest: Option<(usize, usize)> = None;
+        for sp in self.start..=self.end {
+            if threads.threads.is_empty() {
+                break;
             }
-            Instruction::AnyNumber => {
-                todo!()
+            next_threads.clear(registers, 0);
+            for i in 0..threads.threads.len() {
+                self.watchdog.tick()?;
+                let (pc, node, register, slots) = threads.get(i);
+                let sp_c = self.line[..self.end].get(sp);
+                match &self.inst[pc] {
+                    Instruction::Match => return Err(EvalError::InvalidContext),
+                    Instruction::MatchRule(rule) => {
+                        // sp は増えていくので、同じ sp で番号が小さいときだけ入れ替える
+                        if longest.is_none_or(|(end, r)| end < sp || *rule < r) {
+                            longest = Some((sp, *rule));
+                        }
+                    }
+                    Instruction::Trie(trie) => {
+                        if let Some(next) = sp_c.and_then(|c| trie.next(node, *c)) {
+                            self.step_trie(sp, next_threads, pending, trie, (pc, next, register, slots))?;
+                        }
+                    }
+                    inst => {
+                        if sp_c.is_some_and(|c| is_char_match(inst, c)) {
+                            pending.push(pc + 1, register, slots);
+                            self.add_thread(sp + 1, next_threads, pending)?;
+                        }
+                    }
+                }
             }
-            Instruction::NotNumber => {
-                todo!()
+            mem::swap(&mut threads, &mut next_threads);
+        }
+
+        Ok(longest)
+    }
+
+    /// 開始位置を決めずに走査し、最優先のマッチの終わりの位置を返す。キャプチャは記録しない。
+    /// マッチが見つかるまでは、各 sp で優先度の最も低いスレッドとして開始位置のスレッドを加える。
+    /// 左の開始位置から出たスレッドほど優先度が高いので、最も左から始まるマッチが選ばれる
+    fn run_unanchored(&mut self, scratch: &mut WidthScratch) -> Result<Option<usize>, EvalError> {
+        let WidthScratch { lists, pending, .. } = scratch;
+        let [mut threads, mut next_threads] = lists.each_mut();
+        let registers = count_registers(self.inst);
+        threads.clear(registers, 0);
+        pending.clear(registers, 0);
+
+        let mut matched = None;
+        for sp in self.start..=self.end {
+            if matched.is_none() {
+                pending.push_start(0);
+                self.add_thread(sp, threads, pending)?;
+            } else if threads.threads.is_empty() {
+                break;
             }
-            Instruction::Caret => {
-                if sp != 0 {
-                    return Ok(false);
+            next_threads.clear(registers, 0);
+            for i in 0..threads.threads.len() {
+                self.watchdog.tick()?;
+                let (pc, node, register, slots) = threads.get(i);
+                let sp_c = self.line[..self.end].get(sp);
+                match &self.inst[pc] {
+                    Instruction::Match | Instruction::MatchRule(_) => {
+                        // 残りのスレッドはこれより優先度が低い
+                        matched = Some(sp);
+                        break;
+                    }
+                    Instruction::Trie(trie) => {
+                        if let Some(next) = sp_c.and_then(|c| trie.next(node, *c)) {
+                            self.step_trie(sp, next_threads, pending, trie, (pc, next, register, slots))?;
+                        }
+                    }
+                    inst => {
+                        if sp_c.is_some_and(|c| is_char_match(inst, c)) {
+                            pending.push(pc + 1, register, slots);
+                            self.add_thread(sp + 1, next_threads, pending)?;
+                        }
+                    }
                 }
-                safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
             }
-            Instruction::Doller => {
-                if sp != line.len() {
-                    return Ok(false);
+            mem::swap(&mut threads, &mut next_threads);
+        }
+
+        Ok(matched)
+    }
+
+    /// トライ木を 1 文字進めたスレッド `thread` を加える。
+    /// そこで終わる選択肢とまだ続く選択肢とでは、選択肢の番号が小さいほうを先にする。
+    /// コード生成は `Trie::is_nested` なトライ木しか作らないので、まだ続く選択肢はすべて同じ側にある
+    fn step_trie(
+        &mut self,
+        sp: usize,
+        threads: &mut ThreadList,
+        pending: &mut Pending,
+        trie: &Trie,
+        thread: Thread,
+    ) -> Result<(), EvalError> {
+        let (pc, node, register, slots) = thread;
+        let accept = trie.accept(node);
+        // ここで終わる選択肢が、この先で終わるどの選択肢よりも優先されるか
+        let accept_first = accept.is_some_and(|alt| alt == trie.min_accept(node));
+
+        if accept_first {
+            pending.push(pc + 1, register, slots);
+            self.add_thread(sp + 1, threads, pending)?;
+        }
+        if trie.has_next(node) && threads.insert(pc, node, register) {
+            threads.push(thread);
+        }
+        if accept.is_some() && !accept_first {
+            pending.push(pc + 1, register, slots);
+            self.add_thread(sp + 1, threads, pending)?;
+        }
+        Ok(())
+    }
+
+    /// `pending` に積んだスレッドから文字を読まない命令を辿り、
+    /// 文字を読む命令か Match にいるスレッドを `threads` に加える
+    fn add_thread(&mut self, sp: usize, threads: &mut ThreadList, pending: &mut Pending) -> Result<(), EvalError> {
+        while let Some(mut pc) = pending.pop() {
+            loop {
+                self.watchdog.tick()?;
+                if !threads.insert(pc, Trie::ROOT, &pending.register) {
+                    break;
+                }
+                let next = if let Some(i) = self.inst.get(pc) {
+                    i
+                } else {
+                    return Err(EvalError::InvalidPC);
+                };
+
+                match next {
+                    Instruction::Caret => {
+                        if sp != 0 {
+                            break;
+                        }
+                        safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                    }
+                    Instruction::Doller => {
+                        if sp != self.line.len() {
+                            break;
+                        }
+                        safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                    }
+                    Instruction::Assertion(a) => {
+                        if !a.is_match(self.line, sp) {
+                            self.hungry |= sp >= self.line.len() && a.looks_ahead();
+                            break;
+                        }
+                        safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                    }
+                    Instruction::LookBehind(l) => {
+                        let behind = if let Some(behind) = self.behind.as_deref_mut() {
+                            behind
+                        } else {
+                            return Err(EvalError::InvalidContext);
+                        };
+                        if !look_behind(l, self.line, sp, self.watchdog, behind)? {
+                            self.hungry |= sp >= self.line.len() && l.looks_ahead();
+                            break;
+                        }
+                        safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                    }
+                    Instruction::Jump(addr) => pc = *addr,
+                    Instruction::Split(addr1, addr2) => {
+                        pending.fork(*addr2);
+                        pc = *addr1;
+                    }
+                    Instruction::CounterReset(idx) => {
+                        pending.register[*idx] = 0;
+                        safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                    }
+                    Instruction::CounterSplit(addr1, addr2, (min, max), idx) => {
+                        let count = pending.register[*idx];
+                        if count < *min {
+                            pc = *addr1;
+                        } else if count >= *max {
+                            pc = *addr2;
+                        } else {
+                            pending.fork(*addr2);
+                            pc = *addr1;
+                        }
+                    }
+                    Instruction::CounterIncrement(idx) => {
+                        pending.register[*idx] += 1;
+                        safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                    }
+                    Instruction::CapcherBegin(_) | Instruction::CapcherEnd(_) => {
+                        // キャプチャを記録しない走査ではスロットがない
+                        if let Some(slot) = capcher_slot(next).and_then(|slot| pending.current_slots.get_mut(slot)) {
+                            *slot = Some(sp);
+                        }
+                        safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                    }
+                    // アトミックグループを含むプログラムは eval_depth で評価する
+                    Instruction::AtomicBegin | Instruction::AtomicEnd => {
+                        return Err(EvalError::InvalidContext)
+                    }
+                    Instruction::Match
+                    | Instruction::MatchRule(_)
+                    | Instruction::Char(_)
+                    | Instruction::UnmatchChars(_)
+                    | Instruction::AnyNumber
+                    | Instruction::NotNumber
+                    | Instruction::MatchChars(_)
+                    | Instruction::Property(_)
+                    | Instruction::NotProperty(_)
+                    | Instruction::AnyChar
+                    | Instruction::Trie(_) => {
+                        threads.push((pc, Trie::ROOT, &pending.register, &pending.current_slots));
+                        break;
+                    }
                 }
             }
-            Instruction::Match => {
-                return Ok(true);
-            }
-            Instruction::Jump(addr) => {
-                pc = *addr;
-            }
-            Instruction::Split(addr1, addr2, _count, _is_register_idx_increment) => {
-                pc = *addr1;
-                ctx.push_back((*addr2, sp));
-                continue;
-            }
-            Instruction::Descrement(_register_idx) => todo!(),
-            Instruction::CapcherBegin(_) => todo!(),
-            Instruction::CapcherEnd(_) => todo!(),
         }
 
-        if !ctx.is_empty() {
-            ctx.push_back((pc, sp));
-            pop_ctx(&mut pc, &mut sp, &mut ctx)?;
-        }
+        Ok(())
     }
 }
 
-fn pop_ctx(
-    pc: &mut usize,
-    sp: &mut usize,
-    ctx: &mut VecDeque<(usize, usize)>,
-) -> Result<(), EvalError> {
-    if let Some((p, s)) = ctx.pop_back() {
-        *pc = p;
-        *sp = s;
-        Ok(())
+/// 後読みの中身を評価する作業領域
+#[derive(Default)]
+struct BehindScratch {
+    /// 中身が届く範囲の入力を逆順にしたもの
+    window: Vec<char>,
+    width: WidthScratch,
+}
+
+/// 入力 `line` の `sp` の直前に後読み `l` の中身があるか (否定なら、ないか)。
+/// 中身が届く範囲の前後に 1 文字ずつ足した部分だけを逆順にして、逆向きのプログラムを Pike VM で評価する。
+/// 足した文字は `\b` の判定に使い、部分の端が入力の端でなければ `^` や `$` は成り立たない。
+/// 中身で実行した命令も、呼び出し元の `watchdog` で数える
+fn look_behind(
+    l: &LookBehind,
+    line: &[char],
+    sp: usize,
+    watchdog: &mut Watchdog,
+    scratch: &mut BehindScratch,
+) -> Result<bool, EvalError> {
+    let lo = sp.saturating_sub(l.max_len() + 1);
+    let hi = (sp + 1).min(line.len());
+    let BehindScratch { window, width } = scratch;
+    window.clear();
+    window.extend(line[lo..hi].iter().rev());
+    // 逆向きのプログラムにはアトミックグループも後読みもないので、Pike VM で評価できる
+    let mut vm = PikeVm {
+        inst: l.code(),
+        line: window,
+        start: hi - sp,
+        end: window.len(),
+        watchdog,
+        behind: None,
+        hungry: false,
+    };
+    Ok(vm.run(width)? != l.is_negated())
+}
+
+fn eval_width(
+    inst: &[Instruction],
+    line: &[char],
+    start: usize,
+    limits: &EvalLimits,
+    scratch: &mut Scratch,
+    captures: &mut CaptureSpans,
+) -> Result<bool, EvalError> {
+    let mut vm = PikeVm {
+        inst,
+        line,
+        start,
+        end: line.len(),
+        watchdog: &mut Watchdog::new(limits),
+        behind: Some(&mut scratch.behind),
+        hungry: false,
+    };
+    if vm.run(&mut scratch.width)? {
+        write_captures(&scratch.width.matched, captures);
+        Ok(true)
     } else {
-        Err(EvalError::InvalidContext)
+        Ok(false)
     }
 }
 
+/// 入力の途中までで判定した結果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Partial {
+    /// 今の入力でマッチした
+    Matched(CaptureSpans),
+    /// 入力を足してもマッチしない
+    NoMatch,
+    /// 今はマッチしないが、入力を足せばマッチするかもしれない
+    NeedMoreInput,
+}
+
+/// `line` を入力の先頭部分とみなして、`start` 文字目から評価する。
+/// マッチしなかったとき、入力の終わりで文字を待ったスレッドがあれば `NeedMoreInput` を返す。
+/// アトミックグループを含むプログラムでは、足した入力で選択肢が変わっても結局マッチしない場合にも
+/// `NeedMoreInput` になることがある
+pub fn eval_partial(
+    inst: &[Instruction],
+    line: &[char],
+    start: usize,
+    is_depth: bool,
+    limits: &EvalLimits,
+    scratch: &mut Scratch,
+) -> Result<Partial, EvalError> {
+    let has_atomic = inst.contains(&Instruction::AtomicBegin);
+    let (matched, hungry) = if is_depth || has_atomic {
+        let vm = &mut scratch.depth;
+        vm.reset(inst, start, limits);
+        loop {
+            match vm.step(inst, line)? {
+                Step::Running => (),
+                Step::Matched => break (Some(vm.captures()), vm.needs_more_input()),
+                Step::Failed => break (None, vm.needs_more_input()),
+            }
+        }
+    } else {
+        let mut vm = PikeVm {
+            inst,
+            line,
+            start,
+            end: line.len(),
+            watchdog: &mut Watchdog::new(limits),
+            behind: Some(&mut scratch.behind),
+            hungry: false,
+        };
+        let found = vm.run(&mut scratch.width)?;
+        (found.then(|| to_captures(&scratch.width.matched)), vm.hungry)
+    };
+
+    Ok(match matched {
+        Some(captures) => Partial::Matched(captures),
+        None if hungry => Partial::NeedMoreInput,
+        None => Partial::NoMatch,
+    })
+}
+
+/// `codegen::get_rules_code` のプログラムを `line` の `start` 文字目から評価し、
+/// 最長のマッチを (終わりの位置, 規則の番号) で返す。同じ長さなら番号の小さい規則を選ぶ。
+/// アトミックグループは Pike VM で扱えないので使えない
+pub fn eval_longest(
+    inst: &[Instruction],
+    line: &[char],
+    start: usize,
+    limits: &EvalLimits,
+) -> Result<Option<(usize, usize)>, EvalError> {
+    let mut vm = PikeVm {
+        inst,
+        line,
+        start,
+        end: line.len(),
+        watchdog: &mut Watchdog::new(limits),
+        behind: Some(&mut BehindScratch::default()),
+        hungry: false,
+    };
+    vm.run_longest(&mut WidthScratch::default())
+}
+
+/// `line` の `from` 文字目から右で、最も左から始まるマッチの開始位置を、開始位置ごとに評価し直さずに求める。
+/// `reverse` は `codegen::get_reverse_code` で作った逆向きのプログラム、`reversed` は `line` を逆順にしたもの。
+///
+/// まず Pike VM で前向きに 1 度だけ走査し、最も左から始まるマッチの終わりを見つける。
+/// 次にその終わりから `reverse` を右から左へ `from` まで走らせ、最も遠くに届いた位置を始まりとする。
+/// それより左から始まってこの終わりに届くマッチはないので、これが求める開始位置になる。
+/// 入力の長さを n、プログラムの長さを m として O(n × m) で終わる。
+/// アトミックグループは Pike VM で扱えないので使えない。
+///
+/// 前向きの走査には DFA ではなく Pike VM を使う。最も左から始まるマッチの終わりは、
+/// 選択や繰り返しの優先度 (leftmost-first) で決まるので、スレッドの優先順位を持たない DFA では求まらない。
+/// `DerivativeRegex` の DFA は最長や最短のマッチしか表せず、`\b` や後読みも扱えない
+pub fn leftmost_start(
+    inst: &[Instruction],
+    reverse: &[Instruction],
+    line: &[char],
+    reversed: &[char],
+    from: usize,
+    scratch: &mut Scratch,
+) -> Result<Option<usize>, EvalError> {
+    let mut vm = PikeVm {
+        inst,
+        line,
+        start: from,
+        end: line.len(),
+        watchdog: &mut Watchdog::default(),
+        behind: Some(&mut scratch.behind),
+        hungry: false,
+    };
+    let end = if let Some(end) = vm.run_unanchored(&mut scratch.width)? {
+        end
+    } else {
+        return Ok(None);
+    };
+
+    let n = line.len();
+    let mut vm = PikeVm {
+        inst: reverse,
+        line: reversed,
+        start: n - end,
+        end: n - from,
+        watchdog: &mut Watchdog::default(),
+        behind: None,
+        hungry: false,
+    };
+    Ok(vm.run_longest(&mut scratch.width)?.map(|(rev_end, _)| n - rev_end))
+}
+
+/// プログラムが使うキャプチャの数
+fn count_capchers(inst: &[Instruction]) -> usize {
+    inst.iter()
+        .filter_map(|i| match i {
+            Instruction::CapcherBegin(idx) if *idx >= 0 => Some(*idx as usize + 1),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// プログラムが使うカウンタのレジスタ数
+fn count_registers(inst: &[Instruction]) -> usize {
+    inst.iter()
+        .filter_map(|i| match i {
+            Instruction::CounterReset(idx) => Some(idx + 1),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// `line` の `start` 文字目からマッチを試し、マッチしたかと、グループごとにキャプチャした文字列を返す。
+/// `start` より前の文字は `^` や `\b` の判定にだけ使う。
+/// グループは最後に通ったときの位置を返し、一度も通らなかったグループは None になる
 pub fn eval(
     inst: &[Instruction],
     line: &[char],
-    index: usize,
+    start: usize,
+    is_depth: bool,
+    limits: &EvalLimits,
+) -> Result<(bool, Vec<Option<String>>), EvalError> {
+    let (is_match, captures) = eval_captures(inst, line, start, is_depth, limits)?;
+    let captures = captures
+        .into_iter()
+        .map(|c| c.map(|(start, end)| line[start..end].iter().collect()))
+        .collect();
+    Ok((is_match, captures))
+}
+
+/// `eval` と同じだが、キャプチャを `line` の中の位置 (何文字目から何文字目まで) で返す
+pub fn eval_captures(
+    inst: &[Instruction],
+    line: &[char],
+    start: usize,
+    is_depth: bool,
+    limits: &EvalLimits,
+) -> Result<(bool, CaptureSpans), EvalError> {
+    let mut captures = Vec::new();
+    let is_match = eval_captures_with(inst, line, start, is_depth, limits, &mut Scratch::default(), &mut captures)?;
+    Ok((is_match, captures))
+}
+
+/// `eval_captures` と同じだが、作業領域 `scratch` とキャプチャを書き込む `captures` を使い回す。
+/// マッチしなかったときの `captures` の中身は決まっていない
+pub fn eval_captures_with(
+    inst: &[Instruction],
+    line: &[char],
+    start: usize,
     is_depth: bool,
-) -> Result<(bool, Vec<String>), EvalError> {
-    if is_depth {
-        let register = Vec::<(i32, Option<i32>)>::new();
-        let mut cache = HashSet::<(usize, usize)>::new();
-        let mut matched_str = Vec::<(usize, usize)>::new();
-        let is_match = eval_depth(inst, line, index, 0, 0, register, &mut cache, &mut matched_str)?;
-        Ok((is_match, matched_str.into_iter().map(|(start, end)| line[start..end].iter().collect()).collect()))
-        //Ok((false, vec![]))
+    limits: &EvalLimits,
+    scratch: &mut Scratch,
+    captures: &mut CaptureSpans,
+) -> Result<bool, EvalError> {
+    // Pike VM ではスレッドを捨てる順序を表せないので、アトミックグループは深さ優先で評価する
+    let has_atomic = inst.contains(&Instruction::AtomicBegin);
+    if is_depth || has_atomic {
+        if backtrack::is_applicable(inst, line) {
+            backtrack::eval(inst, line, start, limits, scratch, captures)
+        } else {
+            eval_depth(inst, line, start, limits, scratch, captures)
+        }
     } else {
-        eval_width(inst, line);
-        todo!()
+        eval_width(inst, line, start, limits, scratch, captures)
+    }
+}
+
+/// `start` 文字目から評価し、マッチしたら `captures` にグループの位置を入れる
+#[cfg(test)]
+type EvalFn =
+    fn(&[Instruction], &[char], usize, &EvalLimits, &mut Scratch, &mut CaptureSpans) -> Result<bool, EvalError>;
+
+/// 結果を突き合わせるテストで使う評価器
+#[cfg(test)]
+pub(crate) struct Backend {
+    pub name: &'static str,
+    /// このプログラムと入力を評価できるか
+    pub supports: fn(&[Instruction], &[char]) -> bool,
+    pub eval: EvalFn,
+}
+
+/// すべての評価器。評価器を足したらここにも加える
+#[cfg(test)]
+pub(crate) const BACKENDS: &[Backend] = &[
+    Backend {
+        name: "backtrack",
+        supports: backtrack::is_applicable,
+        eval: backtrack::eval,
+    },
+    Backend {
+        name: "depth",
+        supports: |_, _| true,
+        eval: eval_depth,
+    },
+    Backend {
+        name: "width",
+        supports: |inst, _| !inst.contains(&Instruction::AtomicBegin),
+        eval: eval_width,
+    },
+];
+
+#[cfg(test)]
+mod tests {
+    use super::{eval, eval_depth, EvalError, EvalLimits, Scratch, BACKENDS};
+    use crate::engine::{
+        codegen::{get_code, get_code_with_unroll_limit},
+        parser::parse,
+    };
+    use std::time::Duration;
+
+    fn run(expr: &str, line: &str, limits: &EvalLimits) -> Result<bool, EvalError> {
+        let code = get_code(&parse(expr).unwrap()).unwrap();
+        let line = line.chars().collect::<Vec<char>>();
+        eval(&code, &line, 0, true, limits).map(|(is_match, _)| is_match)
+    }
+
+    #[test]
+    fn test_budget() {
+        let line = "a".repeat(5000);
+        let limits = EvalLimits {
+            step_budget: Some(1000),
+            timeout: None,
+        };
+        assert!(matches!(run("a*c", &line, &limits), Err(EvalError::BudgetExceeded)));
+        assert!(matches!(run("a{1,}c", &line, &limits), Err(EvalError::BudgetExceeded)));
+
+        // 上限内で終わるものは普通に評価される
+        assert!(run("a?a?aa", "aa", &limits).unwrap());
+        assert!(!run("a?a?aa", "a", &limits).unwrap());
+
+        // 後読みの中身で実行した命令も数える
+        let code = get_code(&parse("(?<=a{0,1000})c").unwrap()).unwrap();
+        let line = format!("{}c", "a".repeat(1000)).chars().collect::<Vec<char>>();
+        for is_depth in [true, false] {
+            assert!(matches!(eval(&code, &line, 1000, is_depth, &limits), Err(EvalError::BudgetExceeded)));
+            assert!(eval(&code, &line, 1000, is_depth, &Default::default()).unwrap().0);
+        }
+    }
+
+    #[test]
+    fn test_timeout() {
+        let line = "a".repeat(5000);
+        let limits = EvalLimits {
+            step_budget: None,
+            timeout: Some(Duration::ZERO),
+        };
+        assert!(matches!(run("a*c", &line, &limits), Err(EvalError::Timeout)));
+        assert!(matches!(run("a{1,}c", &line, &limits), Err(EvalError::Timeout)));
+    }
+
+    #[test]
+    fn test_counter_cache() {
+        // 同じ (pc, sp) でもカウンタの値が違えば別の状態
+        assert!(run("[a*]{2}b", "aab", &EvalLimits::default()).unwrap());
+        assert!(run("[a?]{3}b", "ab", &EvalLimits::default()).unwrap());
+        assert!(!run("[a?]{3}b", "ac", &EvalLimits::default()).unwrap());
+    }
+
+    #[test]
+    fn test_deep_input() {
+        // 再帰していた頃はスタックを食いつぶしていた長さ
+        let line = "a".repeat(100_000) + "b";
+        assert!(run("a*b", &line, &EvalLimits::default()).unwrap());
+        assert!(run("[a|c]+b", &line, &EvalLimits::default()).unwrap());
+    }
+
+    /// alphabet からなる長さ max_len 以下の文字列をすべて返す
+    fn all_strings(alphabet: &[char], max_len: usize) -> Vec<Vec<char>> {
+        let mut result = vec![vec![]];
+        let mut last = vec![vec![]];
+        for _ in 0..max_len {
+            let mut next = Vec::new();
+            for s in &last {
+                for c in alphabet {
+                    let mut s = s.clone();
+                    s.push(*c);
+                    next.push(s);
+                }
+            }
+            result.extend(next.iter().cloned());
+            last = next;
+        }
+        result
+    }
+
+    #[test]
+    fn test_counter_exhaustive() {
+        let limits = EvalLimits::default();
+        let exprs = [
+            "^((ab){2}c){3}$",
+            "^[[ab]{1,2}c]{2}$",
+            "^[a*]{2,3}b$",
+            "^[a?]{3}b$",
+            "^[a|ab]{1,3}c$",
+            "^[b*]{0,2}$",
+            "^[a{1,2}b]{2,}$",
+            "^a{0}b$",
+            "^[[a|b]{2}]*$",
+            "^[c?]{2}[a|ab]{2}$",
+            "^[a{0,2}]{2}c$",
+        ];
+        let lines = all_strings(&['a', 'b', 'c'], 7);
+
+        for expr in exprs {
+            let ast = parse(expr).unwrap();
+            let unrolled = get_code(&ast).unwrap();
+            let looped = get_code_with_unroll_limit(&ast, 0).unwrap();
+            for line in &lines {
+                let expected = eval(&unrolled, line, 0, true, &limits).unwrap().0;
+                for (code, is_depth) in [(&unrolled, false), (&looped, true), (&looped, false)] {
+                    assert_eq!(
+                        eval(code, line, 0, is_depth, &limits).unwrap().0,
+                        expected,
+                        "expr: {expr}, line: {line:?}, is_depth: {is_depth}"
+                    );
+                }
+            }
+        }
+
+        let matches = |expr: &str, line: &str| {
+            let code = get_code_with_unroll_limit(&parse(expr).unwrap(), 0).unwrap();
+            let line = line.chars().collect::<Vec<char>>();
+            eval(&code, &line, 0, true, &limits).unwrap().0
+        };
+        assert!(matches("^((ab){2}c){3}$", "ababcababcababc"));
+        assert!(!matches("^((ab){2}c){3}$", "ababcababcabc"));
+        assert!(matches("^[a*]{2,3}b$", "aaab"));
+        assert!(matches("^[a?]{3}b$", "b"));
+        assert!(!matches("^[a?]{3}b$", "aaaab"));
+        assert!(matches("^[b*]{0,2}$", ""));
+        assert!(matches("^[a|ab]{1,3}c$", "abaabc"));
+        assert!(!matches("^[a|ab]{1,3}c$", "abaabac"));
+    }
+
+    #[test]
+    fn test_large_counter() {
+        let limits = EvalLimits::default();
+        let matches = |expr: &str, line: &str, is_depth: bool| {
+            let code = get_code(&parse(expr).unwrap()).unwrap();
+            let line = line.chars().collect::<Vec<char>>();
+            eval(&code, &line, 0, is_depth, &limits).unwrap().0
+        };
+        let line = "ab".repeat(200) + "c";
+        for is_depth in [true, false] {
+            assert!(matches("^[ab]{200}c$", &line, is_depth));
+            assert!(!matches("^[ab]{201}c$", &line, is_depth));
+            assert!(matches("^[ab]{150,}c$", &line, is_depth));
+            assert!(!matches("^[ab]{150,199}c$", &line, is_depth));
+            assert!(matches("^[[ab]{20}]{10}c$", &line, is_depth));
+            assert!(matches("^[[ab]{20}|b]{10,30}c$", &line, is_depth));
+        }
+    }
+
+    #[test]
+    fn test_atomic() {
+        let limits = EvalLimits::default();
+        let matches = |expr: &str, line: &str, unroll_limit: usize| {
+            let code = get_code_with_unroll_limit(&parse(expr).unwrap(), unroll_limit).unwrap();
+            let line = line.chars().collect::<Vec<char>>();
+            let is_match = eval(&code, &line, 0, true, &limits).unwrap().0;
+            // Pike VM を指定しても深さ優先で評価される
+            assert_eq!(eval(&code, &line, 0, false, &limits).unwrap().0, is_match);
+            is_match
+        };
+        // カウンタを含むパターンは、unroll_limit が 0 だとレジスタを使うので eval_depth で評価される
+        for unroll_limit in [0, 16] {
+            assert!(!matches("^a*+a$", "aaa", unroll_limit));
+            assert!(matches("^a*+b$", "aaab", unroll_limit));
+            assert!(!matches("^(?>ab|a)bc$", "abc", unroll_limit));
+            assert!(matches("^(?>a|ab)c$", "ac", unroll_limit));
+            assert!(!matches("^(?>a|ab)c$", "abc", unroll_limit));
+            assert!(matches("^[a|ab]c$", "abc", unroll_limit));
+            assert!(!matches("^a{1,3}+a$", "aaa", unroll_limit));
+            assert!(matches("^a{1,3}+a$", "aaaa", unroll_limit));
+            assert!(matches("^(?>(?>a+)b|a)+c$", "abaac", unroll_limit));
+            assert!(!matches("^\\d++0$", "1230", unroll_limit));
+            // アトミックグループの外の分岐は残る
+            assert!(matches("^[x|(?>a+)]b$", "aab", unroll_limit));
+            assert!(matches("^[(?>a)|ab]b$", "abb", unroll_limit));
+        }
+    }
+
+    #[test]
+    fn test_atomic_cache() {
+        // アトミックグループの中の状態の結果は、そこに来るまでに積んだ分岐で変わるので覚えてはいけない。
+        // x? が x を読んだときに x* の状態が失敗しても、x? が何も読まなければ x*a が xx を読んで、xxab は捨てられる
+        let long = "y".repeat(50_000) + "xxab";
+        let cases = [
+            ("^x?(?>x*a|xxab)$", "xxab", false),
+            ("^y*x?(?>x*a|xxab)$", long.as_str(), false),
+            ("^x?(?>x*a|xxab)b$", "xxab", true),
+            ("^(?:x?(?>x*a|xxab)$|xxa)", "xxab", true),
+        ];
+        let limits = EvalLimits::default();
+        for (expr, line, expected) in cases {
+            let code = get_code(&parse(expr).unwrap()).unwrap();
+            let line = line.chars().collect::<Vec<char>>();
+            for backend in BACKENDS.iter().filter(|b| (b.supports)(&code, &line)) {
+                let is_match = (backend.eval)(&code, &line, 0, &limits, &mut Scratch::default(), &mut vec![]).unwrap();
+                assert_eq!(is_match, expected, "backend = {}, pattern = {expr}", backend.name);
+            }
+            for is_depth in [true, false] {
+                assert_eq!(eval(&code, &line, 0, is_depth, &limits).unwrap().0, expected, "{expr}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_trie() {
+        let limits = EvalLimits::default();
+        let matches = |expr: &str, line: &str| {
+            let code = get_code(&parse(expr).unwrap()).unwrap();
+            let line = line.chars().collect::<Vec<char>>();
+            let is_match = eval(&code, &line, 0, true, &limits).unwrap().0;
+            assert_eq!(eval_depth(&code, &line, 0, &limits, &mut Scratch::default(), &mut vec![]).unwrap(), is_match);
+            assert_eq!(eval(&code, &line, 0, false, &limits).unwrap().0, is_match);
+            is_match
+        };
+
+        assert!(matches("wx|wy|wz", "wx"));
+        let words = (0..300).map(|i| format!("w{i}x")).collect::<Vec<String>>();
+        let expr = format!("^[{}]$", words.join("|"));
+        assert!(matches(&expr, "w0x"));
+        assert!(matches(&expr, "w299x"));
+        assert!(!matches(&expr, "w300x"));
+        assert!(!matches(&expr, "w29"));
+
+        // 共通の接頭辞を持つ選択肢も、後ろが合わなければ次の選択肢を試す
+        assert!(matches("^[a|ab|abc]c$", "abcc"));
+        assert!(matches("^[abc|ab|a]c$", "ac"));
+        assert!(!matches("^[abc|ab|a]d$", "abcc"));
+        // 終わる選択肢の優先度が、同じ接頭辞を持つ選択肢の間に挟まっていても、幅優先と深さ優先で同じ範囲になる
+        let cases = [("(abc|a|abd)", "abd", "a"), ("(abc|a|abd)", "abc", "abc"), ("x(abc|ab|abcd|a)", "xabcd", "abc")];
+        for (expr, line, expected) in cases {
+            let code = get_code(&parse(expr).unwrap()).unwrap();
+            let line = line.chars().collect::<Vec<char>>();
+            for is_depth in [true, false] {
+                let (is_match, captures) = eval(&code, &line, 0, is_depth, &limits).unwrap();
+                assert!(is_match);
+                assert_eq!(captures[0].as_deref(), Some(expected), "{expr} (depth_first: {is_depth})");
+            }
+        }
+        // アトミックグループでは最初に一致した選択肢だけを使う
+        assert!(matches("^(?>abc|ab|a)c$", "abcc"));
+        assert!(!matches("^(?>a|ab|abc)c$", "abcc"));
+        assert!(matches("^(?>ab|a|abc)c$", "abc"));
+    }
+
+    #[test]
+    fn test_capture() {
+        let limits = EvalLimits::default();
+        let captures = |expr: &str, line: &str| {
+            let line = line.chars().collect::<Vec<char>>();
+            let mut results = Vec::new();
+            for unroll_limit in [0, 16] {
+                let code = get_code_with_unroll_limit(&parse(expr).unwrap(), unroll_limit).unwrap();
+                results.push(eval(&code, &line, 0, true, &limits).unwrap());
+                results.push(eval(&code, &line, 0, false, &limits).unwrap());
+                let mut captures = Vec::new();
+                let is_match = eval_depth(&code, &line, 0, &limits, &mut Scratch::default(), &mut captures).unwrap();
+                let captures = captures
+                    .into_iter()
+                    .map(|c| c.map(|(start, end)| line[start..end].iter().collect()))
+                    .collect();
+                results.push((is_match, captures));
+            }
+            assert!(results.windows(2).all(|w| w[0] == w[1]), "{expr}: {results:?}");
+            results.pop().unwrap().1
+        };
+        let some = |s: &str| Some(s.to_string());
+
+        // 繰り返しの中のグループは最後の回の位置
+        assert_eq!(captures("(a|b)+", "ab"), vec![some("b")]);
+        assert_eq!(captures("(\\d)+", "123"), vec![some("3")]);
+        assert_eq!(captures("(\\d){3}", "123"), vec![some("3")]);
+        assert_eq!(captures("(\\d){2,4}", "123"), vec![some("3")]);
+        assert_eq!(captures("(a(b)?)+", "aba"), vec![some("a"), some("b")]);
+
+        // 通らなかった選択肢のグループは None
+        assert_eq!(captures("(a)|(b)", "b"), vec![None, some("b")]);
+        assert_eq!(captures("(a)?b", "b"), vec![None]);
+        assert_eq!(captures("(?:(a)|b)c", "bc"), vec![None]);
+        assert_eq!(captures("(?:(a)|b)+", "ab"), vec![some("a")]);
+        // 失敗して戻った選択肢で書いた位置も残らない
+        assert_eq!(captures("^(?:(a)x|a)c$", "ac"), vec![None]);
+        assert_eq!(captures("^(?>(a)x|a)c$", "ac"), vec![None]);
+
+        // 選択肢は左にあるものが優先される
+        assert_eq!(captures("(a|ab)(c|bcd)", "abcd"), vec![some("a"), some("bcd")]);
+        assert_eq!(captures("(a+)(a*)", "aaa"), vec![some("aaa"), some("")]);
+        assert_eq!(captures("(a)()", "a"), vec![some("a"), some("")]);
     }
 }
@@ -0,0 +1,257 @@
+//! RE2 の BitState と同じ考え方の、上限付きバックトラッカ。
+//!
+//! 状態が (pc, sp) だけで決まるプログラムなら、一度失敗した (pc, sp) に
+//! もう一度来ても必ず失敗するので、ビット集合で訪問済みを覚えて枝を刈れる。
+//! 計算量は O(プログラム長 × 入力長) で抑えられる。
+//!
+//! カウンタのレジスタはスレッドごとの状態なので (pc, sp) だけでは決まらない。
+//! そのためレジスタを使うループ (`CounterSplit` など) を含むプログラムには使わない。
+//!
+//! アトミックグループも使わない。atomic end はグループに入ってから積んだ分岐を捨てるので、
+//! グループの中の (pc, sp) から先の結果は、そこに来るまでに積んだ分岐によって変わる。
+
+use super::{
+    capcher_slot, count_capchers, cut, is_char_match, look_behind, save, write_captures, CaptureSpans, EvalError, EvalLimits, Frame,
+    Instruction, Scratch, Slots, Watchdog,
+};
+use crate::helper::safe_add;
+
+/// 訪問済みビット集合の上限 (ビット数)。これを超える入力では使わない。
+const MAX_VISITED_BITS: usize = 256 * 1024 * 8;
+
+#[derive(Default)]
+struct Visited {
+    bits: Vec<u64>,
+    width: usize,
+}
+
+impl Visited {
+    /// 印をすべて消す。確保済みの領域はそのまま使う
+    fn reset(&mut self, inst_len: usize, line_len: usize) {
+        // sp は line.len() まで取り得る
+        self.width = line_len + 1;
+        self.bits.clear();
+        self.bits.resize((inst_len * self.width).div_ceil(64), 0);
+    }
+
+    /// 未訪問なら印を付けて true を返す
+    fn insert(&mut self, pc: usize, sp: usize) -> bool {
+        let n = pc * self.width + sp;
+        let (word, bit) = (n / 64, 1 << (n % 64));
+        if self.bits[word] & bit != 0 {
+            false
+        } else {
+            self.bits[word] |= bit;
+            true
+        }
+    }
+}
+
+/// 評価のたびに確保し直さずに使い回す領域
+#[derive(Default)]
+pub(super) struct BacktrackScratch {
+    visited: Visited,
+    stack: Vec<Frame<(usize, usize)>>,
+    slots: Slots,
+    /// トライ木の命令でマッチした選択肢
+    ends: Vec<(usize, usize)>,
+}
+
+/// このバックトラッカで評価できるかを返す
+pub(super) fn is_applicable(inst: &[Instruction], line: &[char]) -> bool {
+    let has_state = inst.iter().any(|i| {
+        matches!(
+            i,
+            Instruction::CounterReset(_)
+                | Instruction::CounterSplit(..)
+                | Instruction::CounterIncrement(_)
+                | Instruction::AtomicBegin
+        )
+    });
+    !has_state
+        && inst
+            .len()
+            .checked_mul(line.len() + 1)
+            .is_some_and(|bits| bits <= MAX_VISITED_BITS)
+}
+
+pub(super) fn eval(
+    inst: &[Instruction],
+    line: &[char],
+    start: usize,
+    limits: &EvalLimits,
+    scratch: &mut Scratch,
+    captures: &mut CaptureSpans,
+) -> Result<bool, EvalError> {
+    let mut watchdog = Watchdog::new(limits);
+    let Scratch { backtrack, behind, .. } = scratch;
+    let BacktrackScratch {
+        visited,
+        stack,
+        slots,
+        ends,
+    } = backtrack;
+    slots.clear();
+    slots.resize(count_capchers(inst) * 2, None);
+    visited.reset(inst.len(), line.len());
+    stack.clear();
+    stack.push(Frame::Resume((0, start)));
+
+    'thread: while let Some(frame) = stack.pop() {
+        let (mut pc, mut sp) = match frame {
+            Frame::Resume(thread) => thread,
+            Frame::Barrier => continue,
+            Frame::Restore(slot, old) => {
+                slots[slot] = old;
+                continue;
+            }
+        };
+        loop {
+            watchdog.tick()?;
+
+            if !visited.insert(pc, sp) {
+                continue 'thread;
+            }
+            let next = if let Some(i) = inst.get(pc) {
+                i
+            } else {
+                return Err(EvalError::InvalidPC);
+            };
+
+            match next {
+                Instruction::Char(_)
+                | Instruction::UnmatchChars(_)
+                | Instruction::AnyNumber
+                | Instruction::NotNumber
+                | Instruction::MatchChars(_)
+                | Instruction::Property(_)
+                | Instruction::NotProperty(_)
+                | Instruction::AnyChar => {
+                    if !line.get(sp).is_some_and(|sp_c| is_char_match(next, sp_c)) {
+                        continue 'thread;
+                    }
+                    safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                    safe_add(&mut sp, &1, || EvalError::SPOverFlow)?;
+                }
+                Instruction::Caret => {
+                    if sp != 0 {
+                        continue 'thread;
+                    }
+                    safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                }
+                Instruction::Doller => {
+                    if sp != line.len() {
+                        continue 'thread;
+                    }
+                    safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                }
+                Instruction::Assertion(a) => {
+                    if !a.is_match(line, sp) {
+                        continue 'thread;
+                    }
+                    safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                }
+                Instruction::LookBehind(l) => {
+                    if !look_behind(l, line, sp, &mut watchdog, behind)? {
+                        continue 'thread;
+                    }
+                    safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                }
+                Instruction::Match | Instruction::MatchRule(_) => {
+                    write_captures(slots, captures);
+                    return Ok(true);
+                }
+                Instruction::Jump(addr) => pc = *addr,
+                Instruction::Split(addr1, addr2) => {
+                    stack.push(Frame::Resume((*addr2, sp)));
+                    pc = *addr1;
+                }
+                Instruction::CounterReset(_)
+                | Instruction::CounterSplit(..)
+                | Instruction::CounterIncrement(_) => return Err(EvalError::InvalidContext),
+                Instruction::Trie(trie) => {
+                    trie.matches(line, sp, ends);
+                    let (first, rest) = if let Some(ends) = ends.split_first() {
+                        ends
+                    } else {
+                        continue 'thread;
+                    };
+                    for (_, end) in rest.iter().rev() {
+                        stack.push(Frame::Resume((pc + 1, *end)));
+                    }
+                    safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                    sp = first.1;
+                }
+                Instruction::AtomicBegin => {
+                    stack.push(Frame::Barrier);
+                    safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                }
+                Instruction::AtomicEnd => {
+                    cut(stack);
+                    safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                }
+                Instruction::CapcherBegin(_) | Instruction::CapcherEnd(_) => {
+                    if let Some(slot) = capcher_slot(next) {
+                        save(slots, stack, slot, sp);
+                    }
+                    safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                }
+            }
+        }
+    }
+
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{eval, is_applicable};
+    use crate::engine::{
+        codegen::get_code,
+        evaluator::{EvalLimits, Scratch},
+        parser::parse,
+    };
+
+    fn run(expr: &str, line: &str) -> (bool, Vec<Option<(usize, usize)>>) {
+        let code = get_code(&parse(expr).unwrap()).unwrap();
+        let line = line.chars().collect::<Vec<char>>();
+        assert!(is_applicable(&code, &line));
+        let mut captures = Vec::new();
+        let is_match = eval(&code, &line, 0, &EvalLimits::default(), &mut Scratch::default(), &mut captures).unwrap();
+        (is_match, captures)
+    }
+
+    #[test]
+    fn test_bounded() {
+        assert!(run("abc|def", "def").0);
+        assert!(run("a[bc|de]*f", "abcdebcf").0);
+        assert!(!run("a[bc|de]*f", "abcdbcf").0);
+        assert!(run("^a+$", "aaa").0);
+        assert!(!run("^a+$", "aab").0);
+        assert_eq!(run("ab(\\d+)-", "ab123-"), (true, vec![Some((2, 5))]));
+
+        // 空文字にマッチするループの中の繰り返しでも止まる
+        assert!(run("[a*]*b", "aab").0);
+        assert!(!run("[a*]*b", "aac").0);
+
+        // バックトラックが指数的に増えるパターンでも (pc, sp) の数で抑えられる
+        let expr = "a?".repeat(30) + &"a".repeat(30);
+        assert!(run(&expr, &"a".repeat(30)).0);
+        assert!(!run(&expr, &"a".repeat(29)).0);
+    }
+
+    #[test]
+    fn test_applicable() {
+        let code = get_code(&parse("ad{2}b").unwrap()).unwrap();
+        assert!(is_applicable(&code, &['a', 'd', 'd', 'b']));
+        let code = get_code(&parse("ad{20}b").unwrap()).unwrap();
+        assert!(!is_applicable(&code, &['a', 'd', 'd', 'b']));
+
+        let code = get_code(&parse("a*").unwrap()).unwrap();
+        assert!(is_applicable(&code, &['a'; 1000]));
+        assert!(!is_applicable(&code, &vec!['a'; 1 << 20]));
+
+        let code = get_code(&parse("(?>a*)b").unwrap()).unwrap();
+        assert!(!is_applicable(&code, &['a', 'b']));
+    }
+}
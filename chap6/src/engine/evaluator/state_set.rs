@@ -0,0 +1,112 @@
+//! 評価器が一度通った状態の集合。
+//!
+//! 状態は pc などの決まった `N` 個の値と、カウンタのレジスタの値を並べた同じ長さの列で表す。
+//! `HashSet<Vec<usize>>` だと状態ごとに `Vec` を確保するので、すべての状態を 1 つの配列に詰め、
+//! 開番地法のハッシュ表からその位置を引く。`clear` しても確保した領域は手放さないので、
+//! 同じ集合を使い回せば、前より多くの状態を入れない限りメモリを確保し直さない。
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+};
+
+/// 空の表から最初に確保する大きさ
+const MIN_TABLE_LEN: usize = 16;
+
+#[derive(Default)]
+pub(super) struct StateSet<const N: usize> {
+    /// 1 つの状態の長さ
+    width: usize,
+    /// 入っている状態を入れた順に並べたもの
+    keys: Vec<usize>,
+    /// ハッシュ表。(世代, 状態の番号) で、世代が `generation` と違う場所は空いている。
+    /// `clear` は世代を進めるだけなので、表をなめずに空にできる
+    table: Vec<(usize, usize)>,
+    generation: usize,
+}
+
+impl<const N: usize> StateSet<N> {
+    /// 空にして、状態の長さを `N + registers` にする
+    pub fn clear(&mut self, registers: usize) {
+        self.width = N + registers;
+        self.keys.clear();
+        self.generation += 1;
+    }
+
+    fn len(&self) -> usize {
+        self.keys.len() / self.width
+    }
+
+    fn hash(head: [usize; N], tail: &[usize]) -> usize {
+        let mut hasher = DefaultHasher::new();
+        head.hash(&mut hasher);
+        tail.hash(&mut hasher);
+        hasher.finish() as usize
+    }
+
+    /// 状態 `head` + `tail` がなければ入れて true を返す。
+    /// `tail` の長さは `clear` で決めたレジスタの数と同じでなければならない
+    pub fn insert(&mut self, head: [usize; N], tail: &[usize]) -> bool {
+        debug_assert_eq!(N + tail.len(), self.width);
+        if (self.len() + 1) * 2 > self.table.len() {
+            self.grow();
+        }
+        let mask = self.table.len() - 1;
+        let mut i = Self::hash(head, tail) & mask;
+        loop {
+            let (generation, n) = self.table[i];
+            if generation != self.generation {
+                self.table[i] = (self.generation, self.len());
+                self.keys.extend_from_slice(&head);
+                self.keys.extend_from_slice(tail);
+                return true;
+            }
+            let key = &self.keys[n * self.width..(n + 1) * self.width];
+            if key[..N] == head && key[N..] == *tail {
+                return false;
+            }
+            i = (i + 1) & mask;
+        }
+    }
+
+    /// 表の大きさを倍にして、入っている状態を入れ直す
+    fn grow(&mut self) {
+        let len = (self.table.len() * 2).max(MIN_TABLE_LEN);
+        self.table.clear();
+        self.table.resize(len, (0, 0));
+        // 0 の世代を空き地として使えるように、世代を 1 から数え直す
+        self.generation = 1;
+        let mask = len - 1;
+        for (n, key) in self.keys.chunks(self.width).enumerate() {
+            let mut i = Self::hash(std::array::from_fn(|j| key[j]), &key[N..]) & mask;
+            while self.table[i].0 == self.generation {
+                i = (i + 1) & mask;
+            }
+            self.table[i] = (self.generation, n);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StateSet;
+
+    #[test]
+    fn test_insert() {
+        let mut set = StateSet::<2>::default();
+        set.clear(2);
+        for pc in 0..100 {
+            assert!(set.insert([pc, 0], &[pc % 3, 1]));
+        }
+        for pc in 0..100 {
+            assert!(!set.insert([pc, 0], &[pc % 3, 1]));
+            assert!(set.insert([pc, 0], &[pc % 3, 2]));
+        }
+
+        // 空にした後は同じ状態をまた入れられる。レジスタの数も変えられる
+        set.clear(0);
+        assert!(set.insert([1, 2], &[]));
+        assert!(!set.insert([1, 2], &[]));
+        assert!(set.insert([2, 1], &[]));
+    }
+}
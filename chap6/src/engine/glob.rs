@@ -0,0 +1,238 @@
+//! シェルのグロブを正規表現の AST にする。
+//!
+//! - `*` は `/` 以外の 0 文字以上、`?` は `/` 以外の 1 文字
+//! - パスの要素全体が `**` なら、0 個以上のディレクトリ (`**/`) か、その下のすべて (末尾の `**`)
+//! - `[ch]` `[a-z]` は文字の集合、`[!ch]` `[^ch]` はその否定。どちらも `/` にはマッチしない
+//! - `{a,b}` はどれか 1 つ。中にグロブを書いたり入れ子にしたりできる
+//! - `\` の次の文字はその文字そのもの
+
+use super::parser::AST;
+use std::{
+    error::Error,
+    fmt::{self, Display},
+};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum GlobError {
+    /// `[` が閉じていない
+    UnclosedBracket(usize),
+    /// `{` が閉じていない
+    UnclosedBrace(usize),
+    /// 対応する `{` のない `}`
+    UnmatchedBrace(usize),
+    /// `[z-a]` のように範囲が逆
+    InvalidRange(usize),
+    /// `\` で終わっている
+    TrailingEscape(usize),
+}
+
+impl Display for GlobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GlobError::UnclosedBracket(pos) => write!(f, "GlobError: unclosed bracket: pos = {pos}"),
+            GlobError::UnclosedBrace(pos) => write!(f, "GlobError: unclosed brace: pos = {pos}"),
+            GlobError::UnmatchedBrace(pos) => write!(f, "GlobError: unmatched brace: pos = {pos}"),
+            GlobError::InvalidRange(pos) => write!(f, "GlobError: invalid range: pos = {pos}"),
+            GlobError::TrailingEscape(pos) => write!(f, "GlobError: trailing escape: pos = {pos}"),
+        }
+    }
+}
+
+impl Error for GlobError {}
+
+impl GlobError {
+    /// エラーになった文字の位置 (何文字目か)
+    pub fn position(&self) -> usize {
+        match self {
+            GlobError::UnclosedBracket(pos)
+            | GlobError::UnclosedBrace(pos)
+            | GlobError::UnmatchedBrace(pos)
+            | GlobError::InvalidRange(pos)
+            | GlobError::TrailingEscape(pos) => *pos,
+        }
+    }
+}
+
+const SEPARATOR: char = '/';
+
+/// `/` 以外の 1 文字
+fn not_separator() -> AST {
+    AST::UnmatchChars(vec![SEPARATOR])
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    /// `{...}` の中なら `,` か `}` の手前まで、外なら最後まで読む
+    fn parse_seq(&mut self, in_brace: bool) -> Result<AST, GlobError> {
+        let mut seq = Vec::new();
+        while let Some(&c) = self.chars.get(self.pos) {
+            match c {
+                ',' | '}' if in_brace => break,
+                '}' => return Err(GlobError::UnmatchedBrace(self.pos)),
+                '*' => seq.push(self.parse_star(in_brace)),
+                '?' => {
+                    self.pos += 1;
+                    seq.push(not_separator());
+                }
+                '[' => seq.push(self.parse_bracket()?),
+                '{' => seq.push(self.parse_brace()?),
+                '\\' => {
+                    let c = *self.chars.get(self.pos + 1).ok_or(GlobError::TrailingEscape(self.pos))?;
+                    self.pos += 2;
+                    seq.push(AST::Char(c));
+                }
+                c => {
+                    self.pos += 1;
+                    seq.push(AST::Char(c));
+                }
+            }
+        }
+        Ok(AST::Seq(seq))
+    }
+
+    /// `*` か `**`
+    fn parse_star(&mut self, in_brace: bool) -> AST {
+        let start = self.pos;
+        let mut end = start;
+        while self.chars.get(end) == Some(&'*') {
+            end += 1;
+        }
+        self.pos = end;
+
+        // ** がパスの要素全体になっているときだけ、ディレクトリをまたぐ
+        let at_segment_start = start == 0 || self.chars[start - 1] == SEPARATOR;
+        let next = self.chars.get(end).copied();
+        let is_globstar = end - start == 2 && at_segment_start;
+        match next {
+            Some(SEPARATOR) if is_globstar => {
+                self.pos += 1;
+                // (?:[^/]*/)*
+                AST::Star(Box::new(AST::Seq(vec![AST::Star(Box::new(not_separator())), AST::Char(SEPARATOR)])))
+            }
+            None if is_globstar => AST::Star(Box::new(AST::AnyChar)),
+            Some(',' | '}') if is_globstar && in_brace => AST::Star(Box::new(AST::AnyChar)),
+            _ => AST::Star(Box::new(not_separator())),
+        }
+    }
+
+    /// `[...]`。`]` を集合に入れるときは先頭に書く
+    fn parse_bracket(&mut self) -> Result<AST, GlobError> {
+        let open = self.pos;
+        self.pos += 1;
+        let negated = matches!(self.chars.get(self.pos), Some('!' | '^'));
+        if negated {
+            self.pos += 1;
+        }
+
+        let mut chars = Vec::new();
+        let mut first = true;
+        loop {
+            let c = *self.chars.get(self.pos).ok_or(GlobError::UnclosedBracket(open))?;
+            if c == ']' && !first {
+                self.pos += 1;
+                break;
+            }
+            first = false;
+            // a-z の範囲。最後の - は文字そのもの
+            if self.chars.get(self.pos + 1) == Some(&'-') && self.chars.get(self.pos + 2).is_some_and(|c| *c != ']') {
+                let last = self.chars[self.pos + 2];
+                if last < c {
+                    return Err(GlobError::InvalidRange(self.pos));
+                }
+                chars.extend(c..=last);
+                self.pos += 3;
+            } else {
+                chars.push(c);
+                self.pos += 1;
+            }
+        }
+
+        // 文字の集合はディレクトリの区切りにはマッチしない
+        chars.retain(|c| *c != SEPARATOR);
+        chars.sort_unstable();
+        chars.dedup();
+        if negated {
+            chars.push(SEPARATOR);
+            Ok(AST::UnmatchChars(chars))
+        } else {
+            Ok(AST::MatchChars(chars))
+        }
+    }
+
+    /// `{a,b,...}`
+    fn parse_brace(&mut self) -> Result<AST, GlobError> {
+        let open = self.pos;
+        self.pos += 1;
+        let mut alternatives = Vec::new();
+        loop {
+            alternatives.push(self.parse_seq(true)?);
+            match self.chars.get(self.pos) {
+                Some(',') => self.pos += 1,
+                Some('}') => {
+                    self.pos += 1;
+                    break;
+                }
+                _ => return Err(GlobError::UnclosedBrace(open)),
+            }
+        }
+        let mut ast = alternatives.pop().unwrap();
+        while let Some(e) = alternatives.pop() {
+            ast = AST::Or(Box::new(e), Box::new(ast));
+        }
+        Ok(ast)
+    }
+}
+
+/// グロブを、パス全体にマッチする (`^...$` の) AST にする
+pub fn to_ast(glob: &str) -> Result<AST, GlobError> {
+    let mut parser = Parser {
+        chars: glob.chars().collect(),
+        pos: 0,
+    };
+    let ast = parser.parse_seq(false)?;
+    Ok(AST::Seq(vec![AST::Caret, ast, AST::Doller]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{to_ast, GlobError};
+    use crate::engine::{parser::AST, printer::to_pattern};
+
+    fn pattern(glob: &str) -> String {
+        to_pattern(&to_ast(glob).unwrap())
+    }
+
+    #[test]
+    fn test_to_ast() {
+        assert_eq!(pattern("*.rs"), "^[^/]*\\.rs$");
+        assert_eq!(pattern("file?.[ch]"), "^file[^/]\\.(?:c|h)$");
+        assert_eq!(pattern("src/**/mod.rs"), "^src/(?:[^/]*/)*mod\\.rs$");
+        assert_eq!(pattern("src/**"), "^src/.*$");
+        assert_eq!(pattern("a**b"), "^a[^/]*b$");
+        assert_eq!(pattern("{a,b*}.txt"), "^(?:a|b[^/]*)\\.txt$");
+        assert_eq!(pattern("[!a-c/]"), "^[^abc/]$");
+        assert_eq!(pattern("[]a]"), "^(?:\\]|a)$");
+        assert_eq!(pattern("\\*"), "^\\*$");
+        assert_eq!(
+            to_ast("{,x}").unwrap(),
+            AST::Seq(vec![
+                AST::Caret,
+                AST::Seq(vec![AST::Or(Box::new(AST::Seq(vec![])), Box::new(AST::Seq(vec![AST::Char('x')])))]),
+                AST::Doller,
+            ])
+        );
+    }
+
+    #[test]
+    fn test_error() {
+        assert_eq!(to_ast("a[bc").unwrap_err(), GlobError::UnclosedBracket(1));
+        assert_eq!(to_ast("{a,{b}").unwrap_err(), GlobError::UnclosedBrace(0));
+        assert_eq!(to_ast("a}").unwrap_err(), GlobError::UnmatchedBrace(1));
+        assert_eq!(to_ast("[z-a]").unwrap_err(), GlobError::InvalidRange(1));
+        assert_eq!(to_ast("ab\\").unwrap_err(), GlobError::TrailingEscape(2));
+    }
+}
@@ -0,0 +1,460 @@
+//! 評価器の結果を突き合わせるテスト用のモジュール。
+//!
+//! `Reference` は AST を直接たどる参照実装で、各ノードを読み終えられる位置を
+//! 優先度順に並べて返す。同じ位置で終わるものは優先度の高いものだけ残すので、
+//! 位置の集合を順序付きで持つことになる。遅いが、コード生成も評価器の枝刈りも通らない。
+//!
+//! 評価器は一度通った状態にもう一度来たスレッドを捨てるので、文字を読まない繰り返しは次のように扱われる。
+//! 参照実装もこれに合わせる。
+//!
+//! - `e*` と 2 回目以降の `e+` では、何も読まなかった回は失敗する
+//! - `e+` の 1 回目が何も読まなかったら、そこで繰り返しを終える
+//! - `e{n,m}` は e を n 個並べた後に `(e(e)?)?` を置いたもの、`e{n,}` は e を n 個並べた後に `e*` を置いたもの
+//!
+//! 後読みは、今の位置で終わる中身がどこかから始まるかを、左の位置をすべて試して調べる。
+//!
+//! `Xor64` と `random_ast` `random_input` で、ランダムなパターンと入力を作る。
+
+use super::{assertion::Assertion, evaluator::CaptureSpans, parser::AST, reverse};
+
+/// xorshift の乱数。テストを再現できるよう種から決まる
+pub(crate) struct Xor64 {
+    x: u64,
+}
+
+impl Xor64 {
+    pub fn new(seed: u64) -> Xor64 {
+        Xor64 {
+            x: seed ^ 88_172_645_463_325_252,
+        }
+    }
+
+    pub fn next(&mut self) -> u64 {
+        let x = self.x;
+        let x = x ^ (x << 13);
+        let x = x ^ (x >> 7);
+        let x = x ^ (x << 17);
+        self.x = x;
+        x
+    }
+
+    /// 0 以上 n 未満
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+/// ランダムな入力に使う文字。\d と \b の両側が出るように数字と空白も入れる
+const ALPHABET: [char; 4] = ['a', 'b', '1', ' '];
+
+/// 深さ `depth` までのランダムな AST。キャプチャは含むが、全体をグループ 0 で囲むのは呼び出し側
+pub(crate) fn random_ast(rng: &mut Xor64, depth: usize) -> AST {
+    if depth == 0 || rng.below(3) == 0 {
+        return random_leaf(rng);
+    }
+    let sub = |rng: &mut Xor64| Box::new(random_ast(rng, depth - 1));
+    match rng.below(10) {
+        0 | 1 => {
+            let n = 2 + rng.below(2);
+            AST::Seq((0..n).map(|_| random_ast(rng, depth - 1)).collect())
+        }
+        2 => AST::Or(sub(rng), sub(rng)),
+        3 => AST::Star(sub(rng)),
+        4 => AST::Plus(sub(rng)),
+        5 => AST::Question(sub(rng)),
+        6 => {
+            let min = rng.below(3);
+            let max = [None, Some(min), Some(min + 1), Some(min + 2)][rng.below(4)];
+            AST::Counter(sub(rng), (min, max))
+        }
+        7 => AST::Chapcher(sub(rng)),
+        8 => {
+            // 後読みの中身は長さに上限があり、逆向きにできるものだけ
+            let e = sub(rng);
+            if reverse::max_len(&e).is_none() || reverse::reverse(&e).is_err() {
+                random_leaf(rng)
+            } else if rng.below(2) == 0 {
+                AST::LookBehind(e)
+            } else {
+                AST::NotLookBehind(e)
+            }
+        }
+        _ => AST::Atomic(sub(rng)),
+    }
+}
+
+fn random_leaf(rng: &mut Xor64) -> AST {
+    match rng.below(12) {
+        0..=3 => AST::Char(ALPHABET[rng.below(2)]),
+        4 => AST::AnyChar,
+        5 => AST::MatchChars(vec!['a', '1']),
+        6 => AST::UnmatchChars(vec!['a']),
+        7 => AST::AnyNumber,
+        8 => AST::NotNumber,
+        9 if rng.below(2) == 0 => AST::Caret,
+        9 => AST::Doller,
+        10 => {
+            let assertions = [
+                Assertion::StartText,
+                Assertion::EndText,
+                Assertion::EndTextNewline,
+                Assertion::WordBoundary,
+                Assertion::NotWordBoundary,
+            ];
+            AST::Assertion(assertions[rng.below(assertions.len())])
+        }
+        _ => AST::Seq(vec![]),
+    }
+}
+
+/// 長さ `max_len` までのランダムな入力
+pub(crate) fn random_input(rng: &mut Xor64, max_len: usize) -> Vec<char> {
+    let len = rng.below(max_len + 1);
+    (0..len).map(|_| ALPHABET[rng.below(ALPHABET.len())]).collect()
+}
+
+/// 参照実装の結果。キャプチャの番号と同じ並びで、通らなかったグループは None
+type Caps = Vec<Option<(usize, usize)>>;
+
+/// AST を直接たどる参照実装
+pub(crate) struct Reference<'a> {
+    line: &'a [char],
+}
+
+impl<'a> Reference<'a> {
+    pub fn new(line: &'a [char]) -> Self {
+        Reference { line }
+    }
+
+    /// `start` 文字目から `ast` にマッチするか。マッチしたら各グループの位置も返す
+    pub fn captures(&self, ast: &AST, start: usize) -> Option<CaptureSpans> {
+        let caps = vec![None; count_groups(ast)];
+        self.ends(ast, 0, start, caps).into_iter().next().map(|(_, caps)| caps)
+    }
+
+    /// `sp` から `ast` を読み終えられる位置と、そのときのキャプチャを優先度順に返す。
+    /// `group` は `ast` の中で最初に現れるグループの番号
+    fn ends(&self, ast: &AST, group: usize, sp: usize, caps: Caps) -> Vec<(usize, Caps)> {
+        let when = |cond: bool, caps: Caps| if cond { vec![(sp, caps)] } else { vec![] };
+        let mut out = match ast {
+            AST::Char(_)
+            | AST::UnmatchChars(_)
+            | AST::AnyNumber
+            | AST::NotNumber
+            | AST::MatchChars(_)
+            | AST::Property(_)
+            | AST::NotProperty(_)
+            | AST::AnyChar => {
+                if self.line.get(sp).is_some_and(|c| accepts(ast, *c)) {
+                    vec![(sp + 1, caps)]
+                } else {
+                    vec![]
+                }
+            }
+            AST::Caret => when(sp == 0, caps),
+            AST::Doller => when(sp == self.line.len(), caps),
+            AST::Assertion(a) => when(a.is_match(self.line, sp), caps),
+            AST::Seq(v) => self.seq(v, group, sp, caps),
+            AST::Or(e1, e2) => {
+                let mut out = self.ends(e1, group, sp, caps.clone());
+                out.extend(self.ends(e2, group + count_groups(e1), sp, caps));
+                out
+            }
+            AST::Question(e) => {
+                let mut out = self.ends(e, group, sp, caps.clone());
+                out.push((sp, caps));
+                out
+            }
+            AST::Star(e) => self.star(e, group, sp, caps),
+            AST::Plus(e) => {
+                let mut out = Vec::new();
+                for (end, caps) in self.ends(e, group, sp, caps) {
+                    if end == sp {
+                        out.push((end, caps));
+                    } else {
+                        out.extend(self.star(e, group, end, caps));
+                    }
+                }
+                out
+            }
+            AST::Counter(e, (min, max)) => {
+                let optional = max.map(|max| max.saturating_sub(*min));
+                self.repeat(e, group, *min, optional, sp, caps)
+            }
+            AST::Chapcher(e) | AST::NamedChapcher(_, e) => self
+                .ends(e, group + 1, sp, caps)
+                .into_iter()
+                .map(|(end, mut caps)| {
+                    caps[group] = Some((sp, end));
+                    (end, caps)
+                })
+                .collect(),
+            AST::Atomic(e) => self.ends(e, group, sp, caps).into_iter().take(1).collect(),
+            AST::LookBehind(e) => when(self.looks_behind(e, group, sp, &caps), caps),
+            AST::NotLookBehind(e) => when(!self.looks_behind(e, group, sp, &caps), caps),
+        };
+        dedup_ends(&mut out);
+        out
+    }
+
+    fn seq(&self, v: &[AST], group: usize, sp: usize, caps: Caps) -> Vec<(usize, Caps)> {
+        let (first, rest) = if let Some(split) = v.split_first() {
+            split
+        } else {
+            return vec![(sp, caps)];
+        };
+        let mut out = Vec::new();
+        for (end, caps) in self.ends(first, group, sp, caps) {
+            out.extend(self.seq(rest, group + count_groups(first), end, caps));
+        }
+        out
+    }
+
+    /// `sp` の直前に `e` があるか。中のグループは記録しない
+    fn looks_behind(&self, e: &AST, group: usize, sp: usize, caps: &Caps) -> bool {
+        (0..=sp).any(|start| self.ends(e, group, start, caps.clone()).iter().any(|(end, _)| *end == sp))
+    }
+
+    /// 何も読まなかった回は失敗する繰り返し
+    fn star(&self, e: &AST, group: usize, sp: usize, caps: Caps) -> Vec<(usize, Caps)> {
+        let mut out = Vec::new();
+        for (end, caps) in self.ends(e, group, sp, caps.clone()) {
+            if end != sp {
+                out.extend(self.star(e, group, end, caps));
+            }
+        }
+        out.push((sp, caps));
+        out
+    }
+
+    /// e を `required` 回読み、続けて `optional` 回まで読む。`optional` が None なら何回でも
+    fn repeat(
+        &self,
+        e: &AST,
+        group: usize,
+        required: usize,
+        optional: Option<usize>,
+        sp: usize,
+        caps: Caps,
+    ) -> Vec<(usize, Caps)> {
+        if required > 0 {
+            let mut out = Vec::new();
+            for (end, caps) in self.ends(e, group, sp, caps) {
+                out.extend(self.repeat(e, group, required - 1, optional, end, caps));
+            }
+            return out;
+        }
+        match optional {
+            None => self.star(e, group, sp, caps),
+            Some(0) => vec![(sp, caps)],
+            Some(n) => {
+                let mut out = Vec::new();
+                for (end, caps) in self.ends(e, group, sp, caps.clone()) {
+                    out.extend(self.repeat(e, group, 0, Some(n - 1), end, caps));
+                }
+                out.push((sp, caps));
+                out
+            }
+        }
+    }
+}
+
+/// 同じ位置で終わるものは先の (優先度の高い) ものだけ残す
+fn dedup_ends(ends: &mut Vec<(usize, Caps)>) {
+    let mut seen = Vec::new();
+    ends.retain(|(end, _)| {
+        if seen.contains(end) {
+            false
+        } else {
+            seen.push(*end);
+            true
+        }
+    });
+}
+
+/// 1 文字を読むノードが文字 `c` を受理するか
+fn accepts(ast: &AST, c: char) -> bool {
+    match ast {
+        AST::Char(i) => *i == c,
+        AST::UnmatchChars(i) => !i.contains(&c),
+        AST::AnyNumber => c.is_ascii_digit(),
+        AST::NotNumber => !c.is_ascii_digit(),
+        AST::MatchChars(i) => i.contains(&c),
+        AST::Property(p) => p.contains(c),
+        AST::NotProperty(p) => !p.contains(c),
+        AST::AnyChar => true,
+        _ => false,
+    }
+}
+
+fn count_groups(ast: &AST) -> usize {
+    match ast {
+        AST::Chapcher(e) | AST::NamedChapcher(_, e) => 1 + count_groups(e),
+        AST::Plus(e)
+        | AST::Star(e)
+        | AST::Question(e)
+        | AST::Counter(e, _)
+        | AST::Atomic(e)
+        | AST::LookBehind(e)
+        | AST::NotLookBehind(e) => count_groups(e),
+        AST::Or(e1, e2) => count_groups(e1) + count_groups(e2),
+        AST::Seq(v) => v.iter().map(count_groups).sum(),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{random_ast, random_input, Reference, Xor64};
+    use crate::engine::{
+        codegen::{get_code, get_reverse_code},
+        evaluator::{eval_captures_with, leftmost_start, EvalLimits, Scratch, BACKENDS},
+        parser::{parse, AST},
+        printer::to_pattern,
+    };
+
+    fn reference(expr: &str, line: &str) -> Option<Vec<Option<(usize, usize)>>> {
+        let ast = AST::Chapcher(Box::new(parse(expr).unwrap()));
+        let line = line.chars().collect::<Vec<char>>();
+        Reference::new(&line).captures(&ast, 0)
+    }
+
+    #[test]
+    fn test_reference() {
+        assert_eq!(reference("a(b|c)*", "abcb"), Some(vec![Some((0, 4)), Some((3, 4))]));
+        assert_eq!(reference("a|ab", "ab"), Some(vec![Some((0, 1))]));
+        assert_eq!(reference("(a?)*$", "aa"), Some(vec![Some((0, 2)), Some((1, 2))]));
+        assert_eq!(reference("(?>a*)a", "aa"), None);
+        assert_eq!(reference("(x)?a", "a"), Some(vec![Some((0, 1)), None]));
+        assert_eq!(reference("a{2,3}", "aaaa"), Some(vec![Some((0, 3))]));
+        // 1 回目が何も読まない e+ はそこで終わるが、e{1,} は続ける
+        assert_eq!(reference("(?:b?|a)+", "a"), Some(vec![Some((0, 0))]));
+        assert_eq!(reference("(?:b?|a){1,}", "a"), Some(vec![Some((0, 1))]));
+        // 後読みの中のグループは記録しないが、番号は使う
+        assert_eq!(reference(".(?<=(a))(b)", "ab"), Some(vec![Some((0, 2)), None, Some((1, 2))]));
+        assert_eq!(reference(".(?<!a)", "a"), None);
+    }
+
+    #[test]
+    fn test_backends_agree() {
+        let mut rng = Xor64::new(2024);
+        let limits = EvalLimits::default();
+        // 作業領域を使い回しても前の評価の結果が残らないことも確かめる
+        let mut scratch = Scratch::default();
+        for _ in 0..3000 {
+            let ast = AST::Chapcher(Box::new(random_ast(&mut rng, 4)));
+            let code = get_code(&ast).unwrap();
+            for _ in 0..4 {
+                let line = random_input(&mut rng, 6);
+                let reference = Reference::new(&line);
+                for start in 0..=line.len() {
+                    let expected = reference.captures(&ast, start);
+                    for backend in BACKENDS.iter().filter(|b| (b.supports)(&code, &line)) {
+                        let mut captures = Vec::new();
+                        let is_match = (backend.eval)(&code, &line, start, &limits, &mut scratch, &mut captures).unwrap();
+                        let actual = is_match.then(|| {
+                            // e{0} の中のグループはプログラムに現れないので、足りない分を埋める
+                            captures.resize(expected.as_ref().map_or(0, |e| e.len()), None);
+                            captures
+                        });
+                        assert_eq!(
+                            actual,
+                            expected,
+                            "backend = {}, pattern = {}, line = {:?}, start = {start}",
+                            backend.name,
+                            to_pattern(&ast),
+                            line.iter().collect::<String>()
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_atomic_agrees() {
+        // ランダムなパターンではまず出ない、アトミックグループの外の分岐でグループの中の状態に
+        // 別の道筋から戻ってくる形。評価器が状態を覚えて刈ると結果が変わる
+        let patterns = [
+            "^x?(?>x*a|xxab)$",
+            "x?(?>x*a|xxab)b?$",
+            "(x|xx)?(?>x*(a|b)|xxab)(b|$)",
+            "^(?:x|(?>x+a|xab))*$",
+            "(?>(?>x*)a|x*ab)+b",
+        ];
+        let limits = EvalLimits::default();
+        let mut scratch = Scratch::default();
+        // x, a, b からなる 5 文字以下の入力すべて
+        let mut lines: Vec<Vec<char>> = vec![vec![]];
+        let mut i = 0;
+        while i < lines.len() {
+            if lines[i].len() < 5 {
+                for c in ['x', 'a', 'b'] {
+                    let mut line = lines[i].clone();
+                    line.push(c);
+                    lines.push(line);
+                }
+            }
+            i += 1;
+        }
+        for pattern in patterns {
+            let ast = AST::Chapcher(Box::new(parse(pattern).unwrap()));
+            let code = get_code(&ast).unwrap();
+            for line in &lines {
+                let reference = Reference::new(line);
+                for start in 0..=line.len() {
+                    let expected = reference.captures(&ast, start).is_some();
+                    for backend in BACKENDS.iter().filter(|b| (b.supports)(&code, line)) {
+                        let is_match = (backend.eval)(&code, line, start, &limits, &mut scratch, &mut vec![]).unwrap();
+                        assert_eq!(
+                            is_match,
+                            expected,
+                            "backend = {}, pattern = {pattern}, line = {:?}, start = {start}",
+                            backend.name,
+                            line.iter().collect::<String>()
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_leftmost_start() {
+        let mut rng = Xor64::new(2025);
+        let limits = EvalLimits::default();
+        let mut scratch = Scratch::default();
+        let mut captures = Vec::new();
+        for _ in 0..3000 {
+            let ast = AST::Chapcher(Box::new(random_ast(&mut rng, 4)));
+            let code = get_code(&ast).unwrap();
+            let reverse = if let Ok(reverse) = get_reverse_code(&ast, &Default::default()) {
+                reverse
+            } else {
+                continue;
+            };
+            for _ in 0..4 {
+                let line = random_input(&mut rng, 8);
+                let reversed = line.iter().rev().copied().collect::<Vec<char>>();
+                for from in 0..=line.len() {
+                    // 開始位置を左から順に試したときの、最初にマッチする位置
+                    let mut expected = None;
+                    for start in from..=line.len() {
+                        if eval_captures_with(&code, &line, start, false, &limits, &mut scratch, &mut captures).unwrap() {
+                            expected = Some(start);
+                            break;
+                        }
+                    }
+                    let actual = leftmost_start(&code, &reverse, &line, &reversed, from, &mut scratch).unwrap();
+                    assert_eq!(
+                        actual,
+                        expected,
+                        "pattern = {}, line = {:?}, from = {from}",
+                        to_pattern(&ast),
+                        line.iter().collect::<String>()
+                    );
+                }
+            }
+        }
+    }
+}
@@ -1,9 +1,15 @@
+use super::{
+    assertion::Assertion,
+    unicode::{self, Property},
+};
 use std::{
     error::Error,
     fmt::{self, Display},
+    iter::Peekable,
     mem::take,
 };
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, PartialEq)]
 pub enum AST {
     Char(char),
@@ -19,6 +25,18 @@ pub enum AST {
     AnyNumber,
     NotNumber,
     Chapcher(Box<AST>),
+    /// `(?<name>...)` 名前の付いたグループ。番号も普通のグループと同じように振る
+    NamedChapcher(String, Box<AST>),
+    MatchChars(Vec<char>),
+    Property(Property),
+    NotProperty(Property),
+    AnyChar,
+    Atomic(Box<AST>),
+    Assertion(Assertion),
+    /// `(?<=...)` 直前に中身がある位置にだけマッチする。中身の長さには上限が要る
+    LookBehind(Box<AST>),
+    /// `(?<!...)` 直前に中身がない位置にだけマッチする
+    NotLookBehind(Box<AST>),
 }
 
 #[derive(Debug)]
@@ -31,6 +49,13 @@ pub enum ParseError {
     NoPrev(usize),
     NoRightParen,
     Empty,
+    InvalidProperty(usize),
+    UnknownProperty(usize, String),
+    InvalidGroup(usize),
+    InvalidGroupName(usize),
+    DuplicateGroupName(usize, String),
+    InvalidHex(usize),
+    InvalidCodePoint(usize, String),
 }
 
 impl Display for ParseError {
@@ -60,17 +85,82 @@ impl Display for ParseError {
             ParseError::InvalidRightBracket(pos) => {
                 write!(f, "ParseError: invalid bracket: pos = {pos}")
             }
+            ParseError::InvalidProperty(pos) => {
+                write!(f, "ParseError: invalid property: pos = {pos}")
+            }
+            ParseError::UnknownProperty(pos, name) => {
+                write!(f, "ParseError: unknown property: pos = {pos}, name = {name}")
+            }
+            ParseError::InvalidGroup(pos) => {
+                write!(f, "ParseError: invalid group: pos = {pos}")
+            }
+            ParseError::InvalidGroupName(pos) => {
+                write!(f, "ParseError: invalid group name: pos = {pos}")
+            }
+            ParseError::DuplicateGroupName(pos, name) => {
+                write!(f, "ParseError: duplicate group name: pos = {pos}, name = {name}")
+            }
+            ParseError::InvalidHex(pos) => {
+                write!(f, "ParseError: invalid hex escape: pos = {pos}")
+            }
+            ParseError::InvalidCodePoint(pos, hex) => {
+                write!(f, "ParseError: invalid code point: pos = {pos}, value = {hex}")
+            }
         }
     }
 }
 
 impl Error for ParseError {}
 
-fn parse_escape(pos: usize, c: char) -> Result<AST, ParseError> {
+impl ParseError {
+    /// エラーになった文字の位置 (何文字目か)。パターン全体に関わるエラーでは None
+    pub fn position(&self) -> Option<usize> {
+        match self {
+            ParseError::InvalidEscape(pos, _)
+            | ParseError::InvalidRightParen(pos)
+            | ParseError::InvalidRightBracket(pos)
+            | ParseError::NoPrev(pos)
+            | ParseError::InvalidProperty(pos)
+            | ParseError::UnknownProperty(pos, _)
+            | ParseError::InvalidGroup(pos)
+            | ParseError::InvalidGroupName(pos)
+            | ParseError::DuplicateGroupName(pos, _)
+            | ParseError::InvalidHex(pos)
+            | ParseError::InvalidCodePoint(pos, _) => Some(*pos),
+            ParseError::InvalidBrace | ParseError::InvalidCaret | ParseError::NoRightParen | ParseError::Empty => None,
+        }
+    }
+}
+
+/// `\` を付けるとその文字そのものになるメタ文字
+pub(super) const META_CHARS: &[char] = &['\\', '(', ')', '|', '+', '*', '?', '.', '^', '$', '{', '}', '[', ']'];
+
+fn parse_escape<I>(pos: usize, c: char, chars: &mut Peekable<I>) -> Result<AST, ParseError>
+where
+    I: Iterator<Item = (usize, char)>,
+{
     match c {
-        '\\' | '(' | ')' | '|' | '+' | '*' | '?' | '.' | '^' | '$' => Ok(AST::Char(c)),
+        c if META_CHARS.contains(&c) => Ok(AST::Char(c)),
         'd' => Ok(AST::AnyNumber),
         'D' => Ok(AST::NotNumber),
+        'n' => Ok(AST::Char('\n')),
+        't' => Ok(AST::Char('\t')),
+        'r' => Ok(AST::Char('\r')),
+        '0' => Ok(AST::Char('\0')),
+        'x' => Ok(AST::Char(parse_hex(pos, false, chars)?)),
+        'u' => Ok(AST::Char(parse_hex(pos, true, chars)?)),
+        // \cX は Ctrl + X の制御文字
+        'c' => match chars.next() {
+            Some((_, x)) if x.is_ascii_alphabetic() => Ok(AST::Char(((x.to_ascii_uppercase() as u8) ^ 0x40) as char)),
+            _ => Err(ParseError::InvalidEscape(pos, c)),
+        },
+        'A' => Ok(AST::Assertion(Assertion::StartText)),
+        'z' => Ok(AST::Assertion(Assertion::EndText)),
+        'Z' => Ok(AST::Assertion(Assertion::EndTextNewline)),
+        'b' => Ok(AST::Assertion(Assertion::WordBoundary)),
+        'B' => Ok(AST::Assertion(Assertion::NotWordBoundary)),
+        'p' => Ok(AST::Property(parse_property(pos, chars)?)),
+        'P' => Ok(AST::NotProperty(parse_property(pos, chars)?)),
         _ => {
             let err = ParseError::InvalidEscape(pos, c);
             Err(err)
@@ -78,6 +168,83 @@ fn parse_escape(pos: usize, c: char) -> Result<AST, ParseError> {
     }
 }
 
+/// `\xHH` の 2 桁か、`\x{...}` `\u{...}` の括弧の中の 16 進数を読んで文字にする。
+/// `braced_only` なら括弧の形だけを受け付ける
+fn parse_hex<I>(pos: usize, braced_only: bool, chars: &mut Peekable<I>) -> Result<char, ParseError>
+where
+    I: Iterator<Item = (usize, char)>,
+{
+    let mut digits = String::new();
+    match chars.peek() {
+        Some((_, '{')) => {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some((_, '}')) => break,
+                    Some((_, c)) => digits.push(c),
+                    None => return Err(ParseError::InvalidHex(pos)),
+                }
+            }
+        }
+        _ if braced_only => return Err(ParseError::InvalidHex(pos)),
+        _ => {
+            for _ in 0..2 {
+                match chars.next() {
+                    Some((_, c)) => digits.push(c),
+                    None => return Err(ParseError::InvalidHex(pos)),
+                }
+            }
+        }
+    }
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(ParseError::InvalidHex(pos));
+    }
+    // 大きすぎる値やサロゲートは文字にならない
+    u32::from_str_radix(&digits, 16)
+        .ok()
+        .and_then(char::from_u32)
+        .ok_or(ParseError::InvalidCodePoint(pos, digits))
+}
+
+/// `\p{Name}` か `\pL` の名前を読む
+fn parse_property<I>(pos: usize, chars: &mut Peekable<I>) -> Result<Property, ParseError>
+where
+    I: Iterator<Item = (usize, char)>,
+{
+    let name = match chars.next() {
+        Some((_, '{')) => {
+            let mut name = String::new();
+            loop {
+                match chars.next() {
+                    Some((_, '}')) => break,
+                    Some((_, c)) => name.push(c),
+                    None => return Err(ParseError::InvalidProperty(pos)),
+                }
+            }
+            name
+        }
+        Some((_, c)) if c.is_ascii_alphabetic() => c.to_string(),
+        _ => return Err(ParseError::InvalidProperty(pos)),
+    };
+    unicode::lookup(&name).ok_or(ParseError::UnknownProperty(pos, name))
+}
+
+/// 大文字小文字を区別しないときは、文字をその大文字小文字のどれかにマッチするものに置き換える
+fn fold_case(ast: AST, case_insensitive: bool) -> AST {
+    match ast {
+        AST::Char(c) if case_insensitive => {
+            let chars = unicode::fold_case(c);
+            if chars.len() > 1 {
+                AST::MatchChars(chars)
+            } else {
+                AST::Char(c)
+            }
+        }
+        ast => ast,
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
 enum PSQ {
     Plus,
     Star,
@@ -106,6 +273,37 @@ fn parse_plus_star_question(
     }
 }
 
+/// 量指定子の直後に + があれば、その繰り返しをアトミックグループで包んで強欲にする
+fn parse_possessive<I>(seq: &mut Vec<AST>, chars: &mut Peekable<I>)
+where
+    I: Iterator<Item = (usize, char)>,
+{
+    if let Some((_, '+')) = chars.peek() {
+        chars.next();
+        if let Some(prev) = seq.pop() {
+            seq.push(AST::Atomic(Box::new(prev)));
+        }
+    }
+}
+
+/// `(?<` の後ろのグループ名を `>` まで読む。名前は英字か `_` で始まり、英数字と `_` からなる
+fn parse_group_name<I>(pos: usize, chars: &mut Peekable<I>) -> Result<String, ParseError>
+where
+    I: Iterator<Item = (usize, char)>,
+{
+    let mut name = String::new();
+    while let Some((_, c)) = chars.next_if(|(_, c)| c.is_alphanumeric() || *c == '_') {
+        name.push(c);
+    }
+    if chars.next_if(|(_, c)| *c == '>').is_none() {
+        return Err(ParseError::InvalidGroup(pos));
+    }
+    if !name.starts_with(|c: char| c.is_alphabetic() || c == '_') {
+        return Err(ParseError::InvalidGroupName(pos));
+    }
+    Ok(name)
+}
+
 fn fold_or(mut seq_or: Vec<AST>) -> Option<AST> {
     if seq_or.len() > 1 {
         let mut ast = seq_or.pop().unwrap();
@@ -119,15 +317,18 @@ fn fold_or(mut seq_or: Vec<AST>) -> Option<AST> {
     }
 }
 
-fn unmatch_charctors(seq_or: &mut Vec<AST>, seq: &Vec<AST>) -> Result<(), ParseError> {
+fn unmatch_charctors(seq_or: &mut Vec<AST>, seq: &[AST]) -> Result<(), ParseError> {
     let chars = seq
         .iter()
         .map(|ast| match ast {
-            AST::Char(c) => Ok(c.to_owned()),
+            AST::Char(c) => Ok(vec![c.to_owned()]),
+            AST::MatchChars(c) => Ok(c.to_owned()),
+            // [^.] の . は任意の 1 文字ではなくドットそのもの
+            AST::AnyChar => Ok(vec!['.']),
             _ => Err(ParseError::InvalidCaret),
         })
-        .collect::<Result<Vec<char>, _>>()?;
-    seq_or.push(AST::UnmatchChars(chars));
+        .collect::<Result<Vec<Vec<char>>, _>>()?;
+    seq_or.push(AST::UnmatchChars(chars.concat()));
     Ok(())
 }
 
@@ -138,6 +339,17 @@ pub fn parse(expr: &str) -> Result<AST, ParseError> {
         Brace,
     }
 
+    // スタックに積んだグループがどの括弧で始まったか
+    enum Group {
+        Bracket,
+        Capture,
+        Named(String),
+        NonCapture,
+        Atomic,
+        LookBehind,
+        NotLookBehind,
+    }
+
     let mut seq = Vec::new();
     let mut seq_or = Vec::new();
     let mut stack = Vec::new();
@@ -147,13 +359,16 @@ pub fn parse(expr: &str) -> Result<AST, ParseError> {
     let mut expect_second_count = false;
     let mut expect_grouping = false;
     let mut expect_except_charactors = false;
+    let mut case_insensitive = false;
+    let mut names = Vec::new();
 
-    for (i, c) in expr.chars().enumerate() {
+    let mut chars = expr.chars().enumerate().peekable();
+    while let Some((i, c)) = chars.next() {
         match &state {
             ParseState::Char => match c {
                 '^' => {
                     if expect_grouping {
-                        if seq.len() == 0 {
+                        if seq.is_empty() {
                             expect_except_charactors = true;
                         } else {
                             return Err(ParseError::InvalidCaret);
@@ -163,17 +378,27 @@ pub fn parse(expr: &str) -> Result<AST, ParseError> {
                     }
                 }
                 '$' => seq.push(AST::Doller),
-                '+' => parse_plus_star_question(&mut seq, PSQ::Plus, i)?,
-                '*' => parse_plus_star_question(&mut seq, PSQ::Star, i)?,
-                '?' => parse_plus_star_question(&mut seq, PSQ::Question, i)?,
+                '.' => seq.push(AST::AnyChar),
+                '+' => {
+                    parse_plus_star_question(&mut seq, PSQ::Plus, i)?;
+                    parse_possessive(&mut seq, &mut chars);
+                }
+                '*' => {
+                    parse_plus_star_question(&mut seq, PSQ::Star, i)?;
+                    parse_possessive(&mut seq, &mut chars);
+                }
+                '?' => {
+                    parse_plus_star_question(&mut seq, PSQ::Question, i)?;
+                    parse_possessive(&mut seq, &mut chars);
+                }
                 '[' => {
                     let prev = take(&mut seq);
                     let prev_or = take(&mut seq_or);
                     expect_grouping = true;
-                    stack.push((prev, prev_or))
+                    stack.push((prev, prev_or, case_insensitive, Group::Bracket))
                 }
                 ']' => {
-                    if let Some((mut prev, prev_or)) = stack.pop() {
+                    if let Some((mut prev, prev_or, prev_case_insensitive, Group::Bracket)) = stack.pop() {
                         if !seq.is_empty() {
                             if expect_except_charactors {
                                 unmatch_charctors(&mut seq_or, &seq)?;
@@ -187,26 +412,78 @@ pub fn parse(expr: &str) -> Result<AST, ParseError> {
                         }
                         seq = prev;
                         seq_or = prev_or;
+                        case_insensitive = prev_case_insensitive;
                         expect_grouping = false;
                     } else {
                         return Err(ParseError::InvalidRightBracket(i));
                     }
                 }
                 '(' => {
+                    let group = if let Some((_, '?')) = chars.peek() {
+                        chars.next();
+                        match chars.next() {
+                            // (?i) は、それを含むグループの終わりまで大文字小文字を区別しない
+                            Some((_, 'i')) => {
+                                if let Some((_, ')')) = chars.next() {
+                                    case_insensitive = true;
+                                    continue;
+                                }
+                                return Err(ParseError::InvalidGroup(i));
+                            }
+                            Some((_, ':')) => Group::NonCapture,
+                            Some((_, '>')) => Group::Atomic,
+                            // (?<= と (?<! は名前ではなく後読み
+                            Some((_, '<')) if chars.next_if(|(_, c)| *c == '=').is_some() => Group::LookBehind,
+                            Some((_, '<')) if chars.next_if(|(_, c)| *c == '!').is_some() => Group::NotLookBehind,
+                            Some((_, '<')) => Group::Named(parse_group_name(i, &mut chars)?),
+                            Some((_, 'P')) => {
+                                if chars.next_if(|(_, c)| *c == '<').is_none() {
+                                    return Err(ParseError::InvalidGroup(i));
+                                }
+                                Group::Named(parse_group_name(i, &mut chars)?)
+                            }
+                            _ => return Err(ParseError::InvalidGroup(i)),
+                        }
+                    } else {
+                        Group::Capture
+                    };
+                    if let Group::Named(name) = &group {
+                        if names.contains(name) {
+                            return Err(ParseError::DuplicateGroupName(i, name.clone()));
+                        }
+                        names.push(name.clone());
+                    }
                     let prev = take(&mut seq);
                     let prev_or = take(&mut seq_or);
-                    stack.push((prev, prev_or))
+                    stack.push((prev, prev_or, case_insensitive, group))
                 }
                 ')' => {
-                    if let Some((mut prev, prev_or)) = stack.pop() {
+                    if let Some((mut prev, prev_or, prev_case_insensitive, group)) = stack.pop() {
                         if !seq.is_empty() {
                             seq_or.push(AST::Seq(seq));
                         }
-                        if let Some(ast) = fold_or(seq_or) {
-                            prev.push(AST::Chapcher(Box::new(ast)))
+                        match (group, fold_or(seq_or)) {
+                            (Group::Bracket, _) => return Err(ParseError::InvalidRightParen(i)),
+                            // 空のグループも番号を 1 つ使う
+                            (Group::Capture, ast) => {
+                                prev.push(AST::Chapcher(Box::new(ast.unwrap_or(AST::Seq(vec![])))))
+                            }
+                            (Group::Named(name), ast) => {
+                                prev.push(AST::NamedChapcher(name, Box::new(ast.unwrap_or(AST::Seq(vec![])))))
+                            }
+                            (Group::NonCapture, Some(ast)) => prev.push(ast),
+                            (Group::Atomic, Some(ast)) => prev.push(AST::Atomic(Box::new(ast))),
+                            (Group::LookBehind, ast) => {
+                                prev.push(AST::LookBehind(Box::new(ast.unwrap_or(AST::Seq(vec![])))))
+                            }
+                            (Group::NotLookBehind, ast) => {
+                                prev.push(AST::NotLookBehind(Box::new(ast.unwrap_or(AST::Seq(vec![])))))
+                            }
+                            (_, None) => (),
                         }
                         seq = prev;
                         seq_or = prev_or;
+                        case_insensitive = prev_case_insensitive;
                     } else {
                         return Err(ParseError::InvalidRightParen(i))
                     }
@@ -226,21 +503,21 @@ pub fn parse(expr: &str) -> Result<AST, ParseError> {
                 }
                 '\\' => state = ParseState::Escape,
                 '{' => state = ParseState::Brace,
-                _ => seq.push(AST::Char(c)),
+                _ => seq.push(fold_case(AST::Char(c), case_insensitive)),
             },
             ParseState::Escape => {
-                let ast = parse_escape(i, c)?;
-                seq.push(ast);
+                let ast = parse_escape(i, c, &mut chars)?;
+                seq.push(fold_case(ast, case_insensitive));
                 state = ParseState::Char;
             }
             ParseState::Brace => {
-                if let Some(_) = c.to_digit(10) {
+                if c.is_ascii_digit() {
                     counter.push(c);
                 } else {
                     if c == ' ' {
                         // nop
                     } else if c == ',' && !expect_second_count {
-                        let count = counter.parse::<usize>().unwrap();
+                        let count = counter.parse::<usize>().or(Err(ParseError::InvalidBrace))?;
                         counter_pair.0 = count;
                         counter = "".to_string();
                         expect_second_count = true;
@@ -252,15 +529,19 @@ pub fn parse(expr: &str) -> Result<AST, ParseError> {
                                 Err(_) => return Err(ParseError::InvalidBrace),
                             }
                         } else {
-                            match counter_result {
-                                Ok(c) => counter_pair.1 = Some(c),
-                                Err(_) => (),
+                            if let Ok(c) = counter_result {
+                                if c < counter_pair.0 {
+                                    return Err(ParseError::InvalidBrace);
+                                }
+                                counter_pair.1 = Some(c);
                             }
                         }
 
                         parse_plus_star_question(&mut seq, PSQ::Counter(counter_pair), i)?;
+                        parse_possessive(&mut seq, &mut chars);
                         counter = "".to_string();
                         counter_pair = (0, None);
+                        expect_second_count = false;
                         state = ParseState::Char;
                     } else {
                         return Err(ParseError::InvalidBrace);
@@ -285,9 +566,35 @@ pub fn parse(expr: &str) -> Result<AST, ParseError> {
     }
 }
 
+/// AST を再帰せずに捨てる。パースはスタックを使わないので、とても深い AST もできる。
+/// そのまま drop すると再帰でスタックを使い切る
+pub fn dispose(ast: AST) {
+    let mut stack = vec![ast];
+    while let Some(ast) = stack.pop() {
+        match ast {
+            AST::Plus(e)
+            | AST::Star(e)
+            | AST::Question(e)
+            | AST::Counter(e, _)
+            | AST::Chapcher(e)
+            | AST::NamedChapcher(_, e)
+            | AST::Atomic(e)
+            | AST::LookBehind(e)
+            | AST::NotLookBehind(e) => stack.push(*e),
+            AST::Or(e1, e2) => stack.extend([*e1, *e2]),
+            AST::Seq(v) => stack.extend(v),
+            _ => (),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::engine::parser::{parse, AST};
+    use crate::engine::{
+        assertion::Assertion,
+        parser::{parse, ParseError, AST},
+        unicode::lookup,
+    };
 
 
     #[test]
@@ -322,7 +629,7 @@ mod tests {
         );
             
         assert_eq!(parse("abc.e").unwrap(), 
-            AST::Seq(vec![AST::Char('a'), AST::Char('b'), AST::Char('c'), AST::Char('.'), AST::Char('e')]
+            AST::Seq(vec![AST::Char('a'), AST::Char('b'), AST::Char('c'), AST::AnyChar, AST::Char('e')]
             )
         );
             
@@ -408,4 +715,155 @@ mod tests {
             )
         );
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_parse_property() {
+        let katakana = lookup("Katakana").unwrap();
+        assert_eq!(parse("\\p{Katakana}+").unwrap(),
+            AST::Seq(vec![AST::Plus(Box::new(AST::Property(katakana)))])
+        );
+        assert_eq!(parse("a\\PL").unwrap(),
+            AST::Seq(vec![AST::Char('a'), AST::NotProperty(lookup("L").unwrap())])
+        );
+        assert!(matches!(parse("\\p{Klingon}"), Err(ParseError::UnknownProperty(1, _))));
+        assert!(matches!(parse("\\p{Han"), Err(ParseError::InvalidProperty(1))));
+        assert!(matches!(parse("\\p"), Err(ParseError::InvalidProperty(1))));
+    }
+
+    #[test]
+    fn test_parse_case_insensitive() {
+        assert_eq!(parse("(?i)a1").unwrap(),
+            AST::Seq(vec![AST::MatchChars(vec!['a', 'A']), AST::Char('1')])
+        );
+        assert_eq!(parse("a((?i)b)c").unwrap(),
+            AST::Seq(vec![
+                AST::Char('a'),
+                AST::Chapcher(Box::new(AST::Seq(vec![AST::MatchChars(vec!['b', 'B'])]))),
+                AST::Char('c'),
+            ])
+        );
+        assert_eq!(parse("(?i)[^ab]").unwrap(),
+            AST::Seq(vec![AST::UnmatchChars(vec!['a', 'A', 'b', 'B'])])
+        );
+        assert!(matches!(parse("(?x)a"), Err(ParseError::InvalidGroup(0))));
+    }
+
+    #[test]
+    fn test_parse_escape() {
+        assert_eq!(parse("a\\.b").unwrap(),
+            AST::Seq(vec![AST::Char('a'), AST::Char('.'), AST::Char('b')])
+        );
+        assert_eq!(parse("\\\\\\^\\$\\{\\}\\[\\]\\(\\)\\|\\+\\*\\?").unwrap(),
+            AST::Seq("\\^${}[]()|+*?".chars().map(AST::Char).collect())
+        );
+        assert_eq!(parse("[^.]").unwrap(), AST::Seq(vec![AST::UnmatchChars(vec!['.'])]));
+        assert!(matches!(parse("\\q"), Err(ParseError::InvalidEscape(1, 'q'))));
+        assert_eq!(parse("\\A\\bx\\B\\Z\\z").unwrap(),
+            AST::Seq(vec![
+                AST::Assertion(Assertion::StartText),
+                AST::Assertion(Assertion::WordBoundary),
+                AST::Char('x'),
+                AST::Assertion(Assertion::NotWordBoundary),
+                AST::Assertion(Assertion::EndTextNewline),
+                AST::Assertion(Assertion::EndText),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_control_escape() {
+        assert_eq!(parse("\\n\\t\\r\\0").unwrap(),
+            AST::Seq(vec![AST::Char('\n'), AST::Char('\t'), AST::Char('\r'), AST::Char('\0')])
+        );
+        assert_eq!(parse("\\x41\\x{3042}\\u{1F600}\\u{0}").unwrap(),
+            AST::Seq(vec![AST::Char('A'), AST::Char('あ'), AST::Char('😀'), AST::Char('\0')])
+        );
+        assert_eq!(parse("\\cA\\cz").unwrap(),
+            AST::Seq(vec![AST::Char('\u{1}'), AST::Char('\u{1a}')])
+        );
+        // [^...] の中でも使える
+        assert_eq!(parse("[^\\n\\x20\\t]").unwrap(),
+            AST::Seq(vec![AST::UnmatchChars(vec!['\n', ' ', '\t'])])
+        );
+        assert_eq!(parse("[\\r|\\n]").unwrap(),
+            AST::Seq(vec![AST::Or(
+                Box::new(AST::Seq(vec![AST::Char('\r')])),
+                Box::new(AST::Seq(vec![AST::Char('\n')])),
+            )])
+        );
+
+        assert!(matches!(parse("a\\x4"), Err(ParseError::InvalidHex(2))));
+        assert!(matches!(parse("\\xg1"), Err(ParseError::InvalidHex(1))));
+        assert!(matches!(parse("\\x{}"), Err(ParseError::InvalidHex(1))));
+        assert!(matches!(parse("\\x{41"), Err(ParseError::InvalidHex(1))));
+        assert!(matches!(parse("\\u41"), Err(ParseError::InvalidHex(1))));
+        assert!(matches!(parse("\\u{110000}"), Err(ParseError::InvalidCodePoint(1, h)) if h == "110000"));
+        assert!(matches!(parse("\\u{D800}"), Err(ParseError::InvalidCodePoint(1, _))));
+        assert!(matches!(parse("\\x{123456789}"), Err(ParseError::InvalidCodePoint(1, _))));
+        assert!(matches!(parse("\\c1"), Err(ParseError::InvalidEscape(1, 'c'))));
+        assert!(matches!(parse("\\c"), Err(ParseError::InvalidEscape(1, 'c'))));
+    }
+
+    #[test]
+    fn test_parse_atomic() {
+        assert_eq!(parse("(?>ab|a)c").unwrap(),
+            AST::Seq(vec![
+                AST::Atomic(Box::new(AST::Or(
+                    Box::new(AST::Seq(vec![AST::Char('a'), AST::Char('b')])),
+                    Box::new(AST::Seq(vec![AST::Char('a')])),
+                ))),
+                AST::Char('c'),
+            ])
+        );
+        assert_eq!(parse("a*+b++c?+").unwrap(),
+            AST::Seq(vec![
+                AST::Atomic(Box::new(AST::Star(Box::new(AST::Char('a'))))),
+                AST::Atomic(Box::new(AST::Plus(Box::new(AST::Char('b'))))),
+                AST::Atomic(Box::new(AST::Question(Box::new(AST::Char('c'))))),
+            ])
+        );
+        assert_eq!(parse("a{2,}+").unwrap(),
+            AST::Seq(vec![AST::Atomic(Box::new(AST::Counter(Box::new(AST::Char('a')), (2, None))))])
+        );
+        assert!(matches!(parse("(?<a)"), Err(ParseError::InvalidGroup(0))));
+        assert!(matches!(parse("[ab)"), Err(ParseError::InvalidRightParen(3))));
+        assert!(matches!(parse("(ab]"), Err(ParseError::InvalidRightBracket(3))));
+    }
+
+    #[test]
+    fn test_parse_named() {
+        let named = |name: &str, c: char| AST::NamedChapcher(name.to_string(), Box::new(AST::Seq(vec![AST::Char(c)])));
+        assert_eq!(parse("(?<year>a)(?P<_m2>b)").unwrap(), AST::Seq(vec![named("year", 'a'), named("_m2", 'b')]));
+        assert_eq!(parse("(?<名前>a)").unwrap(), AST::Seq(vec![named("名前", 'a')]));
+        assert!(matches!(parse("a(?<1x>b)"), Err(ParseError::InvalidGroupName(1))));
+        assert!(matches!(parse("(?<>b)"), Err(ParseError::InvalidGroupName(0))));
+        assert!(matches!(parse("(?<a-b>c)"), Err(ParseError::InvalidGroup(0))));
+        assert!(matches!(parse("(?P=a)"), Err(ParseError::InvalidGroup(0))));
+        assert!(matches!(parse("(?<=a"), Err(ParseError::NoRightParen)));
+        assert!(matches!(
+            parse("(?<x>a)(?<x>b)"),
+            Err(ParseError::DuplicateGroupName(7, name)) if name == "x"
+        ));
+    }
+
+    #[test]
+    fn test_parse_look_behind() {
+        let seq = |c: char| Box::new(AST::Seq(vec![AST::Char(c)]));
+        assert_eq!(parse("(?<=a)b").unwrap(), AST::Seq(vec![AST::LookBehind(seq('a')), AST::Char('b')]));
+        assert_eq!(parse("(?<!a)b").unwrap(), AST::Seq(vec![AST::NotLookBehind(seq('a')), AST::Char('b')]));
+        assert_eq!(parse("(?<=)").unwrap(), AST::Seq(vec![AST::LookBehind(Box::new(AST::Seq(vec![])))]));
+        assert!(matches!(parse("(?<+a)"), Err(ParseError::InvalidGroup(0))));
+    }
+
+    #[test]
+    fn test_parse_non_capture() {
+        assert_eq!(parse("(?:ab|c)+d").unwrap(), parse("[ab|c]+d").unwrap());
+        assert_eq!(parse("(?:(a))").unwrap(),
+            AST::Seq(vec![AST::Seq(vec![AST::Chapcher(Box::new(AST::Seq(vec![AST::Char('a')])))])])
+        );
+        assert_eq!(parse("a()").unwrap(),
+            AST::Seq(vec![AST::Char('a'), AST::Chapcher(Box::new(AST::Seq(vec![])))])
+        );
+        assert!(matches!(parse("(?:ab]"), Err(ParseError::InvalidRightBracket(5))));
+    }
+}
@@ -0,0 +1,226 @@
+//! AST を正規表現の文字列に戻す。
+//!
+//! 出力を parse し直すと、元と同じ言語を表す AST になる。グループは必ず `(?:...)` で
+//! 書き、`[...]` や所有的な量指定子 (`a*+`) のような省略形は使わない。
+
+use super::{
+    parser::{AST, META_CHARS},
+    unicode,
+};
+
+pub fn to_pattern(ast: &AST) -> String {
+    let mut pattern = String::new();
+    write_ast(ast, &mut pattern);
+    pattern
+}
+
+fn write_ast(ast: &AST, out: &mut String) {
+    match ast {
+        AST::Or(e1, e2) => {
+            write_ast(e1, out);
+            out.push('|');
+            write_ast(e2, out);
+        }
+        AST::Seq(v) => {
+            for e in v {
+                // a|b は連接より結合が弱い
+                if let AST::Or(..) = e {
+                    write_group(e, out);
+                } else {
+                    write_ast(e, out);
+                }
+            }
+        }
+        AST::Plus(e) => write_repeat(e, "+", out),
+        AST::Star(e) => write_repeat(e, "*", out),
+        AST::Question(e) => write_repeat(e, "?", out),
+        AST::Counter(e, (min, max)) => {
+            let counter = match max {
+                Some(max) if max == min => format!("{{{min}}}"),
+                Some(max) => format!("{{{min},{max}}}"),
+                None => format!("{{{min},}}"),
+            };
+            write_repeat(e, &counter, out);
+        }
+        AST::Chapcher(e) => {
+            out.push('(');
+            write_ast(e, out);
+            out.push(')');
+        }
+        AST::NamedChapcher(name, e) => {
+            out.push_str("(?<");
+            out.push_str(name);
+            out.push('>');
+            write_ast(e, out);
+            out.push(')');
+        }
+        AST::Atomic(e) => {
+            out.push_str("(?>");
+            write_ast(e, out);
+            out.push(')');
+        }
+        AST::LookBehind(e) => {
+            out.push_str("(?<=");
+            write_ast(e, out);
+            out.push(')');
+        }
+        AST::NotLookBehind(e) => {
+            out.push_str("(?<!");
+            write_ast(e, out);
+            out.push(')');
+        }
+        AST::Char(c) => write_char(*c, out),
+        AST::MatchChars(chars) => match chars[..] {
+            [c] => write_char(c, out),
+            // (?i) で作られた文字の集合ならそのまま (?i) で書く
+            [c, ..] if unicode::fold_case(c) == *chars => {
+                out.push_str("(?:(?i)");
+                write_char(c, out);
+                out.push(')');
+            }
+            _ => {
+                out.push_str("(?:");
+                for (i, c) in chars.iter().enumerate() {
+                    if i > 0 {
+                        out.push('|');
+                    }
+                    write_char(*c, out);
+                }
+                out.push(')');
+            }
+        },
+        // どの文字も除かない [^] は書けないので、任意の 1 文字として書く
+        AST::UnmatchChars(chars) if chars.is_empty() => out.push('.'),
+        AST::UnmatchChars(chars) => {
+            out.push_str("[^");
+            for c in chars {
+                write_char(*c, out);
+            }
+            out.push(']');
+        }
+        AST::Property(p) => {
+            out.push_str("\\p{");
+            out.push_str(p.name);
+            out.push('}');
+        }
+        AST::NotProperty(p) => {
+            out.push_str("\\P{");
+            out.push_str(p.name);
+            out.push('}');
+        }
+        AST::AnyNumber => out.push_str("\\d"),
+        AST::NotNumber => out.push_str("\\D"),
+        AST::AnyChar => out.push('.'),
+        AST::Caret => out.push('^'),
+        AST::Doller => out.push('$'),
+        AST::Assertion(a) => out.push_str(&a.to_string()),
+    }
+}
+
+fn write_char(c: char, out: &mut String) {
+    match c {
+        '\n' => out.push_str("\\n"),
+        '\t' => out.push_str("\\t"),
+        '\r' => out.push_str("\\r"),
+        '\0' => out.push_str("\\0"),
+        // そのほかの制御文字も見えるように書く
+        c if c.is_control() => out.push_str(&format!("\\x{{{:X}}}", c as u32)),
+        c => {
+            if META_CHARS.contains(&c) {
+                out.push('\\');
+            }
+            out.push(c);
+        }
+    }
+}
+
+fn write_group(ast: &AST, out: &mut String) {
+    out.push_str("(?:");
+    write_ast(ast, out);
+    out.push(')');
+}
+
+/// 量指定子を付ける。1 文字分の式でなければ括弧で囲む
+fn write_repeat(e: &AST, quantifier: &str, out: &mut String) {
+    let is_atom = match e {
+        AST::MatchChars(chars) => chars.len() == 1,
+        AST::Char(_)
+        | AST::UnmatchChars(_)
+        | AST::AnyNumber
+        | AST::NotNumber
+        | AST::Chapcher(_)
+        | AST::NamedChapcher(..)
+        | AST::Atomic(_)
+        | AST::LookBehind(_)
+        | AST::NotLookBehind(_)
+        | AST::Property(_)
+        | AST::NotProperty(_)
+        | AST::AnyChar
+        | AST::Caret
+        | AST::Doller
+        | AST::Assertion(_) => true,
+        AST::Plus(_) | AST::Star(_) | AST::Question(_) | AST::Counter(..) | AST::Or(..) | AST::Seq(_) => false,
+    };
+    if is_atom {
+        write_ast(e, out);
+    } else {
+        write_group(e, out);
+    }
+    out.push_str(quantifier);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::to_pattern;
+    use crate::engine::{parser::parse, simplifier::simplify};
+
+    #[test]
+    fn test_to_pattern() {
+        let print = |expr: &str| to_pattern(&parse(expr).unwrap());
+        assert_eq!(print("abc|def"), "abc|def");
+        assert_eq!(print("ab[cd|ef]{2,}g{2}h"), "ab(?:cd|ef){2,}g{2}h");
+        assert_eq!(print("ab(\\d{1,3})-"), "ab(\\d{1,3})-");
+        assert_eq!(print("a*+b"), "(?>a*)b");
+        assert_eq!(print("[a+]*"), "(?:a+)*");
+        assert_eq!(print("\\.\\[\\]\\(\\)\\\\"), "\\.\\[\\]\\(\\)\\\\");
+        assert_eq!(print("[^a.\\]]"), "[^a\\.\\]]");
+        assert_eq!(print("(?i)ab"), "(?:(?i)a)(?:(?i)b)");
+        assert_eq!(print("\\p{Katakana}+\\P{L}"), "\\p{Katakana}+\\P{L}");
+        assert_eq!(print("^.$"), "^.$");
+        assert_eq!(print("a()"), "a()");
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let patterns = [
+            "abc|def",
+            "ab[cd|ef]{2,}g{2}h",
+            "ab((\\d{2})-(\\d{2}))",
+            "[a|b]|[c|d]",
+            "(?>ab|a)c",
+            "a*+b++c?+d{2,3}+",
+            "[[a*]*]+",
+            "(?i)(error) (\\p{Katakana}+)",
+            "[^cd|ef]",
+            "^(?:(a)|b)*$",
+            "\\\\\\.\\{2\\}",
+            "ab{0,}c{1,}d{0,1}e{1}",
+            "\\n\\t[^\\r|\\0]\\x{1B}\\cA\\u{3042}",
+            "\\A\\bab\\B+|c\\Z|d\\z",
+            "(?<year>\\d{4})-(?P<m>\\d+)?",
+            "(?<=a|bc)d(?<![x|y]{2})",
+        ];
+        for expr in patterns {
+            let ast = parse(expr).unwrap();
+            let pattern = to_pattern(&ast);
+            // 書き戻した文字列は、簡約すると元と同じ AST になる
+            let reparsed = parse(&pattern).unwrap();
+            assert_eq!(simplify(reparsed), simplify(parse(expr).unwrap()), "{expr} -> {pattern}");
+            // 簡約したものは書き戻しても変わらない
+            let simplified = simplify(ast);
+            let canonical = to_pattern(&simplified);
+            assert_eq!(simplify(parse(&canonical).unwrap()), simplified, "{expr} -> {canonical}");
+            assert_eq!(to_pattern(&simplify(parse(&canonical).unwrap())), canonical);
+        }
+    }
+}
@@ -0,0 +1,146 @@
+//! 右から左へ読むプログラム。
+//!
+//! `reverse` は AST を、逆順にした入力で同じ範囲にマッチする AST にする。
+//! 前向きの走査でマッチの終わりを見つけた後、逆向きのプログラムで始まりを探すのと、
+//! 後読み `(?<=...)` `(?<!...)` の中身を評価するのに使う。
+//! どちらもマッチするかだけを見るので、逆向きの AST にはグループを残さない。
+
+use super::{assertion::Assertion, codegen::CodeGenError, parser::AST, Instruction};
+use std::borrow::Cow;
+
+/// 逆順にした入力で、元の AST と同じ範囲にマッチする AST。
+/// `^` と `$`、`\A` と `\z` は入れ替わる。グループは番号を振らない `(?:...)` になる。
+/// 優先度はマッチの範囲に関係しないので、選択の順序は変えない
+pub fn reverse(ast: &AST) -> Result<AST, CodeGenError> {
+    let rev = |e: &AST| reverse(e).map(Box::new);
+    Ok(match ast {
+        AST::Char(c) => AST::Char(*c),
+        AST::UnmatchChars(c) => AST::UnmatchChars(c.clone()),
+        AST::MatchChars(c) => AST::MatchChars(c.clone()),
+        AST::AnyNumber => AST::AnyNumber,
+        AST::NotNumber => AST::NotNumber,
+        AST::Property(p) => AST::Property(*p),
+        AST::NotProperty(p) => AST::NotProperty(*p),
+        AST::AnyChar => AST::AnyChar,
+        AST::Caret => AST::Doller,
+        AST::Doller => AST::Caret,
+        AST::Assertion(Assertion::StartText) => AST::Assertion(Assertion::EndText),
+        AST::Assertion(Assertion::EndText) => AST::Assertion(Assertion::StartText),
+        // 末尾の改行の直前は、逆向きでは 1 つの位置の判定にならない
+        AST::Assertion(Assertion::EndTextNewline) => return Err(CodeGenError::NotReversible("\\Z")),
+        // \b と \B は前後の文字を入れ替えても同じ
+        AST::Assertion(a) => AST::Assertion(*a),
+        AST::Seq(v) => AST::Seq(v.iter().rev().map(reverse).collect::<Result<_, _>>()?),
+        AST::Or(e1, e2) => AST::Or(rev(e1)?, rev(e2)?),
+        AST::Plus(e) => AST::Plus(rev(e)?),
+        AST::Star(e) => AST::Star(rev(e)?),
+        AST::Question(e) => AST::Question(rev(e)?),
+        AST::Counter(e, count) => AST::Counter(rev(e)?, *count),
+        AST::Chapcher(e) | AST::NamedChapcher(_, e) => reverse(e)?,
+        // 選ばれる選択肢が読む向きで変わる
+        AST::Atomic(_) => return Err(CodeGenError::NotReversible("atomic group")),
+        // 逆向きにすると先読みになる
+        AST::LookBehind(_) | AST::NotLookBehind(_) => return Err(CodeGenError::NotReversible("lookbehind")),
+    })
+}
+
+/// AST が読む文字数の最大値。いくらでも読めるなら None
+pub fn max_len(ast: &AST) -> Option<usize> {
+    match ast {
+        AST::Char(_)
+        | AST::UnmatchChars(_)
+        | AST::MatchChars(_)
+        | AST::AnyNumber
+        | AST::NotNumber
+        | AST::Property(_)
+        | AST::NotProperty(_)
+        | AST::AnyChar => Some(1),
+        AST::Caret | AST::Doller | AST::Assertion(_) | AST::LookBehind(_) | AST::NotLookBehind(_) => Some(0),
+        AST::Seq(v) => v.iter().try_fold(0, |sum: usize, e| sum.checked_add(max_len(e)?)),
+        AST::Or(e1, e2) => Some(max_len(e1)?.max(max_len(e2)?)),
+        AST::Question(e) | AST::Chapcher(e) | AST::NamedChapcher(_, e) | AST::Atomic(e) => max_len(e),
+        // 何も読まない式は何回繰り返しても何も読まない
+        AST::Plus(e) | AST::Star(e) => max_len(e).filter(|n| *n == 0),
+        AST::Counter(e, (_, max)) => match (max_len(e)?, max) {
+            (0, _) => Some(0),
+            (n, Some(max)) => n.checked_mul(*max),
+            (_, None) => None,
+        },
+    }
+}
+
+/// 後読みの命令。評価器は中身を逆向きにしたプログラムを、今の位置から左へ向かって評価する
+#[derive(Debug, Clone, PartialEq)]
+pub struct LookBehind {
+    code: Cow<'static, [Instruction]>,
+    max_len: usize,
+    negate: bool,
+}
+
+impl LookBehind {
+    /// `code` は中身を `reverse` してコンパイルしたもの、`max_len` は中身が読む文字数の最大値。
+    /// `negate` なら中身がないときに成り立つ
+    pub const fn new(code: Cow<'static, [Instruction]>, max_len: usize, negate: bool) -> Self {
+        LookBehind { code, max_len, negate }
+    }
+
+    pub fn code(&self) -> &[Instruction] {
+        &self.code
+    }
+
+    pub fn max_len(&self) -> usize {
+        self.max_len
+    }
+
+    pub fn is_negated(&self) -> bool {
+        self.negate
+    }
+
+    /// 入力の終わりで成り立たなくても、入力を足せば成り立つかもしれないか。
+    /// 中身の右端の `\b` や `\B` は `sp` の後ろの文字を見る
+    pub fn looks_ahead(&self) -> bool {
+        self.code.iter().any(|i| matches!(i, Instruction::Assertion(a) if a.looks_ahead()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{max_len, reverse};
+    use crate::engine::{
+        codegen::get_code,
+        evaluator::eval,
+        parser::{parse, AST},
+        printer::to_pattern,
+    };
+
+    fn reversed(expr: &str) -> String {
+        to_pattern(&reverse(&parse(expr).unwrap()).unwrap())
+    }
+
+    #[test]
+    fn test_reverse() {
+        assert_eq!(reversed("ab(cd)*e"), "e(?:dc)*ba");
+        assert_eq!(reversed("^a|b$"), "a$|^b");
+        assert_eq!(reversed("\\Aa\\b\\d{2,3}\\z"), "\\A\\d{2,3}\\ba\\z");
+        assert!(reverse(&parse("a\\Z").unwrap()).is_err());
+        assert!(reverse(&parse("(?>a)").unwrap()).is_err());
+        assert!(reverse(&parse("(?<=a)b").unwrap()).is_err());
+
+        // 逆順にした入力の先頭からマッチする
+        let code = get_code(&reverse(&parse("x(ab|c)+y$").unwrap()).unwrap()).unwrap();
+        let line = "xabcaby".chars().rev().collect::<Vec<char>>();
+        assert!(eval(&code, &line, 0, false, &Default::default()).unwrap().0);
+    }
+
+    #[test]
+    fn test_max_len() {
+        let len = |expr: &str| max_len(&parse(expr).unwrap());
+        assert_eq!(len("ab|c"), Some(2));
+        assert_eq!(len("(ab)?\\d{2,3}$"), Some(5));
+        assert_eq!(len("\\b*a"), Some(1));
+        assert_eq!(len("a{0}b"), Some(1));
+        assert_eq!(len("a+"), None);
+        assert_eq!(len("a{2,}"), None);
+        assert_eq!(max_len(&AST::Seq(vec![])), Some(0));
+    }
+}
@@ -0,0 +1,171 @@
+//! AST を同じ言語を表すより単純な形に書き換える。
+//!
+//! - 要素が 1 つの `Seq` はその要素にし、`Seq` の中の `Seq` は展開する
+//! - `{0,}` `{0,1}` `{1}` は `*` `?` と繰り返しなしにする。`{1,}` は空文字列にマッチしない式だけ `+` にする
+//! - 隣り合う 1 文字の選択肢 (`a|b|c`) は 1 つの文字の集合にまとめる
+//!
+//! キャプチャの番号や選択肢の優先順位は変えない。
+
+use super::parser::AST;
+
+pub fn simplify(ast: AST) -> AST {
+    match ast {
+        AST::Seq(v) => {
+            let mut seq = Vec::new();
+            for e in v {
+                match simplify(e) {
+                    AST::Seq(inner) => seq.extend(inner),
+                    e => seq.push(e),
+                }
+            }
+            if seq.len() == 1 {
+                seq.pop().unwrap()
+            } else {
+                AST::Seq(seq)
+            }
+        }
+        AST::Or(e1, e2) => simplify_or(*e1, *e2),
+        AST::Counter(e, counter) => {
+            let e = Box::new(simplify(*e));
+            match counter {
+                (0, None) => AST::Star(e),
+                // 評価器は一度通った状態に戻ったスレッドを捨てるので、空文字列にマッチする式の `+` は
+                // 1 回目に空文字列を選ぶとそこで止まり、`{1,}` と結果が変わる
+                (1, None) if !can_be_empty(&e) => AST::Plus(e),
+                (0, Some(1)) => AST::Question(e),
+                (1, Some(1)) => *e,
+                _ => AST::Counter(e, counter),
+            }
+        }
+        AST::Plus(e) => AST::Plus(Box::new(simplify(*e))),
+        AST::Star(e) => AST::Star(Box::new(simplify(*e))),
+        AST::Question(e) => AST::Question(Box::new(simplify(*e))),
+        AST::Chapcher(e) => AST::Chapcher(Box::new(simplify(*e))),
+        AST::NamedChapcher(name, e) => AST::NamedChapcher(name, Box::new(simplify(*e))),
+        AST::Atomic(e) => AST::Atomic(Box::new(simplify(*e))),
+        AST::LookBehind(e) => AST::LookBehind(Box::new(simplify(*e))),
+        AST::NotLookBehind(e) => AST::NotLookBehind(Box::new(simplify(*e))),
+        AST::MatchChars(chars) => one_char(chars),
+        ast => ast,
+    }
+}
+
+/// 空文字列にマッチすることがあるか。アトミックグループや後読みの中身は多めに見積もる
+fn can_be_empty(ast: &AST) -> bool {
+    match ast {
+        AST::Char(_)
+        | AST::UnmatchChars(_)
+        | AST::MatchChars(_)
+        | AST::AnyNumber
+        | AST::NotNumber
+        | AST::Property(_)
+        | AST::NotProperty(_)
+        | AST::AnyChar => false,
+        AST::Caret | AST::Doller | AST::Assertion(_) | AST::LookBehind(_) | AST::NotLookBehind(_) => true,
+        AST::Star(_) | AST::Question(_) => true,
+        AST::Plus(e) | AST::Chapcher(e) | AST::NamedChapcher(_, e) | AST::Atomic(e) => can_be_empty(e),
+        AST::Counter(e, (min, _)) => *min == 0 || can_be_empty(e),
+        AST::Or(e1, e2) => can_be_empty(e1) || can_be_empty(e2),
+        AST::Seq(v) => v.iter().all(can_be_empty),
+    }
+}
+
+/// a|(b|(c|...)) を選択肢の並びにして、隣り合う 1 文字の選択肢をまとめてから右結合で組み直す
+fn simplify_or(e1: AST, e2: AST) -> AST {
+    let mut alternatives = Vec::new();
+    flatten_or(e1, &mut alternatives);
+    flatten_or(e2, &mut alternatives);
+
+    let mut merged: Vec<AST> = Vec::new();
+    for e in alternatives {
+        match (merged.last_mut(), chars(&e)) {
+            (Some(prev), Some(new)) if chars(prev).is_some() => {
+                let mut set = chars(prev).unwrap();
+                for c in new {
+                    if !set.contains(&c) {
+                        set.push(c);
+                    }
+                }
+                *prev = one_char(set);
+            }
+            _ => merged.push(e),
+        }
+    }
+
+    let mut ast = merged.pop().unwrap();
+    while let Some(e) = merged.pop() {
+        ast = AST::Or(Box::new(e), Box::new(ast));
+    }
+    ast
+}
+
+fn flatten_or(ast: AST, alternatives: &mut Vec<AST>) {
+    match simplify(ast) {
+        AST::Or(e1, e2) => {
+            flatten_or(*e1, alternatives);
+            flatten_or(*e2, alternatives);
+        }
+        e => alternatives.push(e),
+    }
+}
+
+/// 1 文字を読む選択肢なら、その文字の集合
+fn chars(ast: &AST) -> Option<Vec<char>> {
+    match ast {
+        AST::Char(c) => Some(vec![*c]),
+        AST::MatchChars(chars) => Some(chars.clone()),
+        _ => None,
+    }
+}
+
+fn one_char(mut chars: Vec<char>) -> AST {
+    if chars.len() == 1 {
+        AST::Char(chars.pop().unwrap())
+    } else {
+        AST::MatchChars(chars)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::simplify;
+    use crate::engine::parser::{parse, AST};
+
+    #[test]
+    fn test_simplify() {
+        let simplified = |expr: &str| simplify(parse(expr).unwrap());
+        assert_eq!(simplified("a"), AST::Char('a'));
+        assert_eq!(simplified("[ab]c"), AST::Seq(vec![AST::Char('a'), AST::Char('b'), AST::Char('c')]));
+        assert_eq!(simplified("a{0,}"), AST::Star(Box::new(AST::Char('a'))));
+        assert_eq!(simplified("a{1,}"), AST::Plus(Box::new(AST::Char('a'))));
+        // 空文字列にマッチする式の `+` は `{1,}` と結果が変わるので書き換えない
+        assert_eq!(simplified("(?:b?|a){1,}"),
+            AST::Counter(
+                Box::new(AST::Or(Box::new(AST::Question(Box::new(AST::Char('b')))), Box::new(AST::Char('a')))),
+                (1, None),
+            )
+        );
+        assert_eq!(simplified("(?:ab?){1,}"),
+            AST::Plus(Box::new(AST::Seq(vec![AST::Char('a'), AST::Question(Box::new(AST::Char('b')))])))
+        );
+        assert_eq!(simplified("a{0,1}"), AST::Question(Box::new(AST::Char('a'))));
+        assert_eq!(simplified("ab{1}"), AST::Seq(vec![AST::Char('a'), AST::Char('b')]));
+        assert_eq!(simplified("a{2,}"), AST::Counter(Box::new(AST::Char('a')), (2, None)));
+        assert_eq!(simplified("a|b|c"), AST::MatchChars(vec!['a', 'b', 'c']));
+        assert_eq!(simplified("a|a"), AST::Char('a'));
+        assert_eq!(simplified("(?i)a|b"), AST::MatchChars(vec!['a', 'A', 'b', 'B']));
+        // 間に他の選択肢があるとまとめない
+        assert_eq!(simplified("a|bc|d|e"),
+            AST::Or(
+                Box::new(AST::Char('a')),
+                Box::new(AST::Or(
+                    Box::new(AST::Seq(vec![AST::Char('b'), AST::Char('c')])),
+                    Box::new(AST::MatchChars(vec!['d', 'e'])),
+                )),
+            )
+        );
+        assert_eq!(simplified("(a)|b"),
+            AST::Or(Box::new(AST::Chapcher(Box::new(AST::Char('a')))), Box::new(AST::Char('b')))
+        );
+    }
+}
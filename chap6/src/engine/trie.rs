@@ -0,0 +1,244 @@
+//! `error|warn|fatal|...` のような文字列だけの選択を 1 命令で照合するためのトライ木。
+//!
+//! 選択肢の数に関係なく、入力を 1 文字ずつ辿るだけでマッチする選択肢が全部わかる。
+
+use std::borrow::Cow;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Node {
+    /// 遷移先が `Trie::edges` のどこからどこまでにあるか
+    next: (usize, usize),
+    /// ここで終わる選択肢の番号
+    accept: Option<usize>,
+    /// この節点以下で終わる選択肢の番号の最小値
+    min_accept: usize,
+}
+
+impl Node {
+    pub const fn new(next: (usize, usize), accept: Option<usize>, min_accept: usize) -> Self {
+        Node { next, accept, min_accept }
+    }
+
+    pub fn next(&self) -> (usize, usize) {
+        self.next
+    }
+
+    pub fn accept(&self) -> Option<usize> {
+        self.accept
+    }
+
+    pub fn min_accept(&self) -> usize {
+        self.min_accept
+    }
+}
+
+/// 節点と遷移を 1 つずつの配列に詰めたもの。`regex!` は両方を静的な配列として埋め込む
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trie {
+    nodes: Cow<'static, [Node]>,
+    /// 節点ごとに文字でソートした遷移先
+    edges: Cow<'static, [(char, usize)]>,
+    /// 選択肢の数
+    literals: usize,
+}
+
+impl Trie {
+    pub const ROOT: usize = 0;
+
+    /// 選択肢を優先度の高い順に受け取る。同じ文字列が 2 度現れたら先のものだけが効く
+    pub fn new(literals: Vec<Vec<char>>) -> Self {
+        // 節点ごとの遷移先と、ここで終わる選択肢。後で 1 つの配列に詰める
+        let mut next: Vec<Vec<(char, usize)>> = vec![Vec::new()];
+        let mut accept: Vec<Option<usize>> = vec![None];
+        for (alt, literal) in literals.iter().enumerate() {
+            let mut node = Self::ROOT;
+            for c in literal {
+                node = match next[node].binary_search_by_key(c, |(c, _)| *c) {
+                    Ok(i) => next[node][i].1,
+                    Err(i) => {
+                        next.push(Vec::new());
+                        accept.push(None);
+                        let new = next.len() - 1;
+                        next[node].insert(i, (*c, new));
+                        new
+                    }
+                };
+            }
+            accept[node].get_or_insert(alt);
+        }
+
+        // 子は必ず親より後ろにあるので、後ろから辿れば部分木の最小値が求まる
+        let mut min_accept = vec![usize::MAX; next.len()];
+        for n in (0..next.len()).rev() {
+            let min_child = next[n].iter().map(|(_, child)| min_accept[*child]).min();
+            min_accept[n] = accept[n].into_iter().chain(min_child).min().unwrap_or(usize::MAX);
+        }
+
+        let mut nodes = Vec::with_capacity(next.len());
+        let mut edges = Vec::new();
+        for ((next, accept), min_accept) in next.into_iter().zip(accept).zip(min_accept) {
+            nodes.push(Node::new((edges.len(), edges.len() + next.len()), accept, min_accept));
+            edges.extend(next);
+        }
+        Trie::from_parts(Cow::Owned(nodes), Cow::Owned(edges), literals.len())
+    }
+
+    pub const fn from_parts(
+        nodes: Cow<'static, [Node]>,
+        edges: Cow<'static, [(char, usize)]>,
+        literals: usize,
+    ) -> Self {
+        Trie { nodes, edges, literals }
+    }
+
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+
+    pub fn edges(&self) -> &[(char, usize)] {
+        &self.edges
+    }
+
+    pub fn literal_count(&self) -> usize {
+        self.literals
+    }
+
+    fn edges_of(&self, node: usize) -> &[(char, usize)] {
+        let (first, end) = self.nodes[node].next;
+        &self.edges[first..end]
+    }
+
+    /// 節点 `node` から文字 `c` で進んだ先
+    pub fn next(&self, node: usize, c: char) -> Option<usize> {
+        let next = self.edges_of(node);
+        next.binary_search_by_key(&c, |(c, _)| *c).ok().map(|i| next[i].1)
+    }
+
+    /// 節点 `node` で終わる選択肢
+    pub fn accept(&self, node: usize) -> Option<usize> {
+        self.nodes[node].accept
+    }
+
+    /// 節点 `node` から先に進めるか
+    pub fn has_next(&self, node: usize) -> bool {
+        !self.edges_of(node).is_empty()
+    }
+
+    /// 節点 `node` より先で終わる選択肢のうち、最も優先度の高いもの
+    pub fn min_accept(&self, node: usize) -> usize {
+        self.nodes[node].min_accept
+    }
+
+    /// どの節点でも、そこで終わる選択肢がその先で終わる選択肢すべてより優先されるか、すべてより後回しになるか。
+    /// 幅優先の評価器は、先へ進むスレッドを 1 つにまとめて、終わるスレッドの前か後ろに置くので、
+    /// `abc|a|abd` のように終わる選択肢の優先度が先の選択肢の間に挟まるトライ木は正しく評価できない
+    pub fn is_nested(&self) -> bool {
+        // 節点ごとの、その先 (節点自身は含まない) で終わる選択肢の番号の (最小値, 最大値)
+        let mut below = vec![None::<(usize, usize)>; self.nodes.len()];
+        for n in (0..self.nodes.len()).rev() {
+            for (_, child) in self.edges_of(n) {
+                let child_range = self.nodes[*child].accept.map(|alt| (alt, alt));
+                for (min, max) in [below[*child], child_range].into_iter().flatten() {
+                    below[n] = Some(below[n].map_or((min, max), |(lo, hi)| (lo.min(min), hi.max(max))));
+                }
+            }
+        }
+        self.nodes
+            .iter()
+            .zip(below)
+            .all(|(node, below)| match (node.accept, below) {
+                (Some(alt), Some((min, max))) => alt < min || max < alt,
+                _ => true,
+            })
+    }
+
+    /// `line[sp..]` がどれかの選択肢の途中までと一致していて、続きの文字を待っているか
+    pub fn needs_more(&self, line: &[char], sp: usize) -> bool {
+        let mut node = Self::ROOT;
+        for c in &line[sp.min(line.len())..] {
+            match self.next(node, *c) {
+                Some(next) => node = next,
+                None => return false,
+            }
+        }
+        self.has_next(node)
+    }
+
+    /// `line[sp..]` の先頭にマッチする選択肢を (選択肢の番号, 終わりの位置) にして、優先度の順に `ends` に入れる。
+    /// `ends` の元の中身は捨てる
+    pub fn matches(&self, line: &[char], sp: usize, ends: &mut Vec<(usize, usize)>) {
+        ends.clear();
+        let mut node = Self::ROOT;
+        let mut pos = sp;
+        loop {
+            if let Some(alt) = self.nodes[node].accept {
+                ends.push((alt, pos));
+            }
+            match line.get(pos).and_then(|c| self.next(node, *c)) {
+                Some(next) => {
+                    node = next;
+                    pos += 1;
+                }
+                None => break,
+            }
+        }
+        ends.sort_unstable();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Trie;
+
+    fn trie(literals: &[&str]) -> Trie {
+        Trie::new(literals.iter().map(|l| l.chars().collect()).collect())
+    }
+
+    impl Trie {
+        fn ends(&self, line: &[char], sp: usize) -> Vec<usize> {
+            let mut ends = Vec::new();
+            self.matches(line, sp, &mut ends);
+            ends.into_iter().map(|(_, end)| end).collect()
+        }
+    }
+
+    #[test]
+    fn test_matches() {
+        let t = trie(&["error", "warn", "err", "e"]);
+        let line = "errors".chars().collect::<Vec<char>>();
+        assert_eq!(t.ends(&line, 0), vec![5, 3, 1]);
+        assert_eq!(t.ends(&line, 1), Vec::<usize>::new());
+
+        let line = "warning".chars().collect::<Vec<char>>();
+        assert_eq!(t.ends(&line, 0), vec![4]);
+        assert_eq!(t.ends(&line, 4), Vec::<usize>::new());
+
+        // 先に書いた選択肢が優先される
+        let t = trie(&["a", "abc", "ab"]);
+        let line = "abcd".chars().collect::<Vec<char>>();
+        assert_eq!(t.ends(&line, 0), vec![1, 3, 2]);
+    }
+
+    #[test]
+    fn test_is_nested() {
+        assert!(trie(&["error", "warn", "err", "e"]).is_nested());
+        assert!(trie(&["a", "abc", "abd"]).is_nested());
+        assert!(trie(&["abc", "abd", "a"]).is_nested());
+        assert!(!trie(&["abc", "a", "abd"]).is_nested());
+        assert!(!trie(&["xabc", "y", "xa", "xabd"]).is_nested());
+        // 後から現れた同じ文字列は効かない
+        assert!(trie(&["a", "abc", "a", "abd"]).is_nested());
+    }
+
+    #[test]
+    fn test_min_accept() {
+        let t = trie(&["ab", "abc", "a"]);
+        assert_eq!(t.min_accept(Trie::ROOT), 0);
+        let a = t.next(Trie::ROOT, 'a').unwrap();
+        assert_eq!((t.accept(a), t.min_accept(a)), (Some(2), 0));
+        let ab = t.next(a, 'b').unwrap();
+        let abc = t.next(ab, 'c').unwrap();
+        assert_eq!((t.accept(abc), t.min_accept(abc)), (Some(1), 1));
+        assert_eq!(t.next(abc, 'd'), None);
+    }
+}
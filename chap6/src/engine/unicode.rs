@@ -0,0 +1,216 @@
+//! `\p{...}` で使う Unicode の一般カテゴリと用字 (script) の表、および大文字小文字の同一視。
+//!
+//! 表は build.rs が data/ のファイルから生成する。
+
+include!(concat!(env!("OUT_DIR"), "/unicode_tables.rs"));
+
+/// 一般カテゴリの長い名前と、表で使っている短い名前の対応
+const CATEGORY_ALIASES: &[(&str, &str)] = &[
+    ("Letter", "L"),
+    ("Cased_Letter", "LC"),
+    ("Uppercase_Letter", "Lu"),
+    ("Lowercase_Letter", "Ll"),
+    ("Titlecase_Letter", "Lt"),
+    ("Modifier_Letter", "Lm"),
+    ("Other_Letter", "Lo"),
+    ("Mark", "M"),
+    ("Nonspacing_Mark", "Mn"),
+    ("Spacing_Mark", "Mc"),
+    ("Enclosing_Mark", "Me"),
+    ("Number", "N"),
+    ("Decimal_Number", "Nd"),
+    ("Letter_Number", "Nl"),
+    ("Other_Number", "No"),
+    ("Punctuation", "P"),
+    ("Connector_Punctuation", "Pc"),
+    ("Dash_Punctuation", "Pd"),
+    ("Open_Punctuation", "Ps"),
+    ("Close_Punctuation", "Pe"),
+    ("Initial_Punctuation", "Pi"),
+    ("Final_Punctuation", "Pf"),
+    ("Other_Punctuation", "Po"),
+    ("Symbol", "S"),
+    ("Math_Symbol", "Sm"),
+    ("Currency_Symbol", "Sc"),
+    ("Modifier_Symbol", "Sk"),
+    ("Other_Symbol", "So"),
+    ("Separator", "Z"),
+    ("Space_Separator", "Zs"),
+    ("Line_Separator", "Zl"),
+    ("Paragraph_Separator", "Zp"),
+    ("Other", "C"),
+    ("Control", "Cc"),
+    ("Format", "Cf"),
+    ("Private_Use", "Co"),
+    ("Unassigned", "Cn"),
+];
+
+/// 一般カテゴリか用字で指定された文字の集合
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Property {
+    pub name: &'static str,
+    ranges: &'static [(char, char)],
+}
+
+/// 文字の集合を引く表
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Table {
+    GeneralCategory,
+    Script,
+}
+
+impl Table {
+    const fn entries(self) -> &'static [(&'static str, &'static [(char, char)])] {
+        match self {
+            Table::GeneralCategory => GENERAL_CATEGORIES,
+            Table::Script => SCRIPTS,
+        }
+    }
+}
+
+impl Property {
+    /// 表 `table` の `index` 番目の文字の集合。`regex!` が埋め込んだプログラムはこれで名前を引かずに作る
+    pub const fn from_table(table: Table, index: usize) -> Self {
+        let (name, ranges) = table.entries()[index];
+        Property { name, ranges }
+    }
+
+    /// `from_table` で同じものを作るための表と位置
+    pub fn position(&self) -> (Table, usize) {
+        [Table::GeneralCategory, Table::Script]
+            .into_iter()
+            .find_map(|table| {
+                let i = table.entries().iter().position(|(name, ranges)| *name == self.name && *ranges == self.ranges)?;
+                Some((table, i))
+            })
+            .expect("properties are taken from the tables")
+    }
+
+    /// ソート済みの文字の範囲 (両端を含む)
+    pub fn ranges(&self) -> &'static [(char, char)] {
+        self.ranges
+    }
+
+    pub fn contains(&self, c: char) -> bool {
+        self.ranges
+            .binary_search_by(|(first, last)| {
+                if c < *first {
+                    std::cmp::Ordering::Greater
+                } else if *last < c {
+                    std::cmp::Ordering::Less
+                } else {
+                    std::cmp::Ordering::Equal
+                }
+            })
+            .is_ok()
+    }
+}
+
+/// 大文字小文字、空白、`_`、`-` を無視して名前を比べる (UAX #44 LM3)
+fn normalize(name: &str) -> String {
+    name.chars()
+        .filter(|c| !matches!(c, ' ' | '_' | '-'))
+        .flat_map(|c| c.to_lowercase())
+        .collect()
+}
+
+fn find(table: &'static [(&'static str, &'static [(char, char)])], name: &str) -> Option<Property> {
+    table
+        .iter()
+        .find(|(n, _)| normalize(n) == name)
+        .map(|(name, ranges)| Property { name, ranges })
+}
+
+fn lookup_category(name: &str) -> Option<Property> {
+    let name = CATEGORY_ALIASES
+        .iter()
+        .find(|(long, _)| normalize(long) == name)
+        .map_or(name.to_string(), |(_, short)| normalize(short));
+    find(GENERAL_CATEGORIES, &name)
+}
+
+/// `\p{...}` の中身から文字の集合を探す。
+///
+/// `L`, `Letter`, `Han` のような名前のほか、`gc=L`, `General_Category=Letter`,
+/// `sc=Han`, `Script=Han` の形も受け付ける。
+pub fn lookup(name: &str) -> Option<Property> {
+    match name.split_once('=') {
+        Some((key, value)) => {
+            let value = normalize(value);
+            match normalize(key).as_str() {
+                "gc" | "generalcategory" => lookup_category(&value),
+                "sc" | "script" => find(SCRIPTS, &value),
+                _ => None,
+            }
+        }
+        None => {
+            let name = normalize(name);
+            lookup_category(&name).or_else(|| find(SCRIPTS, &name))
+        }
+    }
+}
+
+/// 大文字小文字を区別しないときに `c` と同じとみなす文字
+pub fn fold_case(c: char) -> Vec<char> {
+    let mut chars = vec![c];
+    let lower = c.to_lowercase().collect::<Vec<char>>();
+    let upper = c.to_uppercase().collect::<Vec<char>>();
+    for folded in [lower, upper] {
+        if let [f] = folded[..] {
+            if !chars.contains(&f) {
+                chars.push(f);
+            }
+        }
+    }
+    chars
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fold_case, lookup, Property, Table};
+
+    #[test]
+    fn test_lookup() {
+        let letter = lookup("L").unwrap();
+        assert!(letter.contains('a'));
+        assert!(letter.contains('あ'));
+        assert!(!letter.contains('1'));
+        assert_eq!(lookup("Letter"), Some(letter));
+        assert_eq!(lookup("gc=letter"), Some(letter));
+
+        let lu = lookup("Uppercase_Letter").unwrap();
+        assert!(lu.contains('A') && !lu.contains('a'));
+
+        let katakana = lookup("Katakana").unwrap();
+        assert!(katakana.contains('カ') && katakana.contains('ヲ'));
+        assert!(!katakana.contains('か'));
+        assert_eq!(lookup("sc=Katakana"), Some(katakana));
+
+        let hiragana = lookup("Hiragana").unwrap();
+        assert!(hiragana.contains('か') && !hiragana.contains('カ'));
+
+        let han = lookup("Script=Han").unwrap();
+        assert!(han.contains('漢') && han.contains('字') && !han.contains('a'));
+
+        assert!(lookup("Nd").unwrap().contains('٣'));
+        assert_eq!(lookup("NoSuchScript"), None);
+        assert_eq!(lookup("xx=L"), None);
+
+        // 表の位置から同じ集合を作り直せる
+        for p in [letter, lu, katakana, han] {
+            let (table, i) = p.position();
+            assert_eq!(Property::from_table(table, i), p);
+        }
+        assert_eq!(letter.position().0, Table::GeneralCategory);
+        assert_eq!(han.position().0, Table::Script);
+    }
+
+    #[test]
+    fn test_fold_case() {
+        assert_eq!(fold_case('a'), vec!['a', 'A']);
+        assert_eq!(fold_case('A'), vec!['A', 'a']);
+        assert_eq!(fold_case('1'), vec!['1']);
+        assert_eq!(fold_case('Ω'), vec!['Ω', 'ω']);
+        assert_eq!(fold_case('あ'), vec!['あ']);
+    }
+}
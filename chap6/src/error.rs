@@ -0,0 +1,68 @@
+//! ライブラリの公開 API が返すエラー。
+
+use std::{
+    error,
+    fmt::{self, Display},
+    ops::Range,
+};
+
+/// エラーの種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// パターンの構文が正しくない
+    Syntax,
+    /// パターンをプログラムにできなかった
+    Compile,
+    /// パターンが AST の深さ、命令数、繰り返しの回数の上限 (`RegexBuilder::depth_limit` など) を超えた
+    LimitExceeded,
+    /// その照合方式では使えない構文がある
+    Unsupported,
+    /// 評価が `RegexBuilder::step_budget` の命令数を超えた。
+    /// `DerivativeRegex` で言語を調べるときは、辿る DFA の状態の数が上限を超えた
+    BudgetExceeded,
+    /// 評価が `RegexBuilder::timeout` の時間を超えた
+    Timeout,
+    /// 評価器の内部エラー
+    Internal,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    kind: ErrorKind,
+    message: String,
+    span: Option<Range<usize>>,
+}
+
+impl Error {
+    pub(crate) fn new(kind: ErrorKind, message: String, span: Option<Range<usize>>) -> Self {
+        Error { kind, message, span }
+    }
+
+    /// メッセージの前に、どこで起きたエラーかを付ける
+    pub(crate) fn context(mut self, context: &str) -> Self {
+        self.message = format!("{context}: {}", self.message);
+        self
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    /// エラーの原因になったパターン中の範囲 (バイト単位)。
+    /// 位置を特定できないエラーや、評価中のエラーでは None
+    pub fn span(&self) -> Option<Range<usize>> {
+        self.span.clone()
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.span {
+            Some(span) => write!(f, "{} (at {}..{})", self.message, span.start, span.end),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl error::Error for Error {}
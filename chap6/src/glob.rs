@@ -0,0 +1,117 @@
+//! シェルのグロブでパスを照合する。
+//!
+//! グロブは正規表現の AST にしてからコンパイルし、パス全体にマッチするかを Pike VM で調べる。
+//! ディレクトリの区切りは `/` だけで、`.` で始まる名前も特別扱いしない。
+//!
+//! ```
+//! use chap6::Glob;
+//!
+//! let glob = Glob::new("src/**/*.{rs,toml}").unwrap();
+//! assert!(glob.is_match("src/engine/glob.rs").unwrap());
+//! assert!(glob.is_match("src/lib.rs").unwrap());
+//! assert!(!glob.is_match("macros/src/lib.rs").unwrap());
+//! assert_eq!(glob.regex(), "^src/(?:[^/]*/)*[^/]*\\.(?:rs|toml)$");
+//! ```
+
+use crate::{
+    engine::{self, EvalLimits, Instruction},
+    error::Error,
+};
+
+/// コンパイル済みのグロブ
+#[derive(Debug)]
+pub struct Glob {
+    pattern: String,
+    regex: String,
+    code: Vec<Instruction>,
+    limits: EvalLimits,
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Result<Glob, Error> {
+        let (regex, code) = engine::compile_glob(pattern)?;
+        Ok(Glob {
+            pattern: pattern.to_string(),
+            regex,
+            code,
+            limits: EvalLimits::default(),
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    /// 同じパスにマッチする正規表現
+    pub fn regex(&self) -> &str {
+        &self.regex
+    }
+
+    /// パス全体がグロブにマッチするか
+    pub fn is_match(&self, path: &str) -> Result<bool, Error> {
+        let line = path.chars().collect::<Vec<char>>();
+        let (is_match, _) = engine::eval_captures(&self.code, &line, 0, false, &self.limits)?;
+        Ok(is_match)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Glob;
+    use crate::{error::ErrorKind, regex::Regex};
+
+    fn matches(glob: &str, path: &str) -> bool {
+        Glob::new(glob).unwrap().is_match(path).unwrap()
+    }
+
+    #[test]
+    fn test_is_match() {
+        assert!(matches("*.rs", "main.rs"));
+        assert!(matches("*.rs", ".rs"));
+        assert!(!matches("*.rs", "src/main.rs"));
+        assert!(!matches("*.rs", "main.rs.bak"));
+
+        assert!(matches("src/**/mod.rs", "src/mod.rs"));
+        assert!(matches("src/**/mod.rs", "src/engine/mod.rs"));
+        assert!(matches("src/**/mod.rs", "src/a/b/mod.rs"));
+        assert!(!matches("src/**/mod.rs", "src/amod.rs"));
+        assert!(matches("**/*.rs", "lib.rs"));
+        assert!(matches("**/*.rs", "a/b/lib.rs"));
+        assert!(matches("target/**", "target/debug/chap6"));
+        assert!(!matches("target/**", "src/target/x"));
+        assert!(!matches("a**b", "a/b"));
+
+        assert!(matches("file?.[ch]", "file1.c"));
+        assert!(matches("file?.[ch]", "fileX.h"));
+        assert!(!matches("file?.[ch]", "file12.c"));
+        assert!(!matches("file?.[ch]", "file/.c"));
+        assert!(matches("[!.]*", "readme"));
+        assert!(!matches("[!.]*", ".git"));
+        assert!(matches("v[0-9]", "v7"));
+
+        assert!(matches("{a,b}.txt", "b.txt"));
+        assert!(matches("x{,.{tar,zip}}", "x"));
+        assert!(matches("x{,.{tar,zip}}", "x.zip"));
+        assert!(!matches("x{,.{tar,zip}}", "x.tar.zip"));
+        assert!(matches("\\*.{md,}", "*."));
+        assert!(matches("ファイル*.txt", "ファイル一覧.txt"));
+    }
+
+    #[test]
+    fn test_regex() {
+        // 返す正規表現でも同じパスにマッチする
+        let glob = Glob::new("{src,tests}/**/[!_]*.rs").unwrap();
+        let re = Regex::new(glob.regex()).unwrap();
+        for path in ["src/lib.rs", "tests/a/b.rs", "src/_x.rs", "benches/a.rs", "src/a.rsx"] {
+            assert_eq!(glob.is_match(path).unwrap(), re.is_match(path).unwrap(), "{path}");
+        }
+    }
+
+    #[test]
+    fn test_error() {
+        let err = Glob::new("値[ab").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Syntax);
+        assert_eq!(err.span(), Some(3..4));
+        assert_eq!(Glob::new("{a,b").unwrap_err().span(), Some(0..1));
+    }
+}
@@ -0,0 +1,273 @@
+//! 正規表現の規則を並べて作る字句解析器。
+//!
+//! 規則はまとめて 1 つのプログラムにコンパイルし、各規則の終わりに規則の番号を付けた
+//! match を置く。入力の各位置で最も長くマッチする規則を選び、同じ長さなら先に
+//! 加えた規則を選ぶ。どの規則にもマッチしない文字は、続くものをまとめてエラートークンにする。
+//!
+//! ```
+//! use chap6::LexerBuilder;
+//!
+//! let lexer = LexerBuilder::new()
+//!     .rule("if", "if")
+//!     .rule("ident", "\\p{L}+")
+//!     .rule("space", " +")
+//!     .build()
+//!     .unwrap();
+//! let tokens = lexer.tokenize("if ifx").unwrap();
+//! let kinds = tokens.iter().map(|t| t.kind().copied()).collect::<Vec<_>>();
+//! assert_eq!(kinds, vec![Some("if"), Some("space"), Some("ident")]);
+//! ```
+
+use crate::{
+    engine::{self, EvalLimits, Instruction},
+    error::{Error, ErrorKind},
+};
+use std::ops::Range;
+
+/// 規則を加えて `Lexer` を作るビルダ。`K` はトークンの種類
+#[derive(Debug, Clone)]
+pub struct LexerBuilder<K> {
+    rules: Vec<(K, String)>,
+    limits: EvalLimits,
+}
+
+impl<K: Clone> LexerBuilder<K> {
+    pub fn new() -> Self {
+        LexerBuilder {
+            rules: Vec::new(),
+            limits: EvalLimits::default(),
+        }
+    }
+
+    /// 規則を加える。先に加えた規則ほど優先度が高い
+    pub fn rule(&mut self, kind: K, pattern: &str) -> &mut Self {
+        self.rules.push((kind, pattern.to_string()));
+        self
+    }
+
+    /// 1 つのトークンを読むのに実行する命令数の上限
+    pub fn step_budget(&mut self, budget: usize) -> &mut Self {
+        self.limits.step_budget = Some(budget);
+        self
+    }
+
+    pub fn build(&self) -> Result<Lexer<K>, Error> {
+        if self.rules.is_empty() {
+            return Err(Error::new(ErrorKind::Compile, "lexer: no rules".to_string(), None));
+        }
+        let patterns = self.rules.iter().map(|(_, p)| p.as_str()).collect::<Vec<&str>>();
+        Ok(Lexer {
+            kinds: self.rules.iter().map(|(kind, _)| kind.clone()).collect(),
+            code: engine::compile_rules(&patterns)?,
+            limits: self.limits,
+        })
+    }
+}
+
+impl<K: Clone> Default for LexerBuilder<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// コンパイル済みの字句解析器
+#[derive(Debug)]
+pub struct Lexer<K> {
+    kinds: Vec<K>,
+    code: Vec<Instruction>,
+    limits: EvalLimits,
+}
+
+impl<K: Clone> Lexer<K> {
+    /// 入力全体をトークンに分ける。
+    /// 空文字列にしかマッチしない位置は、どの規則にもマッチしないものとして扱う
+    pub fn tokenize<'t>(&self, text: &'t str) -> Result<Vec<Token<'t, K>>, Error> {
+        let line = text.chars().collect::<Vec<char>>();
+        // 何文字目かをバイト位置にする表。末尾の位置も入れておく
+        let offsets = text
+            .char_indices()
+            .map(|(i, _)| i)
+            .chain([text.len()])
+            .collect::<Vec<usize>>();
+        let token = |kind, start: usize, end: usize| Token {
+            kind,
+            text,
+            start: offsets[start],
+            end: offsets[end],
+        };
+
+        let mut tokens = Vec::new();
+        // どの規則にもマッチしなかった文字の始まり
+        let mut error_start = None;
+        let mut pos = 0;
+        while pos < line.len() {
+            match engine::eval_longest(&self.code, &line, pos, &self.limits)? {
+                Some((end, rule)) if end > pos => {
+                    if let Some(start) = error_start.take() {
+                        tokens.push(token(None, start, pos));
+                    }
+                    tokens.push(token(Some(self.kinds[rule].clone()), pos, end));
+                    pos = end;
+                }
+                _ => {
+                    error_start.get_or_insert(pos);
+                    pos += 1;
+                }
+            }
+        }
+        if let Some(start) = error_start {
+            tokens.push(token(None, start, line.len()));
+        }
+        Ok(tokens)
+    }
+}
+
+/// 切り出したトークン。位置は `text` の中のバイト単位
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token<'t, K> {
+    /// どの規則にもマッチしなかった部分なら None
+    kind: Option<K>,
+    text: &'t str,
+    start: usize,
+    end: usize,
+}
+
+impl<'t, K> Token<'t, K> {
+    pub fn kind(&self) -> Option<&K> {
+        self.kind.as_ref()
+    }
+
+    /// どの規則にもマッチしなかった部分か
+    pub fn is_error(&self) -> bool {
+        self.kind.is_none()
+    }
+
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    pub fn range(&self) -> Range<usize> {
+        self.start..self.end
+    }
+
+    pub fn as_str(&self) -> &'t str {
+        &self.text[self.range()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LexerBuilder;
+    use crate::error::ErrorKind;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Kind {
+        Word,
+        Pipe,
+        Or,
+        Redirect,
+        Append,
+        Space,
+    }
+
+    fn tokens(lexer: &super::Lexer<Kind>, text: &str) -> Vec<(Option<Kind>, String)> {
+        lexer
+            .tokenize(text)
+            .unwrap()
+            .iter()
+            .map(|t| (t.kind().copied(), t.as_str().to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_tokenize() {
+        // zerosh のコマンド行
+        let lexer = LexerBuilder::new()
+            .rule(Kind::Pipe, "\\|")
+            .rule(Kind::Or, "\\|\\|")
+            .rule(Kind::Redirect, ">")
+            .rule(Kind::Append, ">>")
+            .rule(Kind::Space, " +")
+            .rule(Kind::Word, "[^ >\\|]+")
+            .build()
+            .unwrap();
+        let t = |kind: Kind, s: &str| (Some(kind), s.to_string());
+        assert_eq!(
+            tokens(&lexer, "ls -l || cat  a.txt >> out"),
+            vec![
+                t(Kind::Word, "ls"),
+                t(Kind::Space, " "),
+                t(Kind::Word, "-l"),
+                t(Kind::Space, " "),
+                t(Kind::Or, "||"),
+                t(Kind::Space, " "),
+                t(Kind::Word, "cat"),
+                t(Kind::Space, "  "),
+                t(Kind::Word, "a.txt"),
+                t(Kind::Space, " "),
+                t(Kind::Append, ">>"),
+                t(Kind::Space, " "),
+                t(Kind::Word, "out"),
+            ]
+        );
+        assert_eq!(tokens(&lexer, "a|b>c")[1], t(Kind::Pipe, "|"));
+        assert_eq!(tokens(&lexer, ""), vec![]);
+    }
+
+    #[test]
+    fn test_priority_and_errors() {
+        // 同じ長さなら先の規則、長さが違えば長いほう
+        let lexer = LexerBuilder::new()
+            .rule("keyword", "let|in")
+            .rule("ident", "\\p{L}[\\p{L}|\\d]*")
+            .rule("number", "\\d{1,3}")
+            .rule("eq", "=")
+            .rule("space", " ")
+            .build()
+            .unwrap();
+        let result = lexer.tokenize("let x1 = 12345 in lettuce ?? y").unwrap();
+        let kinds = result.iter().map(|t| (t.kind().copied(), t.as_str())).collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![
+                (Some("keyword"), "let"),
+                (Some("space"), " "),
+                (Some("ident"), "x1"),
+                (Some("space"), " "),
+                (Some("eq"), "="),
+                (Some("space"), " "),
+                (Some("number"), "123"),
+                (Some("number"), "45"),
+                (Some("space"), " "),
+                (Some("keyword"), "in"),
+                (Some("space"), " "),
+                (Some("ident"), "lettuce"),
+                (Some("space"), " "),
+                (None, "??"),
+                (Some("space"), " "),
+                (Some("ident"), "y"),
+            ]
+        );
+        // 位置はバイト単位
+        let result = lexer.tokenize("値 = 1").unwrap();
+        assert_eq!(result[0].range(), 0..3);
+        assert_eq!(result[2].range(), 4..5);
+        let result = lexer.tokenize("x!").unwrap();
+        assert!(result[1].is_error());
+        assert_eq!(result[1].range(), 1..2);
+    }
+
+    #[test]
+    fn test_build_error() {
+        let err = LexerBuilder::new().rule(0, "a").rule(1, "(b").build().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Syntax);
+        assert!(err.to_string().starts_with("rule 1: "));
+        let err = LexerBuilder::new().rule(0, "(?>a)").build().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+        assert_eq!(LexerBuilder::<u8>::new().build().unwrap_err().kind(), ErrorKind::Compile);
+    }
+}
@@ -1,4 +1,86 @@
+//! 正規表現エンジン。
+//!
+//! ```
+//! use chap6::Regex;
+//!
+//! let re = Regex::new("(\\d{4})-(\\d{2})").unwrap();
+//! let caps = re.captures("date: 2024-01").unwrap().unwrap();
+//! assert_eq!(caps.get(0).unwrap().as_str(), "2024-01");
+//! assert_eq!(caps.get(1).unwrap().range(), 6..10);
+//! ```
+//!
+//! `RegexBuilder` で評価の方式 (深さ優先か幅優先か) や評価の上限を変えられる。
+//! エラーはすべて `Error` で、種類 (`ErrorKind`) とパターン中の位置を持つ。
+//! `debugger::Debugger` で評価器を 1 命令ずつ動かせる。
+//! `chap6-macros` の `regex!` を使うと、パターンをコンパイル時に検査してプログラムを埋め込める。
+//! `LexerBuilder` で規則を並べると、最長一致でトークンに分ける字句解析器を作れる。
+//! `DerivativeRegex` はコード生成を使わない微分による照合で、共通部分や補集合も作れる。
+//! `Glob` はシェルのグロブ (`src/**/*.rs` など) を同じエンジンでパスに照合する。
+
+pub mod debugger;
 mod engine;
+mod error;
+mod glob;
 mod helper;
+mod lexer;
+mod pool;
+mod regex;
+
+pub use engine::{
+    do_matching, do_matching_with_limits, normalize, print, DerivativeRegex, EvalError, EvalLimits,
+};
+pub use error::{Error, ErrorKind};
+pub use glob::Glob;
+pub use helper::DynError;
+pub use lexer::{Lexer, LexerBuilder, Token};
+pub use regex::{Captures, Match, PartialMatch, Regex, RegexBuilder};
+
+/// `chap6-macros` が使う。公開 API ではない
+#[doc(hidden)]
+pub mod __private {
+    pub use crate::engine::{
+        assertion::Assertion,
+        emit::{names_to_rust, reverse_to_rust, to_rust},
+        trie::Node as TrieNode,
+        unicode::Table,
+        Instruction,
+    };
+    use crate::{
+        engine::{self, reverse::LookBehind, trie::Trie, unicode::Property},
+        error::Error,
+        regex::Regex,
+    };
+    use std::borrow::Cow;
+
+    /// パターンをプログラムにし、それを表す Rust の式と、グループの名前の式と、
+    /// 逆向きのプログラムの式を返す
+    pub fn compile_to_rust(pattern: &str) -> Result<(String, String, String), Error> {
+        let program = engine::compile_with_names(pattern, &Default::default())?;
+        Ok((
+            to_rust(&program.code),
+            names_to_rust(&program.names),
+            reverse_to_rust(program.reverse.as_deref()),
+        ))
+    }
+
+    pub const fn regex(
+        pattern: &'static str,
+        code: &'static [Instruction],
+        names: &'static [Option<&'static str>],
+        reverse: Option<&'static [Instruction]>,
+    ) -> Regex {
+        Regex::from_static(pattern, code, names, reverse)
+    }
+
+    pub const fn property(table: Table, index: usize) -> Property {
+        Property::from_table(table, index)
+    }
+
+    pub const fn trie(nodes: &'static [TrieNode], edges: &'static [(char, usize)], literals: usize) -> Trie {
+        Trie::from_parts(Cow::Borrowed(nodes), Cow::Borrowed(edges), literals)
+    }
 
-pub use engine::{do_matching, print};
+    pub const fn look_behind(code: &'static [Instruction], max_len: usize, negate: bool) -> LookBehind {
+        LookBehind::new(Cow::Borrowed(code), max_len, negate)
+    }
+}
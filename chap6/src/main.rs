@@ -1,7 +1,4 @@
-mod engine;
-mod helper;
-
-use helper::DynError;
+use chap6::{DynError, RegexBuilder};
 use std::{
     env,
     fs::File,
@@ -23,7 +20,7 @@ fn match_file(expr: &str, file: &str) -> Result<(), DynError> {
     let f = File::open(file)?;
     let reader = BufReader::new(f);
 
-    engine::print(expr)?;
+    chap6::print(expr)?;
     println!();
 
     for line in reader.lines() {
@@ -34,13 +31,13 @@ fn match_file(expr: &str, file: &str) -> Result<(), DynError> {
     Ok(())
 }
 
-fn exec(expr: &str, line: &str, is_depth: bool) -> Result<(bool, Vec<String>), DynError> {
-    for (i, _) in line.char_indices() {
-        let (is_match, matched_str) = engine::do_matching(expr, &line[i..], i, is_depth)?;
-        if  is_match {
-            println!("line: {line}, &line[i..]: {:?}, i: {}", &line[i..], i);
-            return Ok((true, matched_str));
-        }
+fn exec(expr: &str, line: &str, is_depth: bool) -> Result<(bool, Vec<Option<String>>), DynError> {
+    let re = RegexBuilder::new(expr).depth_first(is_depth).build()?;
+    if let Some(caps) = re.captures(line)? {
+        let m = caps.get(0).unwrap();
+        println!("line: {line}, &line[i..]: {:?}, i: {}", &line[m.start()..], m.start());
+        let matched_str = caps.iter().skip(1).map(|m| m.map(|m| m.as_str().to_string())).collect();
+        return Ok((true, matched_str));
     }
     Ok((false, vec![]))
 }
@@ -77,8 +74,11 @@ mod tests {
         assert_eq!(exec("ab[cd|ef]{2,}g{2}h", "abcdefefggh", true).unwrap(), (true, vec![]));
         assert_eq!(exec("ab[^cd|ef]", "abg", true).unwrap(), (true, vec![]));
         assert_eq!(exec("ab[^cd|ef]", "abef", true).unwrap(), (true, vec![]));
-        assert_eq!(exec("ab([^cd]{2})", "abef", true).unwrap(), (true, vec!["ef".to_string()]));
-        assert_eq!(exec("ab((\\d{2})-(\\d{2}))", "ab12-34", true).unwrap(), (true, vec!["12-34".to_string(), "12".to_string(), "34".to_string()]));
+        assert_eq!(exec("ab([^cd]{2})", "abef", true).unwrap(), (true, vec![Some("ef".to_string())]));
+        assert_eq!(exec("ab((\\d{2})-(\\d{2}))", "ab12-34", true).unwrap(), (true, vec![Some("12-34".to_string()), Some("12".to_string()), Some("34".to_string())]));
+
+        assert_eq!(exec("ab(?:cd|ef)+(\\d)", "abcdef1", true).unwrap(), (true, vec![Some("1".to_string())]));
+        assert_eq!(exec("(x)?(\\d)", "a1", true).unwrap(), (true, vec![None, Some("1".to_string())]));
 
         assert_eq!(exec("abc|def", "efa", true).unwrap(), (false, vec![]));
         assert_eq!(exec("[ab|cd]+", "", true).unwrap(), (false, vec![]));
@@ -98,4 +98,43 @@ mod tests {
         assert_eq!(exec("[abc]*", "aabcabc", true).unwrap(), (true, vec![]));
         assert_eq!(exec("abc", "aabc", true).unwrap(), (true, vec![]));
     }
+
+    #[test]
+    fn test_unicode() {
+        let line = "2024-01-01 ERROR ファイルが見つかりません";
+        assert_eq!(exec("(\\p{Katakana}+)", line, true).unwrap(), (true, vec![Some("ファイル".to_string())]));
+        assert_eq!(exec("(\\p{Hiragana}+)", line, true).unwrap(), (true, vec![Some("が".to_string())]));
+        assert_eq!(exec("(\\p{Han}+)", "見つかりません", true).unwrap(), (true, vec![Some("見".to_string())]));
+        assert_eq!(exec("(\\p{Lu}+) ", line, true).unwrap(), (true, vec![Some("ERROR".to_string())]));
+        assert_eq!(exec("^\\P{L}+$", "2024-01-01", true).unwrap(), (true, vec![]));
+        assert_eq!(exec("^\\P{L}+$", "2024-01-01 ERROR", true).unwrap(), (false, vec![]));
+
+        assert_eq!(exec("(?i)error", line, true).unwrap(), (true, vec![]));
+        assert_eq!(exec("(?i)(error) (\\p{Katakana}+)", line, true).unwrap(), (true, vec![Some("ERROR".to_string()), Some("ファイル".to_string())]));
+        assert_eq!(exec("error", line, true).unwrap(), (false, vec![]));
+        assert_eq!(exec("((?i)e)rror", line, true).unwrap(), (false, vec![]));
+    }
+
+    #[test]
+    fn test_literal_dot() {
+        let ip = "^(\\d{1,3})\\.(\\d{1,3})\\.(\\d{1,3})\\.(\\d{1,3})$";
+        assert_eq!(exec(ip, "192.168.0.1", true).unwrap(), (true, vec![Some("192".to_string()), Some("168".to_string()), Some("0".to_string()), Some("1".to_string())]));
+        assert_eq!(exec(ip, "192x168x0x1", true).unwrap(), (false, vec![]));
+        assert_eq!(exec(ip, "192.168.0", true).unwrap(), (false, vec![]));
+        assert_eq!(exec("\\d+\\.\\d+", "version 1.2", true).unwrap(), (true, vec![]));
+        assert_eq!(exec("\\d+\\.\\d+", "version 1-2", true).unwrap(), (false, vec![]));
+
+        assert_eq!(exec("\\.rs$", "src/main.rs", true).unwrap(), (true, vec![]));
+        assert_eq!(exec("\\.rs$", "src/main_rs", true).unwrap(), (false, vec![]));
+        assert_eq!(exec("\\.[rs|toml]$", "Cargo.toml", true).unwrap(), (true, vec![]));
+        assert_eq!(exec("\\.[rs|toml]$", "Cargo-toml", true).unwrap(), (false, vec![]));
+        assert_eq!(exec("^[^.]+\\.tar\\.gz$", "backup.tar.gz", true).unwrap(), (true, vec![]));
+        assert_eq!(exec("^[^.]+\\.tar\\.gz$", "backup.tarxgz", true).unwrap(), (false, vec![]));
+
+        // 他のメタ文字もエスケープすればそのもの
+        assert_eq!(exec("\\[\\d+\\]", "array[10]", true).unwrap(), (true, vec![]));
+        assert_eq!(exec("a\\{2\\}", "a{2}", true).unwrap(), (true, vec![]));
+        assert_eq!(exec("a\\{2\\}", "aa", true).unwrap(), (false, vec![]));
+        assert_eq!(exec("\\^\\$\\\\", "x^$\\", true).unwrap(), (true, vec![]));
+    }
 }
@@ -0,0 +1,110 @@
+//! スレッドをまたいで使い回す値のプール。
+//!
+//! `get` で取り出した値は、ガードを捨てるとプールに戻る。
+//! 同時に取り出しているスレッドの数だけ値ができ、それぞれのスレッドは
+//! 他のスレッドと取り合わずに自分の値を使える。
+
+use std::{
+    fmt,
+    ops::{Deref, DerefMut},
+    sync::Mutex,
+};
+
+pub(crate) struct Pool<T> {
+    stack: Mutex<Vec<T>>,
+}
+
+impl<T: Default> Pool<T> {
+    pub const fn new() -> Self {
+        Pool {
+            stack: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// 空いている値を取り出す。なければ新しく作る
+    pub fn get(&self) -> PoolGuard<'_, T> {
+        // 値を使っている途中で panic しても、値そのものは壊れていないので使い続ける
+        let value = self.stack.lock().unwrap_or_else(|e| e.into_inner()).pop();
+        PoolGuard {
+            pool: self,
+            value: Some(value.unwrap_or_default()),
+        }
+    }
+}
+
+impl<T> fmt::Debug for Pool<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // 中身はそのときどきで変わるので出さない
+        f.debug_struct("Pool").finish_non_exhaustive()
+    }
+}
+
+/// プールから取り出した値。捨てるとプールに戻る
+pub(crate) struct PoolGuard<'a, T> {
+    pool: &'a Pool<T>,
+    /// Drop で取り出すまでは常に Some
+    value: Option<T>,
+}
+
+impl<T> Deref for PoolGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value.as_ref().unwrap()
+    }
+}
+
+impl<T> DerefMut for PoolGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.value.as_mut().unwrap()
+    }
+}
+
+impl<T> Drop for PoolGuard<'_, T> {
+    fn drop(&mut self) {
+        if let Some(value) = self.value.take() {
+            self.pool.stack.lock().unwrap_or_else(|e| e.into_inner()).push(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Pool;
+    use std::thread;
+
+    #[test]
+    fn test_reuse() {
+        let pool = Pool::<Vec<u8>>::new();
+        {
+            let mut v = pool.get();
+            v.reserve(100);
+            v.push(1);
+        }
+        // 戻した値がそのまま出てくる
+        let v = pool.get();
+        assert_eq!(*v, vec![1]);
+        assert!(v.capacity() >= 100);
+        // 使っている間は別の値になる
+        assert!(pool.get().is_empty());
+    }
+
+    #[test]
+    fn test_threads() {
+        let pool = Pool::<Vec<usize>>::new();
+        thread::scope(|s| {
+            for i in 0..8 {
+                let pool = &pool;
+                s.spawn(move || {
+                    for _ in 0..100 {
+                        let mut v = pool.get();
+                        v.clear();
+                        v.push(i);
+                        assert_eq!(*v, vec![i]);
+                    }
+                });
+            }
+        });
+        assert!(pool.stack.lock().unwrap().len() <= 8);
+    }
+}
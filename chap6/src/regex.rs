@@ -0,0 +1,679 @@
+//! コンパイル済みの正規表現と、マッチの結果。
+
+use crate::{
+    engine::{self, CaptureSpans, CompileLimits, EvalLimits, Instruction, Partial, Scratch},
+    error::Error,
+    pool::Pool,
+};
+use std::{borrow::Cow, fmt, ops::Range, sync::Arc, time::Duration};
+
+/// これより短い入力では、開始位置を順に試すほうが速い
+const FAST_SEARCH_MIN_CHARS: usize = 32;
+
+/// `Regex` の設定を変えてコンパイルするためのビルダ
+#[derive(Debug, Clone)]
+pub struct RegexBuilder {
+    pattern: String,
+    is_depth: bool,
+    limits: EvalLimits,
+    compile_limits: CompileLimits,
+}
+
+impl RegexBuilder {
+    pub fn new(pattern: &str) -> Self {
+        RegexBuilder {
+            pattern: pattern.to_string(),
+            is_depth: true,
+            limits: EvalLimits::default(),
+            compile_limits: CompileLimits::default(),
+        }
+    }
+
+    /// true なら深さ優先 (バックトラック)、false なら幅優先 (Pike VM) で評価する。既定は true。
+    /// アトミックグループを含むパターンは常に深さ優先で評価する
+    pub fn depth_first(&mut self, yes: bool) -> &mut Self {
+        self.is_depth = yes;
+        self
+    }
+
+    /// 1 つの開始位置からの評価で実行する命令数の上限
+    pub fn step_budget(&mut self, budget: usize) -> &mut Self {
+        self.limits.step_budget = Some(budget);
+        self
+    }
+
+    /// 1 つの開始位置からの評価にかける時間の上限
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.limits.timeout = Some(timeout);
+        self
+    }
+
+    /// パターンの入れ子の深さの上限。グループや繰り返しを 1 つ入れ子にするごとに 1 つ深くなる。既定は 500
+    pub fn depth_limit(&mut self, depth: usize) -> &mut Self {
+        self.compile_limits.max_depth = depth;
+        self
+    }
+
+    /// コンパイルしたプログラムの命令数の上限。既定は 100,000
+    pub fn size_limit(&mut self, insts: usize) -> &mut Self {
+        self.compile_limits.max_insts = insts;
+        self
+    }
+
+    /// `{n,m}` に書ける回数の上限。既定は 65,535
+    pub fn counter_limit(&mut self, count: usize) -> &mut Self {
+        self.compile_limits.max_count = count;
+        self
+    }
+
+    /// パターンをコンパイルする。コンパイルの上限を超えると `ErrorKind::LimitExceeded` を返す
+    pub fn build(&self) -> Result<Regex, Error> {
+        let program = engine::compile_with_names(&self.pattern, &self.compile_limits)?;
+        Ok(Regex {
+            pattern: Cow::Owned(self.pattern.clone()),
+            code: Cow::Owned(program.code),
+            names: Names::Shared(program.names.into()),
+            reverse: program.reverse.map(Cow::Owned),
+            is_depth: self.is_depth,
+            limits: self.limits,
+            cache: Pool::new(),
+        })
+    }
+}
+
+/// コンパイル済みの正規表現。
+/// 複数のスレッドから同時に使える。照合に使う作業領域はスレッドごとに使い回す。
+///
+/// 長い行では、マッチの開始位置を前向きの走査と逆向きのプログラムで探すので、
+/// 開始位置ごとに評価し直さない。評価の上限は 1 つの開始位置ごとに数えるものなので、
+/// 上限を付けたときと、逆向きにできないパターン (アトミックグループ、後読み、`\Z` を含むもの) では
+/// 開始位置を左から順に試す
+#[derive(Debug)]
+pub struct Regex {
+    pattern: Cow<'static, str>,
+    code: Cow<'static, [Instruction]>,
+    names: Names,
+    /// マッチの始まりを探す逆向きのプログラム
+    reverse: Option<Cow<'static, [Instruction]>>,
+    is_depth: bool,
+    limits: EvalLimits,
+    cache: Pool<Cache>,
+}
+
+/// グループごとの名前。名前のないグループは None。
+/// `regex!` が埋め込んだ名前は静的な配列をそのまま指す
+#[derive(Clone)]
+enum Names {
+    Static(&'static [Option<&'static str>]),
+    Shared(Arc<[Option<String>]>),
+}
+
+impl Names {
+    fn iter(&self) -> impl Iterator<Item = Option<&str>> + '_ {
+        let len = match self {
+            Names::Static(names) => names.len(),
+            Names::Shared(names) => names.len(),
+        };
+        (0..len).map(|i| match self {
+            Names::Static(names) => names[i],
+            Names::Shared(names) => names[i].as_deref(),
+        })
+    }
+}
+
+impl fmt::Debug for Names {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl PartialEq for Names {
+    fn eq(&self, other: &Self) -> bool {
+        self.iter().eq(other.iter())
+    }
+}
+
+impl Eq for Names {}
+
+/// 照合のたびに確保し直さずに使い回す領域
+#[derive(Default)]
+struct Cache {
+    /// 入力を文字の列にしたもの
+    line: Vec<char>,
+    /// `line` を逆順にしたもの。逆向きのプログラムを使うときだけ作る
+    reversed: Vec<char>,
+    scratch: Scratch,
+    /// 最後にマッチしたときのグループの位置
+    captures: CaptureSpans,
+}
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Regex, Error> {
+        RegexBuilder::new(pattern).build()
+    }
+
+    /// 静的な配列に埋め込んだプログラムから作る。`regex!` が展開した `static` の初期化で呼ばれるので、
+    /// メモリを確保しない
+    pub(crate) const fn from_static(
+        pattern: &'static str,
+        code: &'static [Instruction],
+        names: &'static [Option<&'static str>],
+        reverse: Option<&'static [Instruction]>,
+    ) -> Regex {
+        Regex {
+            pattern: Cow::Borrowed(pattern),
+            code: Cow::Borrowed(code),
+            names: Names::Static(names),
+            reverse: match reverse {
+                Some(reverse) => Some(Cow::Borrowed(reverse)),
+                None => None,
+            },
+            is_depth: true,
+            limits: EvalLimits {
+                step_budget: None,
+                timeout: None,
+            },
+            cache: Pool::new(),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    /// グループの名前を番号の順に返す。グループ 0 と名前のないグループは None
+    pub fn capture_names(&self) -> impl Iterator<Item = Option<&str>> + '_ {
+        self.names.iter()
+    }
+
+    /// マッチするかだけを調べる。同じスレッドで繰り返し呼ぶと、前より長い入力を渡さない限り
+    /// 作業領域を確保し直さない
+    pub fn is_match(&self, text: &str) -> Result<bool, Error> {
+        self.search(text, &mut self.cache.get())
+    }
+
+    /// 最も左から始まるマッチを返す
+    pub fn find<'t>(&self, text: &'t str) -> Result<Option<Match<'t>>, Error> {
+        Ok(self.captures(text)?.and_then(|caps| caps.get(0)))
+    }
+
+    /// 最も左から始まるマッチと、その各グループの位置を返す
+    pub fn captures<'t>(&self, text: &'t str) -> Result<Option<Captures<'t>>, Error> {
+        let mut cache = self.cache.get();
+        if self.search(text, &mut cache)? {
+            Ok(Some(Captures::new(text, cache.captures.clone(), &char_offsets(text), &self.names)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// 重ならないマッチを左から順にすべて返す。
+    /// 空文字へのマッチは、直前のマッチの終わりと同じ位置のものは数えない
+    pub fn captures_all<'t>(&self, text: &'t str) -> Result<Vec<Captures<'t>>, Error> {
+        let mut cache = self.cache.get();
+        self.load(text, &mut cache);
+        let offsets = char_offsets(text);
+
+        let mut all = Vec::new();
+        let mut from = 0;
+        let mut last_end = None;
+        while from <= cache.line.len() && self.search_from(from, &mut cache)? {
+            // マッチしたらグループ 0 は必ずある
+            let (start, end) = cache.captures[0].unwrap();
+            if start == end && last_end == Some(end) {
+                from = end + 1;
+                continue;
+            }
+            all.push(Captures::new(text, cache.captures.clone(), &offsets, &self.names));
+            last_end = Some(end);
+            from = if start == end { end + 1 } else { end };
+        }
+        Ok(all)
+    }
+
+    /// 最も左から始まるマッチを探す。マッチしたら `cache.captures` にグループの位置が入る
+    fn search(&self, text: &str, cache: &mut Cache) -> Result<bool, Error> {
+        self.load(text, cache);
+        self.search_from(0, cache)
+    }
+
+    /// 逆向きのプログラムを使うなら、その入力も作っておく
+    fn load(&self, text: &str, cache: &mut Cache) {
+        cache.line.clear();
+        cache.line.extend(text.chars());
+        cache.reversed.clear();
+        if self.fast_search(cache.line.len()).is_some() {
+            cache.reversed.extend(cache.line.iter().rev());
+        }
+    }
+
+    /// 長さ `len` の入力で、開始位置を前向きの走査と逆向きのプログラムで探すなら、逆向きのプログラムを返す
+    fn fast_search(&self, len: usize) -> Option<&[Instruction]> {
+        let unlimited = self.limits.step_budget.is_none() && self.limits.timeout.is_none();
+        self.reverse.as_deref().filter(|_| unlimited && len >= FAST_SEARCH_MIN_CHARS)
+    }
+
+    /// `cache.line` の `from` 文字目から右で最も左から始まるマッチを探す。`^` や `\b` は入力全体で判定する
+    fn search_from(&self, from: usize, cache: &mut Cache) -> Result<bool, Error> {
+        let Cache {
+            line,
+            reversed,
+            scratch,
+            captures,
+        } = cache;
+        // 開始位置がわかれば、そこから 1 度だけ評価してグループの位置を求める
+        let starts = if let Some(reverse) = self.fast_search(line.len()) {
+            match engine::leftmost_start(&self.code, reverse, line, reversed, from, scratch)? {
+                Some(start) => start..start + 1,
+                None => return Ok(false),
+            }
+        } else {
+            from..line.len() + 1
+        };
+        for start in starts {
+            if engine::eval_captures_with(&self.code, line, start, self.is_depth, &self.limits, scratch, captures)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// `text` を入力の先頭部分とみなしてマッチを試す。
+    /// 今の入力でマッチしなくても、入力を足せばマッチするかもしれないなら `NeedMoreInput` を返す。
+    ///
+    /// 開始位置を左から順に試し、最初に `NoMatch` 以外になったものを返す。
+    /// 左の開始位置が入力を待っている間は、右の開始位置でマッチしていても `NeedMoreInput` になる。
+    /// `^` で始まらないパターンは後ろに足した入力の中でマッチし得るので、`NoMatch` にはならない
+    pub fn partial_match<'t>(&self, text: &'t str) -> Result<PartialMatch<'t>, Error> {
+        let mut cache = self.cache.get();
+        let Cache { line, scratch, .. } = &mut *cache;
+        line.clear();
+        line.extend(text.chars());
+        for start in 0..=line.len() {
+            match engine::eval_partial(&self.code, line, start, self.is_depth, &self.limits, scratch)? {
+                Partial::Matched(spans) => {
+                    return Ok(PartialMatch::Matched(Captures::new(text, spans, &char_offsets(text), &self.names)))
+                }
+                Partial::NeedMoreInput => return Ok(PartialMatch::NeedMoreInput),
+                Partial::NoMatch => (),
+            }
+        }
+        Ok(PartialMatch::NoMatch)
+    }
+}
+
+/// `Regex::partial_match` の結果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PartialMatch<'t> {
+    /// 今の入力でマッチした。入力を足すとマッチが長くなることはある
+    Matched(Captures<'t>),
+    /// 入力を足してもマッチしない
+    NoMatch,
+    /// 今はマッチしないが、入力を足せばマッチするかもしれない
+    NeedMoreInput,
+}
+
+/// マッチした範囲。位置は `text` の中のバイト単位
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Match<'t> {
+    text: &'t str,
+    start: usize,
+    end: usize,
+}
+
+impl<'t> Match<'t> {
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    pub fn range(&self) -> Range<usize> {
+        self.start..self.end
+    }
+
+    pub fn as_str(&self) -> &'t str {
+        &self.text[self.range()]
+    }
+}
+
+/// 何文字目かをバイト位置にする表。末尾の位置も入れておく
+fn char_offsets(text: &str) -> Vec<usize> {
+    text.char_indices().map(|(i, _)| i).chain([text.len()]).collect()
+}
+
+/// グループごとのマッチ。グループ 0 はマッチ全体
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Captures<'t> {
+    text: &'t str,
+    spans: Vec<Option<Range<usize>>>,
+    names: Names,
+}
+
+impl<'t> Captures<'t> {
+    /// 文字単位の位置を `offsets` でバイト単位にする
+    fn new(text: &'t str, spans: CaptureSpans, offsets: &[usize], names: &Names) -> Self {
+        let spans = spans
+            .into_iter()
+            .map(|span| span.map(|(s, e)| offsets[s]..offsets[e]))
+            .collect();
+        Captures {
+            text,
+            spans,
+            names: names.clone(),
+        }
+    }
+
+    /// `i` 番目のグループ。マッチの中で一度も通らなかったグループは None
+    pub fn get(&self, i: usize) -> Option<Match<'t>> {
+        self.spans.get(i)?.as_ref().map(|span| Match {
+            text: self.text,
+            start: span.start,
+            end: span.end,
+        })
+    }
+
+    /// `(?<name>...)` のグループ。そういう名前のグループがないか、通らなかったときは None
+    pub fn name(&self, name: &str) -> Option<Match<'t>> {
+        let i = self.names.iter().position(|n| n == Some(name))?;
+        self.get(i)
+    }
+
+    /// グループ 0 を含むグループの数
+    pub fn len(&self) -> usize {
+        self.spans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.spans.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Option<Match<'t>>> + '_ {
+        (0..self.len()).map(|i| self.get(i))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PartialMatch, Regex, RegexBuilder};
+    use crate::error::ErrorKind;
+    use std::{thread, time::Duration};
+
+    #[test]
+    fn test_find() {
+        let re = Regex::new("\\d+").unwrap();
+        let m = re.find("abc 123 45").unwrap().unwrap();
+        assert_eq!((m.start(), m.end(), m.as_str()), (4, 7, "123"));
+        assert!(re.find("abc").unwrap().is_none());
+        assert!(re.is_match("x1").unwrap());
+        assert!(!re.is_match("x").unwrap());
+
+        // 位置はバイト単位
+        let re = Regex::new("\\p{Katakana}+").unwrap();
+        let m = re.find("error: ファイルがない").unwrap().unwrap();
+        assert_eq!(m.as_str(), "ファイル");
+        assert_eq!(m.range(), 7..19);
+
+        // 空文字にもマッチする
+        let m = Regex::new("x*").unwrap().find("abc").unwrap().unwrap();
+        assert_eq!(m.range(), 0..0);
+        let m = Regex::new("$").unwrap().find("abc").unwrap().unwrap();
+        assert_eq!(m.range(), 3..3);
+    }
+
+    #[test]
+    fn test_captures() {
+        let re = Regex::new("(\\d{4})-(\\d{2})(?:-(\\d{2}))?").unwrap();
+        let caps = re.captures("date: 2024-01").unwrap().unwrap();
+        assert_eq!(caps.len(), 4);
+        assert_eq!(caps.get(0).unwrap().as_str(), "2024-01");
+        assert_eq!(caps.get(1).unwrap().range(), 6..10);
+        assert_eq!(caps.get(2).unwrap().as_str(), "01");
+        assert_eq!(caps.get(3), None);
+        assert_eq!(caps.get(4), None);
+        assert_eq!(
+            caps.iter().map(|m| m.map(|m| m.as_str())).collect::<Vec<_>>(),
+            vec![Some("2024-01"), Some("2024"), Some("01"), None]
+        );
+
+        for depth_first in [true, false] {
+            let re = RegexBuilder::new("(a|ab)(c|bcd)").depth_first(depth_first).build().unwrap();
+            let caps = re.captures("xabcd").unwrap().unwrap();
+            assert_eq!(caps.get(0).unwrap().range(), 1..5);
+            assert_eq!(caps.get(1).unwrap().as_str(), "a");
+        }
+    }
+
+    #[test]
+    fn test_captures_all() {
+        let all = |expr: &str, text: &str| {
+            let re = Regex::new(expr).unwrap();
+            let all = re.captures_all(text).unwrap();
+            all.iter().map(|caps| caps.get(0).unwrap().range()).collect::<Vec<_>>()
+        };
+        assert_eq!(all("\\d+", "a1 23 値456"), vec![1..2, 3..5, 9..12]);
+        assert_eq!(all("\\d+", "abc"), vec![]);
+        // 空文字へのマッチは 1 文字ずつ進める。直前のマッチの終わりでは数えない
+        assert_eq!(all("x*", "ab"), vec![0..0, 1..1, 2..2]);
+        assert_eq!(all("a*", "baaac"), vec![0..0, 1..4, 5..5]);
+        // 2 つ目以降のマッチでも ^ と \b は入力全体で判定する
+        assert_eq!(all("^a", "aaa"), vec![0..1]);
+        assert_eq!(all("\\bab", "ab ab xab"), vec![0..2, 3..5]);
+
+        let re = Regex::new("(?<k>\\p{Ll}+)=(?<v>\\d*)").unwrap();
+        let all = re.captures_all("a=1, b=, c=3").unwrap();
+        let pairs = all.iter().map(|caps| (caps.name("k").unwrap().as_str(), caps.name("v").unwrap().as_str()));
+        assert_eq!(pairs.collect::<Vec<_>>(), vec![("a", "1"), ("b", ""), ("c", "3")]);
+    }
+
+    #[test]
+    fn test_assertions() {
+        for depth_first in [true, false] {
+            let find = |expr: &str, text: &str| {
+                let re = RegexBuilder::new(expr).depth_first(depth_first).build().unwrap();
+                re.find(text).unwrap().map(|m| m.range())
+            };
+            assert_eq!(find("\\bcat\\b", "concat cat_ cat."), Some(12..15));
+            assert_eq!(find("\\Bcat", "cat concat"), Some(7..10));
+            assert_eq!(find("\\b", "  "), None);
+            assert_eq!(find("\\b\\p{Han}+\\b", "東京 都"), Some(0..6));
+            assert_eq!(find("\\Aa", "aa"), Some(0..1));
+            assert_eq!(find("a\\z", "aa\n"), None);
+            assert_eq!(find("a\\Z", "aa\n"), Some(1..2));
+            assert_eq!(find("a\\Z", "aa"), Some(1..2));
+            // 開始位置をずらしても、^ と \b は入力全体で判定する
+            assert_eq!(find("^b", "ab"), None);
+            assert_eq!(find("\\bb", "ab b"), Some(3..4));
+            // カウンタのループの中でも使える
+            assert_eq!(find("(?:\\b\\d+\\b ?){20}", &"1 ".repeat(20)), Some(0..40));
+        }
+
+        fn partial<'t>(expr: &str, text: &'t str) -> PartialMatch<'t> {
+            Regex::new(expr).unwrap().partial_match(text).unwrap()
+        }
+        assert!(matches!(partial("^ab\\b", "ab"), PartialMatch::Matched(_)));
+        // 次の文字が単語の文字なら \B が成り立つ
+        assert_eq!(partial("^ab\\B", "ab"), PartialMatch::NeedMoreInput);
+        assert_eq!(partial("^ab\\B", "ab "), PartialMatch::NoMatch);
+    }
+
+    #[test]
+    fn test_error() {
+        let err = Regex::new("ab\\q").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Syntax);
+        assert_eq!(err.span(), Some(3..4));
+        // 位置はバイト単位
+        let err = Regex::new("あい)").unwrap_err();
+        assert_eq!(err.span(), Some(6..7));
+        let err = Regex::new("(ab").unwrap_err();
+        assert_eq!((err.kind(), err.span()), (ErrorKind::Syntax, None));
+        assert_eq!(Regex::new("").unwrap_err().kind(), ErrorKind::Syntax);
+
+        let re = RegexBuilder::new("a*c").step_budget(100).build().unwrap();
+        assert_eq!(re.is_match(&"a".repeat(1000)).unwrap_err().kind(), ErrorKind::BudgetExceeded);
+        let re = RegexBuilder::new("a*c").timeout(Duration::ZERO).build().unwrap();
+        assert_eq!(re.is_match(&"a".repeat(5000)).unwrap_err().kind(), ErrorKind::Timeout);
+    }
+
+    #[test]
+    fn test_compile_limits() {
+        let kind = |builder: &RegexBuilder| builder.build().map(|_| ()).map_err(|e| e.kind());
+
+        // 深すぎるパターンもスタックを使い切らずにエラーになる
+        let deep = format!("{}a{}", "(".repeat(100_000), ")".repeat(100_000));
+        assert_eq!(kind(&RegexBuilder::new(&deep)), Err(ErrorKind::LimitExceeded));
+        assert_eq!(kind(&RegexBuilder::new(&format!("a{}", "?".repeat(1000)))), Err(ErrorKind::LimitExceeded));
+        assert_eq!(kind(RegexBuilder::new("((a))").depth_limit(7)), Ok(()));
+        assert_eq!(kind(RegexBuilder::new("((a))").depth_limit(6)), Err(ErrorKind::LimitExceeded));
+
+        // 展開した繰り返しで命令が増えすぎる
+        assert_eq!(kind(&RegexBuilder::new("(?:(?:(?:(?:a{16}){16}){16}){16}){16}")), Err(ErrorKind::LimitExceeded));
+        assert_eq!(kind(RegexBuilder::new("a{16}").size_limit(20)), Ok(()));
+        assert_eq!(kind(RegexBuilder::new("a{16}b").size_limit(20)), Ok(()));
+        assert_eq!(kind(RegexBuilder::new("a{16}bcd").size_limit(20)), Err(ErrorKind::LimitExceeded));
+
+        // ループにする回数でも上限を超えればエラー
+        assert_eq!(kind(&RegexBuilder::new("a{65535}")), Ok(()));
+        assert_eq!(kind(&RegexBuilder::new("a{1,65536}")), Err(ErrorKind::LimitExceeded));
+        assert_eq!(kind(&RegexBuilder::new(&format!("a{{{},}}", usize::MAX))), Err(ErrorKind::LimitExceeded));
+        assert_eq!(kind(RegexBuilder::new("a{100}").counter_limit(99)), Err(ErrorKind::LimitExceeded));
+
+        let err = RegexBuilder::new("a{100}").counter_limit(99).build().unwrap_err();
+        assert_eq!(err.to_string(), "CodeGenError: repetition count 100 exceeds 99");
+    }
+
+    #[test]
+    fn test_look_behind() {
+        for depth_first in [true, false] {
+            let find = |expr: &str, text: &str| {
+                let re = RegexBuilder::new(expr).depth_first(depth_first).build().unwrap();
+                re.find(text).unwrap().map(|m| m.as_str().to_string())
+            };
+            assert_eq!(find("(?<=\\$)\\d+", "12 $34"), Some("34".to_string()));
+            assert_eq!(find("(?<!\\$)\\b\\d+", "$12 34"), Some("34".to_string()));
+            assert_eq!(find("(?<=^|,)[^,]*", "a,b"), Some("a".to_string()));
+            assert_eq!(find("(?<=ab|c{2,3})x", "cx ccx"), Some("x".to_string()));
+            assert_eq!(find("(?<=ab|c{2,3})x", "cx bx"), None);
+            // 中身は今の位置の手前で終わればよく、それより前の文字は問わない
+            assert_eq!(find("(?<=a)b", "aaab"), Some("b".to_string()));
+            // \b は後読みの後ろの文字も見る
+            assert_eq!(find("(?<=a\\b)", "ab a"), Some("".to_string()));
+        }
+
+        let re = Regex::new("(?<=(?<cur>\\p{Sc}))(\\d+)").unwrap();
+        let caps = re.captures("price: ¥500").unwrap().unwrap();
+        assert_eq!(caps.get(2).unwrap().as_str(), "500");
+        assert!(caps.name("cur").is_none());
+
+        let err = Regex::new("(?<=a+)b").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+        assert_eq!(err.to_string(), "CodeGenError: lookbehind must have a bounded length");
+        assert_eq!(Regex::new("(?<=(?>a))b").unwrap_err().kind(), ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn test_fast_search() {
+        // 長い行では開始位置を逆向きのプログラムで探す。評価の上限を付けると開始位置を順に試す
+        let line = format!("{}b {} 2024-01-02 x", "a".repeat(100), "ab".repeat(50));
+        for expr in ["a*b", "\\d+-(\\d+)", "(?:ab)+|b", "\\bab", "x$", "^a{3}", "[ab]+ (\\d)"] {
+            let fast = Regex::new(expr).unwrap();
+            let slow = RegexBuilder::new(expr).step_budget(usize::MAX).build().unwrap();
+            let spans = |re: &Regex| -> Vec<Vec<Option<std::ops::Range<usize>>>> {
+                let all = re.captures_all(&line).unwrap();
+                all.iter().map(|caps| caps.iter().map(|m| m.map(|m| m.range())).collect()).collect()
+            };
+            assert_eq!(spans(&fast), spans(&slow), "{expr}");
+            assert!(!spans(&fast).is_empty(), "{expr}");
+        }
+
+        // 開始位置ごとに評価し直すと入力の長さの 2 乗かかるパターン
+        let line = "a".repeat(20_000);
+        assert!(Regex::new("a*b").unwrap().find(&line).unwrap().is_none());
+        let m = Regex::new("(a|aa)*$").unwrap().find(&line).unwrap().unwrap();
+        assert_eq!(m.range(), 0..20_000);
+    }
+
+    #[test]
+    fn test_partial_match() {
+        for depth_first in [true, false] {
+            let partial = |expr: &str, text: &str| {
+                let re = RegexBuilder::new(expr).depth_first(depth_first).build().unwrap();
+                match re.partial_match(text).unwrap() {
+                    PartialMatch::Matched(caps) => Ok(caps.get(0).unwrap().as_str().to_string()),
+                    PartialMatch::NoMatch => Err("no match"),
+                    PartialMatch::NeedMoreInput => Err("need more"),
+                }
+            };
+            let need_more = Err("need more");
+            let no_match = Err("no match");
+
+            // フォームの入力の検証
+            let zip = "^\\d{3}-\\d{4}$";
+            assert_eq!(partial(zip, ""), need_more);
+            assert_eq!(partial(zip, "12"), need_more);
+            assert_eq!(partial(zip, "123-"), need_more);
+            assert_eq!(partial(zip, "123-4567"), Ok("123-4567".to_string()));
+            assert_eq!(partial(zip, "123-45678"), no_match);
+            assert_eq!(partial(zip, "12a"), no_match);
+
+            // 途中で読めるだけ読む
+            assert_eq!(partial("ab+", "xab"), Ok("ab".to_string()));
+            assert_eq!(partial("abc", "xxab"), need_more);
+            // ^ がなければ、後ろに足した入力でマッチするかもしれない
+            assert_eq!(partial("abc", "xxabd"), need_more);
+            assert_eq!(partial("^abc", "xxab"), no_match);
+            // 入力を待つ開始位置より右でマッチしていても、入力を待つ
+            assert_eq!(partial("ab*c|b", "ab"), need_more);
+
+            // 文字列の選択肢 (トライ木)
+            assert_eq!(partial("^(?:error|warn|fatal)$", "wa"), need_more);
+            assert_eq!(partial("^(?:error|warn|fatal)$", "wo"), no_match);
+            assert_eq!(partial("^(?:error|warn|fatal)$", "warn"), Ok("warn".to_string()));
+        }
+
+        // アトミックグループは常に深さ優先で評価する
+        let re = Regex::new("^(?>a+)b").unwrap();
+        assert_eq!(re.partial_match("aa").unwrap(), PartialMatch::NeedMoreInput);
+        assert_eq!(re.partial_match("ac").unwrap(), PartialMatch::NoMatch);
+    }
+
+    #[test]
+    fn test_named() {
+        let re = Regex::new("(?<year>\\d{4})-(\\d{2})(?:-(?P<day>\\d{2}))?").unwrap();
+        assert_eq!(re.capture_names().collect::<Vec<_>>(), vec![None, Some("year"), None, Some("day")]);
+        let caps = re.captures("at 2024-01").unwrap().unwrap();
+        assert_eq!(caps.name("year").unwrap().as_str(), "2024");
+        assert_eq!(caps.name("year"), caps.get(1));
+        assert!(caps.name("day").is_none());
+        assert!(caps.name("month").is_none());
+
+        let err = Regex::new("(?<a>x)(?<a>y)").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Syntax);
+        assert_eq!(err.span(), Some(7..8));
+    }
+
+    #[test]
+    fn test_threads() {
+        // 1 つの Regex を複数のスレッドで使っても、作業領域を取り合わない
+        for depth_first in [true, false] {
+            let re = RegexBuilder::new("(\\p{L}+)@(\\d{1,3})").depth_first(depth_first).build().unwrap();
+            thread::scope(|s| {
+                for i in 0..8 {
+                    let re = &re;
+                    s.spawn(move || {
+                        for j in 0..50 {
+                            let name = "ab".repeat(i + 1);
+                            let text = format!("to: {name}@{j} ok");
+                            let caps = re.captures(&text).unwrap().unwrap();
+                            assert_eq!(caps.get(1).unwrap().as_str(), name);
+                            assert_eq!(caps.get(2).unwrap().as_str(), j.to_string());
+                            assert!(!re.is_match(&name).unwrap());
+                        }
+                    });
+                }
+            });
+        }
+    }
+}
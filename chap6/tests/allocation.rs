@@ -0,0 +1,83 @@
+//! 照合を繰り返してもメモリを確保し直さないことを、確保の回数を数えるアロケータで確かめる。
+
+use chap6::RegexBuilder;
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+};
+
+/// スレッドごとに確保の回数を数えるアロケータ。テストは並行に走るので、他のスレッドの分は数えない
+struct CountingAlloc;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|n| n.set(n.get() + 1));
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.with(|n| n.set(n.get() + 1));
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+fn allocations(f: impl FnOnce()) -> usize {
+    let before = ALLOCATIONS.with(Cell::get);
+    f();
+    ALLOCATIONS.with(Cell::get) - before
+}
+
+#[test]
+fn test_is_match_does_not_allocate() {
+    let patterns = [
+        "ab(c|d)*e",
+        "(a|b){2,5}c",
+        "x(?:ab|cd|ef)+y",
+        "error|warn|fatal",
+        "\\b\\p{Katakana}+\\b",
+        "^(\\d{1,3}\\.){3}\\d{1,3}$",
+        "(?>a+)b",
+        "((ab){1,3}|a*)c$",
+        "(?<=ab)c|(?<!\\d)x+",
+        "(?<=\\b(?:fa|ba){1,3})t",
+    ];
+    // 32 文字以上の入力では、前向きの走査と逆向きのプログラムで開始位置を探す
+    let inputs = [
+        "",
+        "abdcde",
+        "aabc xcdaby",
+        "192.168.0.1",
+        "fabat 1xx",
+        "アイウ ファイル",
+        &"ab".repeat(40),
+        &format!("{}fatal{}", "-".repeat(50), "abababc"),
+    ];
+
+    for pattern in patterns {
+        for depth in [true, false] {
+            let re = RegexBuilder::new(pattern).depth_first(depth).build().unwrap();
+            for input in inputs {
+                re.is_match(input).unwrap();
+            }
+            let n = allocations(|| {
+                for _ in 0..10 {
+                    for input in inputs {
+                        re.is_match(input).unwrap();
+                    }
+                }
+            });
+            assert_eq!(n, 0, "{pattern} (depth_first: {depth})");
+        }
+    }
+}